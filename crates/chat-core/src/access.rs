@@ -0,0 +1,384 @@
+use crate::addr::canonical_ip;
+use crate::allowlist::{Access, AllowlistFiles, Knock, PendingEntry, RulesStamp};
+use crate::util::{now_ts, random_code};
+use anyhow::Context;
+use ipnet::IpNet;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use tracing::{info, warn};
+
+/// Parsed allow and deny rules, swapped as a whole on reload.
+#[derive(Debug, Default)]
+pub struct AccessSnapshot {
+    pub allow: Vec<(IpNet, Option<u64>)>,
+    pub deny: Vec<(IpNet, Option<u64>)>,
+}
+
+impl AccessSnapshot {
+    fn load(files: &AllowlistFiles) -> anyhow::Result<Self> {
+        let [allow_source, _, deny_source] = files.sources();
+        let allow = files.load_allow()?.to_nets().with_context(|| allow_source)?;
+        let deny = files.load_deny()?.to_nets().with_context(|| deny_source)?;
+        Ok(Self { allow, deny })
+    }
+
+    pub fn check(&self, ip: IpAddr, now: u64) -> Access {
+        let ip = canonical_ip(ip);
+        let matches = |(net, expires): &(IpNet, Option<u64>)| {
+            net.contains(&ip) && expires.map(|exp| exp > now).unwrap_or(true)
+        };
+        if self.deny.iter().any(matches) {
+            Access::Denied
+        } else if self.allow.iter().any(matches) {
+            Access::Allowed
+        } else {
+            Access::Pending
+        }
+    }
+}
+
+/// Minimum time between two knocks from the same IP, in seconds.
+pub const KNOCK_INTERVAL: u64 = 600;
+
+/// In-memory view of the allow/deny files for the accept path.
+///
+/// `check` never touches the disk: unapproved attempts are queued and written
+/// to `pending.toml` by `flush_pending`.
+#[derive(Debug)]
+pub struct AccessCache {
+    files: AllowlistFiles,
+    snapshot: RwLock<Arc<AccessSnapshot>>,
+    stamp: Mutex<RulesStamp>,
+    queued: Mutex<BTreeMap<IpAddr, PendingEntry>>,
+    /// Last knock per client key (see `IpKeying`), for the once-per-`KNOCK_INTERVAL` limit.
+    knocks: Mutex<HashMap<IpAddr, (u64, String)>>,
+    /// Held while `pending.toml` is rewritten, so a removal cannot race a flush.
+    writing: Mutex<()>,
+}
+
+impl AccessCache {
+    pub fn load(files: AllowlistFiles) -> anyhow::Result<Self> {
+        let stamp = files.rules_stamp();
+        let snapshot = AccessSnapshot::load(&files)?;
+        Ok(Self {
+            files,
+            snapshot: RwLock::new(Arc::new(snapshot)),
+            stamp: Mutex::new(stamp),
+            queued: Mutex::new(BTreeMap::new()),
+            knocks: Mutex::new(HashMap::new()),
+            writing: Mutex::new(()),
+        })
+    }
+
+    pub fn files(&self) -> &AllowlistFiles {
+        &self.files
+    }
+
+    pub fn snapshot(&self) -> Arc<AccessSnapshot> {
+        self.snapshot.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn check(&self, ip: IpAddr) -> Access {
+        let ip = canonical_ip(ip);
+        let now = now_ts();
+        let access = self.snapshot().check(ip, now);
+        if access == Access::Pending {
+            let mut queued = self.queued.lock().unwrap_or_else(|e| e.into_inner());
+            queued
+                .entry(ip)
+                .and_modify(|entry| {
+                    entry.last_seen = now;
+                    entry.attempts += 1;
+                })
+                .or_insert(PendingEntry {
+                    first_seen: now,
+                    last_seen: now,
+                    attempts: 1,
+                    knock: None,
+                });
+        }
+        access
+    }
+
+    /// Reference code of a knock from client key `key` within the last `KNOCK_INTERVAL`, if any.
+    pub fn recent_knock(&self, key: IpAddr) -> Option<String> {
+        let now = now_ts();
+        let mut knocks = self.knocks.lock().unwrap_or_else(|e| e.into_inner());
+        knocks.retain(|_, (at, _)| at.saturating_add(KNOCK_INTERVAL) > now);
+        knocks.get(&key).map(|(_, code)| code.clone())
+    }
+
+    /// Queues an introduction for `ip` and returns its reference code. The knock is rate-limited
+    /// under `key`, so IPv6 clients cannot knock again by moving to another address in their prefix.
+    pub fn knock(&self, ip: IpAddr, key: IpAddr, name: &str, reason: &str) -> String {
+        let now = now_ts();
+        let code = random_code(6);
+        let knock = Knock::new(name, reason, code.clone(), now);
+        info!(%ip, code = %code, name = %knock.name, "knock received");
+        self.knocks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(key, (now, code.clone()));
+        let mut queued = self.queued.lock().unwrap_or_else(|e| e.into_inner());
+        queued
+            .entry(ip)
+            .or_insert(PendingEntry {
+                first_seen: now,
+                last_seen: now,
+                attempts: 0,
+                knock: None,
+            })
+            .knock = Some(knock);
+        code
+    }
+
+    /// Pending requests as the next `flush_pending` will write them: `pending.toml` plus queued attempts.
+    pub fn pending(&self) -> anyhow::Result<Vec<(String, PendingEntry)>> {
+        let _writing = self.writing.lock().unwrap_or_else(|e| e.into_inner());
+        let mut pending = self.files.load_pending()?;
+        let queued = self.queued.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        for (ip, entry) in queued {
+            pending.merge(ip, entry);
+        }
+        Ok(pending.pending.into_iter().collect())
+    }
+
+    /// Forgets `ip` as pending, both queued and in `pending.toml`, after it was allowed or
+    /// rejected. Returns whether it was pending at all.
+    pub fn drop_pending(&self, ip: IpAddr) -> anyhow::Result<bool> {
+        let ip = canonical_ip(ip);
+        let _writing = self.writing.lock().unwrap_or_else(|e| e.into_inner());
+        let queued = self.queued.lock().unwrap_or_else(|e| e.into_inner()).remove(&ip).is_some();
+        let mut pending = self.files.load_pending()?;
+        let stored = pending.pending.remove(&ip.to_string()).is_some();
+        if stored {
+            self.files.save_pending(&pending)?;
+        }
+        Ok(queued || stored)
+    }
+
+    /// Forgets every address inside `net` as pending, and the knock limit of the client key
+    /// `net` stands for (see `IpKeying::net`). Returns the addresses that were pending.
+    pub fn drop_pending_net(&self, net: IpNet) -> anyhow::Result<Vec<IpAddr>> {
+        let _writing = self.writing.lock().unwrap_or_else(|e| e.into_inner());
+        self.knocks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&net.network());
+        let mut dropped: Vec<IpAddr> = {
+            let mut queued = self.queued.lock().unwrap_or_else(|e| e.into_inner());
+            let dropped: Vec<IpAddr> = queued.keys().filter(|ip| net.contains(*ip)).copied().collect();
+            for ip in &dropped {
+                queued.remove(ip);
+            }
+            dropped
+        };
+        let mut pending = self.files.load_pending()?;
+        let stored: Vec<String> = pending
+            .pending
+            .keys()
+            .filter(|ip| ip.parse::<IpAddr>().is_ok_and(|ip| net.contains(&ip)))
+            .cloned()
+            .collect();
+        for ip in &stored {
+            pending.remove(ip);
+            if let Ok(ip) = ip.parse::<IpAddr>() {
+                if !dropped.contains(&ip) {
+                    dropped.push(ip);
+                }
+            }
+        }
+        if !stored.is_empty() {
+            self.files.save_pending(&pending)?;
+        }
+        Ok(dropped)
+    }
+
+    /// Re-reads both lists. On a parse error the previous rules stay in effect.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let stamp = self.files.rules_stamp();
+        let snapshot = AccessSnapshot::load(&self.files)?;
+        info!(
+            allow = snapshot.allow.len(),
+            deny = snapshot.deny.len(),
+            "access rules reloaded"
+        );
+        *self.snapshot.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(snapshot);
+        *self.stamp.lock().unwrap_or_else(|e| e.into_inner()) = stamp;
+        Ok(())
+    }
+
+    /// Reloads when the modification time or size of either file changed, or another
+    /// connection wrote to the database.
+    pub fn reload_if_changed(&self) -> anyhow::Result<bool> {
+        let current = self.files.rules_stamp();
+        let changed = *self.stamp.lock().unwrap_or_else(|e| e.into_inner()) != current;
+        if changed {
+            self.reload()?;
+        }
+        Ok(changed)
+    }
+
+    /// Merges queued attempts into `pending.toml` and returns the IPs that were not pending before.
+    /// If the file cannot be read or written the attempts stay queued for the next flush.
+    pub fn flush_pending(&self) -> anyhow::Result<Vec<(IpAddr, PendingEntry)>> {
+        let _writing = self.writing.lock().unwrap_or_else(|e| e.into_inner());
+        let queued = std::mem::take(&mut *self.queued.lock().unwrap_or_else(|e| e.into_inner()));
+        if queued.is_empty() {
+            return Ok(Vec::new());
+        }
+        match self.write_queued(&queued) {
+            Ok(fresh) => Ok(fresh),
+            Err(err) => {
+                warn!(%err, "failed to write pending list");
+                let mut requeue = self.queued.lock().unwrap_or_else(|e| e.into_inner());
+                for (ip, mut entry) in queued {
+                    // Attempts queued since the take are newer than the ones put back.
+                    if let Some(newer) = requeue.remove(&ip) {
+                        entry.absorb(newer);
+                    }
+                    requeue.insert(ip, entry);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn write_queued(&self, queued: &BTreeMap<IpAddr, PendingEntry>) -> anyhow::Result<Vec<(IpAddr, PendingEntry)>> {
+        let mut pending = self.files.load_pending()?;
+        let mut fresh = Vec::new();
+        for (ip, entry) in queued {
+            if !pending.pending.contains_key(&ip.to_string()) {
+                fresh.push((*ip, entry.clone()));
+            }
+            pending.merge(*ip, entry.clone());
+        }
+        self.files.save_pending(&pending)?;
+        for (ip, entry) in queued {
+            info!(%ip, attempts = entry.attempts, "ip not approved - added to pending");
+        }
+        Ok(fresh)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allowlist::ListStore;
+    use std::str::FromStr;
+    use tempfile::tempdir;
+
+    #[test]
+    fn cached_checks_and_reload() {
+        let dir = tempdir().unwrap();
+        let files = AllowlistFiles {
+            allowlist: dir.path().join("allowed.toml"),
+            pending: dir.path().join("pending.toml"),
+            denylist: dir.path().join("denied.toml"),
+            store: ListStore::Files,
+        };
+        files.add_allow("10.0.0.0/8", None, None, None).unwrap();
+        let cache = AccessCache::load(files.clone()).unwrap();
+        let ip = IpAddr::from_str("192.0.2.1").unwrap();
+
+        assert_eq!(cache.check(IpAddr::from_str("10.1.2.3").unwrap()), Access::Allowed);
+        assert_eq!(cache.check(ip), Access::Pending);
+        assert_eq!(cache.check(ip), Access::Pending);
+        assert!(!files.pending.exists());
+        assert_eq!(cache.flush_pending().unwrap()[0].0, ip);
+        assert_eq!(files.list_pending().unwrap()[0].1.attempts, 2);
+        cache.check(ip);
+        assert!(cache.flush_pending().unwrap().is_empty());
+        assert_eq!(files.list_pending().unwrap()[0].1.attempts, 3);
+
+        assert!(!cache.reload_if_changed().unwrap());
+        files.add_allow("192.0.2.0/24", None, None, None).unwrap();
+        assert!(cache.reload_if_changed().unwrap());
+        assert_eq!(cache.check(ip), Access::Allowed);
+
+        // Queued attempts show up before they are flushed, and a rejection clears them too.
+        let queued = IpAddr::from_str("203.0.113.5").unwrap();
+        cache.check(queued);
+        assert!(cache.pending().unwrap().iter().any(|(ip, _)| ip == "203.0.113.5"));
+        assert!(cache.drop_pending(queued).unwrap());
+        assert!(!cache.drop_pending(queued).unwrap());
+        assert!(cache.flush_pending().unwrap().is_empty());
+        assert!(files.list_pending().unwrap().iter().all(|(ip, _)| ip != "203.0.113.5"));
+
+        let other = IpAddr::from_str("198.51.100.4").unwrap();
+        assert!(cache.recent_knock(other).is_none());
+        let code = cache.knock(other, other, " Dana ", "new laptop");
+        assert_eq!(cache.recent_knock(other), Some(code.clone()));
+        // Knocks are limited per client key, not per address.
+        let keying = crate::addr::IpKeying::new(64).unwrap();
+        let v6 = |s: &str| IpAddr::from_str(s).unwrap();
+        let first = v6("2001:db8:1:2::1");
+        cache.knock(first, keying.key(first), "Eve", "again");
+        assert!(cache.recent_knock(keying.key(v6("2001:db8:1:2::99"))).is_some());
+        cache.flush_pending().unwrap();
+        let knock = files
+            .list_pending()
+            .unwrap()
+            .into_iter()
+            .find(|(ip, _)| ip == "198.51.100.4")
+            .and_then(|(_, entry)| entry.knock)
+            .unwrap();
+        assert_eq!((knock.name.as_str(), knock.code), ("Dana", code));
+
+        std::fs::write(&files.allowlist, "allow = [").unwrap();
+        assert!(cache.reload().is_err());
+        assert_eq!(cache.check(ip), Access::Allowed);
+    }
+
+    #[test]
+    fn failed_flush_keeps_attempts_queued() {
+        let dir = tempdir().unwrap();
+        let files = AllowlistFiles {
+            allowlist: dir.path().join("allowed.toml"),
+            pending: dir.path().join("pending.toml"),
+            denylist: dir.path().join("denied.toml"),
+            store: ListStore::Files,
+        };
+        let cache = AccessCache::load(files.clone()).unwrap();
+        let ip = IpAddr::from_str("192.0.2.1").unwrap();
+        cache.check(ip);
+        let code = cache.knock(ip, ip, "Dana", "new laptop");
+
+        // A directory where the file should be makes both the read and the write fail.
+        std::fs::create_dir(&files.pending).unwrap();
+        assert!(cache.flush_pending().is_err());
+        cache.check(ip);
+        std::fs::remove_dir(&files.pending).unwrap();
+
+        assert_eq!(cache.flush_pending().unwrap()[0].0, ip);
+        let (_, entry) = files.list_pending().unwrap().remove(0);
+        assert_eq!(entry.attempts, 2);
+        assert_eq!(entry.knock.unwrap().code, code);
+    }
+
+    #[test]
+    fn deny_overrides_allow() {
+        let dir = tempdir().unwrap();
+        let files = AllowlistFiles {
+            allowlist: dir.path().join("allowed.toml"),
+            pending: dir.path().join("pending.toml"),
+            denylist: dir.path().join("denied.toml"),
+            store: ListStore::Files,
+        };
+        files.add_allow("10.0.0.0/8", None, None, None).unwrap();
+        assert!(!files.remove_allow("10.9.0.0/16").unwrap());
+        files.add_deny("10.6.6.6", Some("abuse".into()), None).unwrap();
+        files.add_deny("10.7.7.7", None, Some(now_ts() - 1)).unwrap();
+        assert!(files.add_deny("not-an-ip", None, None).is_err());
+
+        let cache = AccessCache::load(files.clone()).unwrap();
+        let ip = |s: &str| IpAddr::from_str(s).unwrap();
+        assert_eq!(cache.check(ip("10.1.1.1")), Access::Allowed);
+        assert_eq!(cache.check(ip("10.6.6.6")), Access::Denied);
+        assert_eq!(cache.check(ip("10.7.7.7")), Access::Allowed);
+        assert_eq!(cache.check(ip("192.0.2.1")), Access::Pending);
+        assert_eq!(cache.flush_pending().unwrap().len(), 1);
+        assert_eq!(files.list_pending().unwrap().len(), 1);
+    }
+}
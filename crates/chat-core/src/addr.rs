@@ -0,0 +1,112 @@
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use std::net::IpAddr;
+
+/// Maps IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) to plain IPv4.
+///
+/// A listener bound to `[::]` reports IPv4 peers in mapped form; everything
+/// that stores or compares peer addresses should go through this first.
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    ip.to_canonical()
+}
+
+/// Canonical form of an address used as a map key in a state file; other strings pass through.
+pub fn canonical_key(raw: &str) -> String {
+    raw.parse::<IpAddr>()
+        .map(|ip| canonical_ip(ip).to_string())
+        .unwrap_or_else(|_| raw.to_string())
+}
+
+/// Same as `canonical_ip` for networks: `::ffff:10.0.0.0/104` becomes `10.0.0.0/8`.
+pub fn canonical_net(net: IpNet) -> IpNet {
+    match net {
+        IpNet::V6(v6) if v6.prefix_len() >= 96 => match v6.addr().to_ipv4_mapped() {
+            Some(v4) => Ipv4Net::new(v4, v6.prefix_len() - 96)
+                .map(IpNet::V4)
+                .unwrap_or(net),
+            None => net,
+        },
+        _ => net,
+    }
+}
+
+/// Derives the key a client is tracked under for identities and rate limits.
+///
+/// IPv4 clients are keyed by address. IPv6 clients can be keyed by a prefix
+/// (typically /64) so that rotating through a delegated subnet does not yield
+/// a fresh identity or rate budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpKeying {
+    pub v6_prefix: u8,
+}
+
+impl Default for IpKeying {
+    fn default() -> Self {
+        Self { v6_prefix: 128 }
+    }
+}
+
+impl IpKeying {
+    pub fn new(v6_prefix: u8) -> anyhow::Result<Self> {
+        if v6_prefix == 0 || v6_prefix > 128 {
+            anyhow::bail!("ipv6 prefix must be between 1 and 128, got {v6_prefix}");
+        }
+        Ok(Self { v6_prefix })
+    }
+
+    /// The network a key stands for: a single IPv4 address or an IPv6 prefix.
+    pub fn net(&self, ip: IpAddr) -> IpNet {
+        let key = self.key(ip);
+        let prefix = if key.is_ipv4() { 32 } else { self.v6_prefix };
+        IpNet::new(key, prefix).unwrap_or_else(|_| IpNet::from(key))
+    }
+
+    /// `net` as an allow or deny entry: a bare address for single hosts, CIDR otherwise.
+    pub fn entry(&self, ip: IpAddr) -> String {
+        let net = self.net(ip);
+        if net.prefix_len() == net.max_prefix_len() {
+            net.addr().to_string()
+        } else {
+            net.to_string()
+        }
+    }
+
+    pub fn key(&self, ip: IpAddr) -> IpAddr {
+        match canonical_ip(ip) {
+            IpAddr::V6(v6) => Ipv6Net::new(v6, self.v6_prefix)
+                .map(|net| IpAddr::V6(net.network()))
+                .unwrap_or(IpAddr::V6(v6)),
+            v4 => v4,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mapped_addresses_are_canonical() {
+        let mapped: IpAddr = "::ffff:192.0.2.7".parse().unwrap();
+        assert_eq!(canonical_ip(mapped), "192.0.2.7".parse::<IpAddr>().unwrap());
+        let net: IpNet = "::ffff:10.0.0.0/104".parse().unwrap();
+        assert_eq!(canonical_net(net), "10.0.0.0/8".parse::<IpNet>().unwrap());
+        let v6: IpNet = "2001:db8::/32".parse().unwrap();
+        assert_eq!(canonical_net(v6), v6);
+    }
+
+    #[test]
+    fn ipv6_keyed_by_prefix() {
+        let keying = IpKeying::new(64).unwrap();
+        let a: IpAddr = "2001:db8:1:2:aaaa::1".parse().unwrap();
+        let b: IpAddr = "2001:db8:1:2:bbbb::9".parse().unwrap();
+        assert_eq!(keying.key(a), keying.key(b));
+        assert_eq!(keying.key(a), "2001:db8:1:2::".parse::<IpAddr>().unwrap());
+        assert_eq!(keying.net(b), "2001:db8:1:2::/64".parse::<IpNet>().unwrap());
+        assert_eq!(keying.entry(b), "2001:db8:1:2::/64");
+        let mapped: IpAddr = "::ffff:192.0.2.7".parse().unwrap();
+        assert_eq!(keying.key(mapped), "192.0.2.7".parse::<IpAddr>().unwrap());
+        assert_eq!(keying.entry(mapped), "192.0.2.7");
+        assert_eq!(IpKeying::default().key(a), a);
+        assert!(IpKeying::new(0).is_err());
+    }
+}
@@ -1,46 +1,145 @@
-use crate::util::{atomic_write, now_ts};
-use anyhow::Context;
+use crate::addr::{canonical_ip, canonical_key, canonical_net};
+use crate::util::{atomic_write, load_toml, now_ts};
 use ipnet::IpNet;
-use serde::{Deserialize, Serialize};
+use serde::de::{self, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::btree_map::Entry;
 use std::collections::BTreeMap;
+use std::fmt;
 use std::net::IpAddr;
 use std::path::{Path, PathBuf};
-use tracing::{info, warn};
+use std::time::SystemTime;
+use tracing::warn;
+
+/// One allowlist rule. Files written before entries carried metadata list bare
+/// strings; those still load, with no label or owner and `added = 0`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AllowEntry {
+    pub entry: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    pub added: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+}
+
+impl AllowEntry {
+    pub fn new(entry: &str) -> Self {
+        Self {
+            entry: entry.to_string(),
+            label: None,
+            owner: None,
+            added: 0,
+            expires: None,
+        }
+    }
+
+    pub fn active(&self, now: u64) -> bool {
+        self.expires.map(|exp| exp > now).unwrap_or(true)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AllowEntryTable {
+    entry: String,
+    #[serde(default)]
+    label: Option<String>,
+    #[serde(default)]
+    owner: Option<String>,
+    #[serde(default)]
+    added: u64,
+    #[serde(default)]
+    expires: Option<u64>,
+}
+
+impl<'de> Deserialize<'de> for AllowEntry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EntryVisitor;
+
+        impl<'de> Visitor<'de> for EntryVisitor {
+            type Value = AllowEntry;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("an ip/cidr string or a table with an `entry` key")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<AllowEntry, E> {
+                Ok(AllowEntry::new(value))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<AllowEntry, A::Error> {
+                let t = AllowEntryTable::deserialize(de::value::MapAccessDeserializer::new(map))?;
+                Ok(AllowEntry {
+                    entry: t.entry,
+                    label: t.label,
+                    owner: t.owner,
+                    added: t.added,
+                    expires: t.expires,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(EntryVisitor)
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct AllowedList {
-    pub allow: Vec<String>,
+    pub allow: Vec<AllowEntry>,
+}
+
+pub fn parse_net(entry: &str) -> Option<IpNet> {
+    let net = if entry.contains('/') {
+        entry.parse::<IpNet>().ok()
+    } else {
+        entry.parse::<IpAddr>().ok().map(IpNet::from)
+    };
+    net.map(canonical_net)
+}
+
+/// Parses rule entries, reporting every one that is not an IP address or CIDR.
+fn parse_rules<'a>(
+    kind: &str,
+    entries: impl Iterator<Item = (&'a str, Option<u64>)>,
+) -> anyhow::Result<Vec<(IpNet, Option<u64>)>> {
+    let mut nets = Vec::new();
+    let mut bad = Vec::new();
+    for (idx, (entry, expires)) in entries.enumerate() {
+        match parse_net(entry) {
+            Some(net) => nets.push((net, expires)),
+            None => bad.push(format!("#{} {entry:?}", idx + 1)),
+        }
+    }
+    if !bad.is_empty() {
+        anyhow::bail!("invalid {kind} entries, expected ip or cidr: {}", bad.join(", "));
+    }
+    Ok(nets)
 }
 
 impl AllowedList {
-    pub fn to_nets(&self) -> Vec<IpNet> {
-        self.allow
-            .iter()
-            .filter_map(|entry| {
-                if entry.contains('/') {
-                    entry.parse::<IpNet>().ok()
-                } else {
-                    entry
-                        .parse::<IpAddr>()
-                        .ok()
-                        .map(|ip| IpNet::from(ip))
-                }
-            })
-            .collect()
+    pub fn to_nets(&self) -> anyhow::Result<Vec<(IpNet, Option<u64>)>> {
+        parse_rules(
+            "allowlist",
+            self.allow.iter().map(|e| (e.entry.as_str(), e.expires)),
+        )
     }
 
     pub fn allows(&self, ip: IpAddr) -> bool {
-        let nets = self.to_nets();
-        nets.iter().any(|net| net.contains(&ip))
+        let ip = canonical_ip(ip);
+        let now = now_ts();
+        self.allow.iter().any(|entry| {
+            entry.active(now)
+                && parse_net(&entry.entry)
+                    .map(|net| net.contains(&ip))
+                    .unwrap_or(false)
+        })
     }
 
     pub fn load(path: &Path) -> anyhow::Result<Self> {
-        if !path.exists() {
-            return Ok(Self::default());
-        }
-        let raw = std::fs::read_to_string(path).context("read allowlist")?;
-        let parsed = toml::from_str::<AllowedList>(&raw).unwrap_or_default();
-        Ok(parsed)
+        load_toml(path)
     }
 
     pub fn save(&self, path: &Path) -> anyhow::Result<()> {
@@ -49,11 +148,50 @@ impl AllowedList {
     }
 }
 
+pub const KNOCK_NAME_MAX: usize = 32;
+pub const KNOCK_REASON_MAX: usize = 200;
+
+/// Introduction left by an unapproved client.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Knock {
+    pub name: String,
+    pub reason: String,
+    /// Reference code handed back to the client.
+    pub code: String,
+    pub at: u64,
+}
+
+impl Knock {
+    /// Trims and truncates the free-text fields.
+    pub fn new(name: &str, reason: &str, code: String, at: u64) -> Self {
+        Self {
+            name: name.trim().chars().take(KNOCK_NAME_MAX).collect(),
+            reason: reason.trim().chars().take(KNOCK_REASON_MAX).collect(),
+            code,
+            at,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PendingEntry {
     pub first_seen: u64,
     pub last_seen: u64,
     pub attempts: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub knock: Option<Knock>,
+}
+
+impl PendingEntry {
+    /// Folds the later sighting `seen` into this entry; its knock, if any, replaces ours.
+    pub fn absorb(&mut self, seen: PendingEntry) {
+        self.first_seen = self.first_seen.min(seen.first_seen);
+        self.last_seen = self.last_seen.max(seen.last_seen);
+        self.attempts += seen.attempts;
+        if seen.knock.is_some() {
+            self.knock = seen.knock;
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -63,12 +201,16 @@ pub struct PendingList {
 
 impl PendingList {
     pub fn load(path: &Path) -> anyhow::Result<Self> {
-        if !path.exists() {
-            return Ok(Self::default());
+        let parsed: PendingList = load_toml(path)?;
+        // Entries recorded as `::ffff:a.b.c.d` by older versions fold into the IPv4 key.
+        let mut list = PendingList::default();
+        for (key, entry) in parsed.pending {
+            let ip = key
+                .parse::<IpAddr>()
+                .map_err(|_| anyhow::anyhow!("{}: invalid ip {key:?}", path.display()))?;
+            list.merge(ip, entry);
         }
-        let raw = std::fs::read_to_string(path).context("read pending list")?;
-        let parsed = toml::from_str::<PendingList>(&raw).unwrap_or_default();
-        Ok(parsed)
+        Ok(list)
     }
 
     pub fn save(&self, path: &Path) -> anyhow::Result<()> {
@@ -77,7 +219,7 @@ impl PendingList {
     }
 
     pub fn note_attempt(&mut self, ip: IpAddr) {
-        let key = ip.to_string();
+        let key = canonical_ip(ip).to_string();
         let now = now_ts();
         self.pending
             .entry(key)
@@ -89,9 +231,19 @@ impl PendingList {
                 first_seen: now,
                 last_seen: now,
                 attempts: 1,
+                knock: None,
             });
     }
 
+    pub fn merge(&mut self, ip: IpAddr, seen: PendingEntry) {
+        match self.pending.entry(canonical_ip(ip).to_string()) {
+            Entry::Occupied(mut entry) => entry.get_mut().absorb(seen),
+            Entry::Vacant(entry) => {
+                entry.insert(seen);
+            }
+        }
+    }
+
     pub fn remove(&mut self, ip: &str) {
         self.pending.remove(ip);
     }
@@ -101,83 +253,342 @@ impl PendingList {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DenyEntry {
+    pub entry: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(default)]
+    pub added: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+}
+
+impl DenyEntry {
+    pub fn active(&self, now: u64) -> bool {
+        self.expires.map(|exp| exp > now).unwrap_or(true)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct DenyList {
+    pub deny: Vec<DenyEntry>,
+}
+
+impl DenyList {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        load_toml(path)
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let data = toml::to_string_pretty(self)?;
+        atomic_write(path, data.as_bytes())
+    }
+
+    pub fn to_nets(&self) -> anyhow::Result<Vec<(IpNet, Option<u64>)>> {
+        parse_rules(
+            "denylist",
+            self.deny.iter().map(|e| (e.entry.as_str(), e.expires)),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Allowed,
+    Pending,
+    Denied,
+}
+
+/// Where the allow, deny and pending lists are kept.
+#[derive(Debug, Clone, Default)]
+pub enum ListStore {
+    /// The TOML files named in `AllowlistFiles`.
+    #[default]
+    Files,
+    #[cfg(feature = "sqlite")]
+    Sqlite(crate::sqlite::SqliteDb),
+}
+
+type FileStamp = Option<(SystemTime, u64)>;
+
+fn file_stamp(path: &Path) -> FileStamp {
+    let meta = std::fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+/// Changes when the allow or deny rules may have been modified by another writer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RulesStamp(FileStamp, FileStamp, Option<i64>);
+
 #[derive(Debug, Clone)]
 pub struct AllowlistFiles {
     pub allowlist: PathBuf,
     pub pending: PathBuf,
+    pub denylist: PathBuf,
+    pub store: ListStore,
 }
 
 impl AllowlistFiles {
-    pub fn check_or_note(&self, ip: IpAddr) -> anyhow::Result<bool> {
-        let allowed = AllowedList::load(&self.allowlist)?.allows(ip);
-        if allowed {
-            return Ok(true);
+    pub fn load_allow(&self) -> anyhow::Result<AllowedList> {
+        match &self.store {
+            ListStore::Files => AllowedList::load(&self.allowlist),
+            #[cfg(feature = "sqlite")]
+            ListStore::Sqlite(db) => db.load_allow(),
+        }
+    }
+
+    fn save_allow(&self, list: &AllowedList) -> anyhow::Result<()> {
+        match &self.store {
+            ListStore::Files => list.save(&self.allowlist),
+            #[cfg(feature = "sqlite")]
+            ListStore::Sqlite(db) => db.save_allow(list),
+        }
+    }
+
+    pub fn load_deny(&self) -> anyhow::Result<DenyList> {
+        match &self.store {
+            ListStore::Files => DenyList::load(&self.denylist),
+            #[cfg(feature = "sqlite")]
+            ListStore::Sqlite(db) => db.load_deny(),
+        }
+    }
+
+    fn save_deny(&self, list: &DenyList) -> anyhow::Result<()> {
+        match &self.store {
+            ListStore::Files => list.save(&self.denylist),
+            #[cfg(feature = "sqlite")]
+            ListStore::Sqlite(db) => db.save_deny(list),
+        }
+    }
+
+    pub fn load_pending(&self) -> anyhow::Result<PendingList> {
+        match &self.store {
+            ListStore::Files => PendingList::load(&self.pending),
+            #[cfg(feature = "sqlite")]
+            ListStore::Sqlite(db) => db.load_pending(),
+        }
+    }
+
+    pub fn save_pending(&self, list: &PendingList) -> anyhow::Result<()> {
+        match &self.store {
+            ListStore::Files => list.save(&self.pending),
+            #[cfg(feature = "sqlite")]
+            ListStore::Sqlite(db) => db.save_pending(list),
+        }
+    }
+
+    /// Names the allow, pending and deny lists in messages: file paths, or database tables.
+    pub fn sources(&self) -> [String; 3] {
+        match &self.store {
+            ListStore::Files => [
+                self.allowlist.display().to_string(),
+                self.pending.display().to_string(),
+                self.denylist.display().to_string(),
+            ],
+            #[cfg(feature = "sqlite")]
+            ListStore::Sqlite(db) => ["allow", "pending", "deny"].map(|table| format!("{} {table}", db.path().display())),
+        }
+    }
+
+    pub fn rules_stamp(&self) -> RulesStamp {
+        match &self.store {
+            ListStore::Files => RulesStamp(file_stamp(&self.allowlist), file_stamp(&self.denylist), None),
+            #[cfg(feature = "sqlite")]
+            ListStore::Sqlite(db) => RulesStamp(None, None, db.rules_version().ok()),
+        }
+    }
+
+    pub fn add_deny(&self, entry: &str, reason: Option<String>, expires: Option<u64>) -> anyhow::Result<()> {
+        if parse_net(entry).is_none() {
+            anyhow::bail!("invalid ip or cidr {entry:?}");
         }
-        let mut pending = PendingList::load(&self.pending)?;
-        pending.note_attempt(ip);
-        pending.save(&self.pending)?;
-        info!(%ip, "ip not approved - added to pending");
-        Ok(false)
+        let mut deny = self.load_deny()?;
+        let now = now_ts();
+        deny.deny.retain(|e| e.entry != entry && e.active(now));
+        deny.deny.push(DenyEntry {
+            entry: entry.to_string(),
+            reason,
+            added: now,
+            expires,
+        });
+        deny.deny.sort_by(|a, b| a.entry.cmp(&b.entry));
+        self.save_deny(&deny)
     }
 
-    pub fn add_allow(&self, entry: &str) -> anyhow::Result<()> {
-        let mut allow = AllowedList::load(&self.allowlist)?;
-        if !allow.allow.iter().any(|e| e == entry) {
-            allow.allow.push(entry.to_string());
-            allow.allow.sort();
-            allow.save(&self.allowlist)?;
+    /// Denies `entry` until `expires` unless it is already denied at least that long.
+    ///
+    /// Returns false when an existing entry (permanent or later-expiring) was left alone.
+    pub fn extend_deny(&self, entry: &str, reason: String, expires: u64) -> anyhow::Result<bool> {
+        let existing = self.load_deny()?
+            .deny
+            .into_iter()
+            .find(|e| e.entry == entry && e.active(now_ts()));
+        if let Some(e) = existing {
+            if e.expires.map(|exp| exp >= expires).unwrap_or(true) {
+                return Ok(false);
+            }
         }
-        Ok(())
+        self.add_deny(entry, Some(reason), Some(expires))?;
+        Ok(true)
     }
 
-    pub fn remove_allow(&self, entry: &str) -> anyhow::Result<()> {
-        let mut allow = AllowedList::load(&self.allowlist)?;
-        allow.allow.retain(|e| e != entry);
-        allow.save(&self.allowlist)?;
-        Ok(())
+    pub fn remove_deny(&self, entry: &str) -> anyhow::Result<bool> {
+        let mut deny = self.load_deny()?;
+        let before = deny.deny.len();
+        deny.deny.retain(|e| e.entry != entry);
+        let removed = deny.deny.len() != before;
+        if removed {
+            self.save_deny(&deny)?;
+        }
+        Ok(removed)
+    }
+
+    pub fn list_deny(&self) -> anyhow::Result<Vec<DenyEntry>> {
+        Ok(self.load_deny()?.deny)
+    }
+
+    /// Adds `entry`, replacing the label, owner and expiry of an existing rule for it.
+    pub fn add_allow(
+        &self,
+        entry: &str,
+        label: Option<String>,
+        owner: Option<String>,
+        expires: Option<u64>,
+    ) -> anyhow::Result<()> {
+        if parse_net(entry).is_none() {
+            anyhow::bail!("invalid ip or cidr {entry:?}");
+        }
+        let mut allow = self.load_allow()?;
+        allow.allow.retain(|e| e.entry != entry);
+        allow.allow.push(AllowEntry {
+            entry: entry.to_string(),
+            label,
+            owner,
+            added: now_ts(),
+            expires,
+        });
+        allow.allow.sort_by(|a, b| a.entry.cmp(&b.entry));
+        self.save_allow(&allow)
+    }
+
+    pub fn remove_allow(&self, entry: &str) -> anyhow::Result<bool> {
+        let mut allow = self.load_allow()?;
+        let before = allow.allow.len();
+        allow.allow.retain(|e| e.entry != entry);
+        let removed = allow.allow.len() != before;
+        if removed {
+            self.save_allow(&allow)?;
+        }
+        Ok(removed)
     }
 
-    pub fn list_allow(&self) -> anyhow::Result<Vec<String>> {
-        let allow = AllowedList::load(&self.allowlist)?;
+    pub fn list_allow(&self) -> anyhow::Result<Vec<AllowEntry>> {
+        let allow = self.load_allow()?;
         Ok(allow.allow)
     }
 
     pub fn list_pending(&self) -> anyhow::Result<Vec<(String, PendingEntry)>> {
-        let pending = PendingList::load(&self.pending)?;
+        let pending = self.load_pending()?;
         Ok(pending.pending.into_iter().collect())
     }
 
-    pub fn remove_pending(&self, ip: &str) -> anyhow::Result<()> {
-        let mut pending = PendingList::load(&self.pending)?;
-        if pending.pending.remove(ip).is_some() {
-            pending.save(&self.pending)?;
+    pub fn remove_pending(&self, ip: &str) -> anyhow::Result<bool> {
+        let mut pending = self.load_pending()?;
+        if pending.pending.remove(&canonical_key(ip)).is_some() {
+            self.save_pending(&pending)?;
+            Ok(true)
         } else {
             warn!(%ip, "pending ip not found");
+            Ok(false)
         }
-        Ok(())
     }
 
+    /// Empties the pending list without reading it, so a corrupt file can be reset.
     pub fn clear_pending(&self) -> anyhow::Result<()> {
-        let mut pending = PendingList::load(&self.pending)?;
-        pending.clear();
-        pending.save(&self.pending)?;
-        Ok(())
+        self.save_pending(&PendingList::default())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::access::AccessSnapshot;
     use std::net::IpAddr;
     use std::str::FromStr;
 
     #[test]
     fn allowlist_basic() {
         let list = AllowedList {
-            allow: vec!["127.0.0.1".into(), "10.0.0.0/8".into()],
+            allow: vec![AllowEntry::new("127.0.0.1"), AllowEntry::new("10.0.0.0/8")],
         };
         assert!(list.allows(IpAddr::from_str("127.0.0.1").unwrap()));
         assert!(list.allows(IpAddr::from_str("10.1.2.3").unwrap()));
         assert!(!list.allows(IpAddr::from_str("192.168.0.1").unwrap()));
+        assert!(list.allows(IpAddr::from_str("::ffff:10.1.2.3").unwrap()));
+    }
+
+    #[test]
+    fn deny_entries_win_over_allow_entries() {
+        let allow = AllowedList {
+            allow: vec![AllowEntry::new("10.0.0.0/8")],
+        };
+        let deny = toml::from_str::<DenyList>(
+            "[[deny]]\nentry = \"10.6.0.0/16\"\n\n[[deny]]\nentry = \"10.7.7.7\"\nexpires = 1\n",
+        )
+        .unwrap();
+        let snapshot = AccessSnapshot {
+            allow: allow.to_nets().unwrap(),
+            deny: deny.to_nets().unwrap(),
+        };
+        let check = |ip: &str| snapshot.check(IpAddr::from_str(ip).unwrap(), now_ts());
+        assert!(allow.allows(IpAddr::from_str("10.6.6.6").unwrap()));
+        assert_eq!(check("10.6.6.6"), Access::Denied);
+        assert_eq!(check("::ffff:10.6.6.6"), Access::Denied);
+        assert_eq!(check("10.7.7.7"), Access::Allowed);
+        assert_eq!(check("10.1.2.3"), Access::Allowed);
+        assert_eq!(check("192.0.2.1"), Access::Pending);
+    }
+
+    #[test]
+    fn allowlist_migrates_plain_entries() {
+        let raw = r#"
+allow = [
+    "127.0.0.1",
+    { entry = "10.3.0.0/16", label = "build farm", owner = "ops", added = 1700000000 },
+    { entry = "192.0.2.0/24", added = 1700000000, expires = 1 },
+]
+"#;
+        let list = toml::from_str::<AllowedList>(raw).unwrap();
+        assert_eq!(list.allow[0], AllowEntry::new("127.0.0.1"));
+        assert_eq!(list.allow[1].label.as_deref(), Some("build farm"));
+        assert!(list.allows(IpAddr::from_str("10.3.4.5").unwrap()));
+        assert!(!list.allows(IpAddr::from_str("192.0.2.1").unwrap()));
+
+        let saved = toml::to_string_pretty(&list).unwrap();
+        let reloaded = toml::from_str::<AllowedList>(&saved).unwrap();
+        assert_eq!(reloaded.allow, list.allow);
+    }
+
+    #[test]
+    fn malformed_files_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("allowed.toml");
+        std::fs::write(&path, "allow = [\n  \"10.0.0.1\",\n  { entry = \"10.0.0.2\", lable = \"x\" },\n]\n").unwrap();
+        let err = AllowedList::load(&path).unwrap_err().to_string();
+        assert!(err.contains("line 3") && err.contains("lable"), "{err}");
+
+        let list = toml::from_str::<AllowedList>(r#"allow = ["10.0.0.1", "10.0.0.300", "nope/8"]"#).unwrap();
+        let err = list.to_nets().unwrap_err().to_string();
+        assert!(err.contains(r#"#2 "10.0.0.300""#) && err.contains(r#"#3 "nope/8""#), "{err}");
+
+        std::fs::write(&path, "[pending.\"not-an-ip\"]\nfirst_seen = 1\nlast_seen = 1\nattempts = 1\n").unwrap();
+        assert!(PendingList::load(&path).is_err());
+
+        let err = toml::from_str::<DenyList>("[[deny]]\nentry = \"10.0.0.1\"\nadded = 0\nexpire = 5\n").unwrap_err();
+        assert!(err.to_string().contains("expire"), "{err}");
+        let list = toml::from_str::<DenyList>("[[deny]]\nentry = \"10.0.0.1\"\n").unwrap();
+        assert_eq!(list.deny[0].added, 0);
     }
 }
@@ -0,0 +1,74 @@
+use crate::util::now_ts;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use tracing::info;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub ts: u64,
+    pub actor: String,
+    pub action: String,
+    pub detail: String,
+}
+
+/// Append-only JSON-lines log of privileged actions.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn record(&self, actor: &str, action: &str, detail: &str) -> anyhow::Result<()> {
+        info!(target: "audit", actor, action, detail, "audit");
+        let event = AuditEvent {
+            ts: now_ts(),
+            actor: actor.to_string(),
+            action: action.to_string(),
+            detail: detail.to_string(),
+        };
+        let mut line = serde_json::to_string(&event)?;
+        line.push('\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .context("open audit log")?;
+        file.write_all(line.as_bytes()).context("write audit log")?;
+        Ok(())
+    }
+
+    pub fn read(&self) -> anyhow::Result<Vec<AuditEvent>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let raw = std::fs::read_to_string(&self.path).context("read audit log")?;
+        Ok(raw
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn audit_appends() {
+        let dir = tempdir().unwrap();
+        let log = AuditLog::new(dir.path().join("audit.log"));
+        log.record("cli", "role.set", "127.0.0.1 admin").unwrap();
+        log.record("alice", "kick", "bob").unwrap();
+        let events = log.read().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].actor, "alice");
+    }
+}
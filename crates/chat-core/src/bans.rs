@@ -0,0 +1,292 @@
+use crate::util::load_toml;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Misbehavior that counts towards an automatic ban.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Offense {
+    /// Disconnected for exceeding the message rate.
+    RateLimit,
+    /// Sent a line that does not parse as a command.
+    InvalidCommand,
+    /// Connected while on the denylist.
+    Denied,
+    /// Connected while not on the allowlist.
+    Unapproved,
+}
+
+impl Offense {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Offense::RateLimit => "rate_limit",
+            Offense::InvalidCommand => "invalid_command",
+            Offense::Denied => "denied",
+            Offense::Unapproved => "unapproved",
+        }
+    }
+}
+
+impl fmt::Display for Offense {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Points each offense adds to an IP's score.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OffenseWeights {
+    pub rate_limit: u32,
+    pub invalid_command: u32,
+    pub denied: u32,
+    pub unapproved: u32,
+}
+
+impl Default for OffenseWeights {
+    fn default() -> Self {
+        Self {
+            rate_limit: 10,
+            invalid_command: 2,
+            denied: 2,
+            // Pending users retry while they wait, so a retry counts half of any other offense:
+            // only a client reconnecting every 15 seconds or faster reaches the threshold.
+            unapproved: 1,
+        }
+    }
+}
+
+impl OffenseWeights {
+    pub fn weight(&self, offense: Offense) -> u32 {
+        match offense {
+            Offense::RateLimit => self.rate_limit,
+            Offense::InvalidCommand => self.invalid_command,
+            Offense::Denied => self.denied,
+            Offense::Unapproved => self.unapproved,
+        }
+    }
+}
+
+/// When and for how long to ban automatically, loaded from `bans.toml`. Times are in seconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BanPolicy {
+    pub enabled: bool,
+    /// Sliding window over which offense points are summed.
+    pub window: u64,
+    /// Score within `window` that triggers a ban.
+    pub threshold: u32,
+    /// Length of the first ban; each further ban doubles it.
+    pub base_ban: u64,
+    pub max_ban: u64,
+    /// A ban this long ago no longer counts towards the next one's length.
+    pub forget_after: u64,
+    pub weights: OffenseWeights,
+}
+
+impl Default for BanPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            window: 10 * 60,
+            threshold: 40,
+            base_ban: 5 * 60,
+            max_ban: 24 * 60 * 60,
+            forget_after: 7 * 24 * 60 * 60,
+            weights: OffenseWeights::default(),
+        }
+    }
+}
+
+impl BanPolicy {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let policy: Self = load_toml(path)?;
+        if policy.threshold == 0 || policy.window == 0 || policy.base_ban == 0 {
+            anyhow::bail!("{}: threshold, window and base_ban must be positive", path.display());
+        }
+        if policy.max_ban < policy.base_ban {
+            anyhow::bail!("{}: max_ban is shorter than base_ban", path.display());
+        }
+        Ok(policy)
+    }
+
+    /// Length of ban number `nth` (starting at 0).
+    pub fn ban_length(&self, nth: u32) -> u64 {
+        self.base_ban
+            .saturating_mul(1u64.checked_shl(nth).unwrap_or(u64::MAX))
+            .min(self.max_ban)
+    }
+}
+
+/// A ban decided by `Offenders::record`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ban {
+    /// 1 for the first ban of this IP, 2 for the second, ...
+    pub count: u32,
+    pub duration: u64,
+    /// The offense that crossed the threshold.
+    pub offense: Offense,
+}
+
+#[derive(Debug, Default)]
+struct Record {
+    events: VecDeque<(u64, u32)>,
+    bans: u32,
+    last_ban: u64,
+}
+
+/// Per-IP offense scores in a sliding window, with the ban history used for backoff.
+#[derive(Debug)]
+pub struct Offenders {
+    policy: BanPolicy,
+    records: Mutex<HashMap<IpAddr, Record>>,
+}
+
+impl Offenders {
+    pub fn new(policy: BanPolicy) -> Self {
+        Self {
+            policy,
+            records: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn policy(&self) -> &BanPolicy {
+        &self.policy
+    }
+
+    /// Adds `offense` to the score of `ip`; returns a ban once the threshold is reached.
+    pub fn record(&self, ip: IpAddr, offense: Offense, now: u64) -> Option<Ban> {
+        let weight = self.policy.weights.weight(offense);
+        if !self.policy.enabled || weight == 0 {
+            return None;
+        }
+        let mut records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        let record = records.entry(ip).or_default();
+        let window = self.policy.window;
+        while record.events.front().map(|(at, _)| at + window <= now).unwrap_or(false) {
+            record.events.pop_front();
+        }
+        record.events.push_back((now, weight));
+        let score: u32 = record.events.iter().map(|(_, w)| w).sum();
+        if score < self.policy.threshold {
+            return None;
+        }
+
+        if record.bans > 0 && record.last_ban.saturating_add(self.policy.forget_after) <= now {
+            record.bans = 0;
+        }
+        let duration = self.policy.ban_length(record.bans);
+        record.bans += 1;
+        record.last_ban = now;
+        record.events.clear();
+        Some(Ban {
+            count: record.bans,
+            duration,
+            offense,
+        })
+    }
+
+    /// Current score and number of past bans per IP, highest score first.
+    pub fn snapshot(&self, now: u64) -> Vec<(IpAddr, u32, u32)> {
+        let window = self.policy.window;
+        let records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        let mut out: Vec<_> = records
+            .iter()
+            .map(|(ip, r)| {
+                let score: u32 = r.events.iter().filter(|(at, _)| at + window > now).map(|(_, w)| w).sum();
+                (*ip, score, r.bans)
+            })
+            .filter(|(_, score, bans)| *score > 0 || *bans > 0)
+            .collect();
+        out.sort_by(|a, b| b.1.cmp(&a.1).then(b.2.cmp(&a.2)).then(a.0.cmp(&b.0)));
+        out
+    }
+
+    /// Drops IPs with no recent offenses and no ban history worth remembering.
+    pub fn prune(&self, now: u64) {
+        let window = self.policy.window;
+        let forget_after = self.policy.forget_after;
+        let mut records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        records.retain(|_, r| {
+            r.events.retain(|(at, _)| at + window > now);
+            !r.events.is_empty() || (r.bans > 0 && r.last_ban.saturating_add(forget_after) > now)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bans_back_off_exponentially() {
+        let policy = BanPolicy {
+            window: 60,
+            threshold: 10,
+            base_ban: 100,
+            max_ban: 350,
+            forget_after: 1000,
+            weights: OffenseWeights {
+                rate_limit: 5,
+                invalid_command: 1,
+                denied: 1,
+                unapproved: 1,
+            },
+            ..BanPolicy::default()
+        };
+        let offenders = Offenders::new(policy);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        assert_eq!(offenders.record(ip, Offense::RateLimit, 0), None);
+        for t in 1..5 {
+            assert_eq!(offenders.record(ip, Offense::InvalidCommand, t), None);
+        }
+        let ban = offenders.record(ip, Offense::InvalidCommand, 5).unwrap();
+        assert_eq!((ban.count, ban.duration, ban.offense), (1, 100, Offense::InvalidCommand));
+
+        // Points older than the window fall out.
+        assert_eq!(offenders.record(ip, Offense::RateLimit, 10), None);
+        assert_eq!(offenders.record(ip, Offense::RateLimit, 100), None);
+        assert_eq!(offenders.record(ip, Offense::RateLimit, 101).unwrap().duration, 200);
+        assert_eq!(offenders.record(ip, Offense::RateLimit, 102), None);
+        assert_eq!(offenders.record(ip, Offense::RateLimit, 103).unwrap().duration, 350);
+        assert_eq!(offenders.snapshot(103), vec![(ip, 0, 3)]);
+
+        // A long quiet spell resets the backoff.
+        offenders.record(ip, Offense::RateLimit, 2000);
+        assert_eq!(offenders.record(ip, Offense::RateLimit, 2001).unwrap(), Ban {
+            count: 1,
+            duration: 100,
+            offense: Offense::RateLimit,
+        });
+        offenders.prune(5000);
+        assert!(offenders.snapshot(5000).is_empty());
+    }
+
+    #[test]
+    fn parse_ban_policy() {
+        let policy = toml::from_str::<BanPolicy>("threshold = 3\n[weights]\ndenied = 0\n").unwrap();
+        assert_eq!(policy.threshold, 3);
+        assert_eq!(policy.weights.rate_limit, 10);
+        let offenders = Offenders::new(policy);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        for t in 0..10 {
+            assert_eq!(offenders.record(ip, Offense::Denied, t), None);
+        }
+        // A client retrying every 20 seconds while it waits for approval is never banned,
+        // one hammering the server every few seconds is.
+        let offenders = Offenders::new(BanPolicy::default());
+        for t in 0..100 {
+            assert_eq!(offenders.record(ip, Offense::Unapproved, t * 20), None);
+        }
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        for t in 0..39 {
+            assert_eq!(offenders.record(other, Offense::Unapproved, t * 5), None);
+        }
+        assert_eq!(offenders.record(other, Offense::Unapproved, 39 * 5).unwrap().offense, Offense::Unapproved);
+        assert!(toml::from_str::<BanPolicy>("treshold = 3\n").is_err());
+    }
+}
@@ -0,0 +1,230 @@
+use crate::rate::TokenBucket;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Why `ConnLimits::acquire` turned a connection away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnRefusal {
+    PerIp { limit: usize },
+    ServerFull { limit: usize },
+}
+
+impl ConnRefusal {
+    /// Stable reason code sent to the client in an `ERROR` frame.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ConnRefusal::PerIp { .. } => "too_many_connections",
+            ConnRefusal::ServerFull { .. } => "server_full",
+        }
+    }
+}
+
+impl fmt::Display for ConnRefusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnRefusal::PerIp { limit } => write!(f, "Too many connections from your address (limit {limit})."),
+            ConnRefusal::ServerFull { limit } => write!(f, "Server is full ({limit} clients)."),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Caps on concurrent connections, per client key and server-wide. A limit of 0 disables it.
+#[derive(Debug)]
+pub struct ConnLimits {
+    pub per_ip: usize,
+    pub total: usize,
+    counts: Mutex<Counts>,
+}
+
+/// Holds one connection slot; the slot is released on drop.
+#[derive(Debug)]
+pub struct ConnGuard {
+    limits: Arc<ConnLimits>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        let mut counts = self.limits.counts.lock().unwrap_or_else(|e| e.into_inner());
+        counts.total = counts.total.saturating_sub(1);
+        if let Some(count) = counts.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+impl ConnLimits {
+    pub fn new(per_ip: usize, total: usize) -> Arc<Self> {
+        Arc::new(Self {
+            per_ip,
+            total,
+            counts: Mutex::new(Counts::default()),
+        })
+    }
+
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Result<ConnGuard, ConnRefusal> {
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        if self.total > 0 && counts.total >= self.total {
+            return Err(ConnRefusal::ServerFull { limit: self.total });
+        }
+        let current = counts.per_ip.get(&ip).copied().unwrap_or(0);
+        if self.per_ip > 0 && current >= self.per_ip {
+            return Err(ConnRefusal::PerIp { limit: self.per_ip });
+        }
+        counts.total += 1;
+        counts.per_ip.insert(ip, current + 1);
+        Ok(ConnGuard {
+            limits: self.clone(),
+            ip,
+        })
+    }
+
+    /// Open connections in total and per key, busiest first.
+    pub fn snapshot(&self) -> (usize, Vec<(IpAddr, usize)>) {
+        let counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        let mut per_ip: Vec<_> = counts.per_ip.iter().map(|(ip, n)| (*ip, *n)).collect();
+        per_ip.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        (counts.total, per_ip)
+    }
+}
+
+/// Idle per-IP buckets are dropped once this many are tracked.
+const ACCEPT_PRUNE_AT: usize = 4096;
+
+/// Counters reported by `AcceptLimiter::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AcceptStats {
+    pub accepted: u64,
+    pub dropped_ip: u64,
+    pub dropped_global: u64,
+}
+
+/// Rate of new TCP connections, checked before any TLS work. A rate of 0 disables a limit.
+#[derive(Debug)]
+pub struct AcceptLimiter {
+    per_ip: (u32, u32),
+    global: Option<Mutex<TokenBucket>>,
+    buckets: Mutex<HashMap<IpAddr, (TokenBucket, Instant)>>,
+    accepted: AtomicU64,
+    dropped_ip: AtomicU64,
+    dropped_global: AtomicU64,
+}
+
+impl AcceptLimiter {
+    /// `per_ip` and `global` are (connections per second, burst).
+    pub fn new(per_ip: (u32, u32), global: (u32, u32)) -> Self {
+        Self {
+            per_ip,
+            global: (global.0 > 0).then(|| Mutex::new(TokenBucket::new(global.0, global.1))),
+            buckets: Mutex::new(HashMap::new()),
+            accepted: AtomicU64::new(0),
+            dropped_ip: AtomicU64::new(0),
+            dropped_global: AtomicU64::new(0),
+        }
+    }
+
+    /// Server-wide check, made as soon as a socket is accepted.
+    pub fn allow_global(&self) -> bool {
+        let Some(global) = &self.global else {
+            return true;
+        };
+        let ok = global.lock().unwrap_or_else(|e| e.into_inner()).check();
+        if !ok {
+            self.dropped_global.fetch_add(1, Ordering::Relaxed);
+        }
+        ok
+    }
+
+    /// Per-client check, made once the real client address is known.
+    pub fn allow_ip(&self, ip: IpAddr) -> bool {
+        let (rate, burst) = self.per_ip;
+        let ok = rate == 0 || {
+            let now = Instant::now();
+            let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+            if buckets.len() >= ACCEPT_PRUNE_AT {
+                // A bucket idle for burst/rate seconds is full again and carries no state.
+                let idle = Duration::from_secs_f64(f64::from(burst.max(1)) / f64::from(rate));
+                buckets.retain(|_, (_, seen)| now.duration_since(*seen) < idle);
+            }
+            let (bucket, seen) = buckets
+                .entry(ip)
+                .or_insert_with(|| (TokenBucket::new(rate, burst), now));
+            *seen = now;
+            bucket.check_at(now)
+        };
+        let counter = if ok { &self.accepted } else { &self.dropped_ip };
+        counter.fetch_add(1, Ordering::Relaxed);
+        ok
+    }
+
+    pub fn stats(&self) -> AcceptStats {
+        AcceptStats {
+            accepted: self.accepted.load(Ordering::Relaxed),
+            dropped_ip: self.dropped_ip.load(Ordering::Relaxed),
+            dropped_global: self.dropped_global.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_per_ip_and_total() {
+        let limits = ConnLimits::new(2, 3);
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+
+        let a1 = limits.acquire(a).unwrap();
+        let _a2 = limits.acquire(a).unwrap();
+        assert_eq!(limits.acquire(a).unwrap_err(), ConnRefusal::PerIp { limit: 2 });
+        let _b1 = limits.acquire(b).unwrap();
+        assert_eq!(limits.acquire(b).unwrap_err().code(), "server_full");
+        assert_eq!(limits.snapshot(), (3, vec![(a, 2), (b, 1)]));
+
+        drop(a1);
+        let _b2 = limits.acquire(b).unwrap();
+        assert_eq!(limits.snapshot(), (3, vec![(b, 2), (a, 1)]));
+
+        let unlimited = ConnLimits::new(0, 0);
+        let guards: Vec<_> = (0..50).map(|_| unlimited.acquire(a).unwrap()).collect();
+        assert_eq!(unlimited.snapshot().0, guards.len());
+    }
+
+    #[test]
+    fn accept_rate_per_ip_and_global() {
+        let limiter = AcceptLimiter::new((1, 2), (1, 5));
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+
+        assert!(limiter.allow_ip(a) && limiter.allow_ip(a));
+        assert!(!limiter.allow_ip(a));
+        assert!(limiter.allow_ip(b));
+        assert_eq!((0..10).filter(|_| limiter.allow_global()).count(), 5);
+        assert_eq!(
+            limiter.stats(),
+            AcceptStats {
+                accepted: 3,
+                dropped_ip: 1,
+                dropped_global: 5,
+            }
+        );
+
+        let open = AcceptLimiter::new((0, 0), (0, 0));
+        assert!((0..100).all(|_| open.allow_global() && open.allow_ip(a)));
+    }
+}
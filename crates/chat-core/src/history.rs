@@ -1,33 +1,177 @@
-use crate::util::now_ts;
+use crate::util::{load_toml, now_ts};
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
+use std::fmt;
+use std::path::Path;
 use tokio::sync::Mutex;
 
+/// How much history to keep, loaded from `retention.toml`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionPolicy {
+    /// Store messages at all; `false` opts the room out and nothing is kept or replayed.
+    pub store: bool,
+    /// Newest messages kept; older ones are dropped as new ones arrive.
+    pub max_items: usize,
+    /// Seconds after which a message is deleted; 0 keeps messages until `max_items` pushes them out.
+    pub max_age: u64,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            store: true,
+            max_items: 100,
+            max_age: 0,
+        }
+    }
+}
+
+impl RetentionPolicy {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let policy: Self = load_toml(path)?;
+        if policy.max_items == 0 {
+            anyhow::bail!("{}: max_items must be positive (set store = false to keep nothing)", path.display());
+        }
+        Ok(policy)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HistoryItem {
+    /// Increases with every stored message; used as the paging cursor.
+    #[serde(default)]
+    pub id: u64,
     pub nick: String,
     pub text: String,
     pub ts: u64,
 }
 
+/// A page of history between two message ids, both exclusive.
+///
+/// With only `after` set the page starts right after it and reads forward; otherwise it is the
+/// newest `limit` messages before `before` (or overall). Pages are always returned oldest first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HistoryQuery {
+    pub before: Option<u64>,
+    pub after: Option<u64>,
+    pub limit: usize,
+}
+
+impl HistoryQuery {
+    pub fn latest(limit: usize) -> Self {
+        Self {
+            limit,
+            ..Self::default()
+        }
+    }
+
+    pub fn forward(&self) -> bool {
+        self.after.is_some() && self.before.is_none()
+    }
+
+    pub fn contains(&self, id: u64) -> bool {
+        self.before.is_none_or(|before| id < before) && self.after.is_none_or(|after| id > after)
+    }
+
+    /// Picks the page from `items`, which must be sorted by id.
+    pub fn select<'a>(&self, items: impl DoubleEndedIterator<Item = &'a HistoryItem>) -> Vec<HistoryItem> {
+        let matching = items.filter(|item| self.contains(item.id));
+        if self.forward() {
+            matching.take(self.limit).cloned().collect()
+        } else {
+            let mut page: Vec<_> = matching.rev().take(self.limit).cloned().collect();
+            page.reverse();
+            page
+        }
+    }
+}
+
+/// Words a message is searched by: lowercased runs of letters and digits.
+pub fn search_terms(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Shortest search term accepted; shorter prefixes match most of the word index.
+pub const SEARCH_MIN_TERM: usize = 3;
+
+/// Most distinct indexed words one search term may stand for.
+pub const SEARCH_MAX_WORDS: usize = 500;
+
+/// A search term that starts more than [`SEARCH_MAX_WORDS`] indexed words.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TooBroad(pub String);
+
+impl fmt::Display for TooBroad {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\" matches too many words, try a longer one", self.0)
+    }
+}
+
+impl std::error::Error for TooBroad {}
+
+/// Messages containing a word starting with each term, optionally from one nick
+/// (ASCII case-insensitive) and within `since..until` in unix seconds.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SearchQuery {
+    pub terms: Vec<String>,
+    pub nick: Option<String>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub limit: usize,
+}
+
+impl SearchQuery {
+    pub fn matches(&self, item: &HistoryItem) -> bool {
+        if self.nick.as_ref().is_some_and(|nick| !nick.eq_ignore_ascii_case(&item.nick))
+            || self.since.is_some_and(|since| item.ts < since)
+            || self.until.is_some_and(|until| item.ts >= until)
+        {
+            return false;
+        }
+        let words = search_terms(&item.text);
+        self.terms
+            .iter()
+            .all(|term| words.iter().any(|word| word.starts_with(term.as_str())))
+    }
+
+    /// Scans `items`, which must be sorted by id, for the newest `limit` matches.
+    pub fn scan<'a>(&self, items: impl DoubleEndedIterator<Item = &'a HistoryItem>) -> Vec<HistoryItem> {
+        items
+            .rev()
+            .filter(|item| self.matches(item))
+            .take(self.limit)
+            .cloned()
+            .collect()
+    }
+}
+
 #[async_trait]
 pub trait HistoryStore: Send + Sync {
     async fn push(&self, nick: String, text: String) -> anyhow::Result<()>;
     async fn list(&self) -> anyhow::Result<Vec<HistoryItem>>;
+    async fn range(&self, query: HistoryQuery) -> anyhow::Result<Vec<HistoryItem>>;
+    /// Matching messages, newest first.
+    async fn search(&self, query: &SearchQuery) -> anyhow::Result<Vec<HistoryItem>>;
+    /// Deletes messages sent at or after `from` and before `until` (unix seconds); returns how many.
+    async fn purge(&self, from: u64, until: u64) -> anyhow::Result<usize>;
 }
 
 #[derive(Debug)]
 pub struct InMemoryHistory {
     max: usize,
-    items: Mutex<VecDeque<HistoryItem>>,
+    items: Mutex<(u64, VecDeque<HistoryItem>)>,
 }
 
 impl InMemoryHistory {
     pub fn new(max: usize) -> Self {
         Self {
             max,
-            items: Mutex::new(VecDeque::new()),
+            items: Mutex::new((0, VecDeque::new())),
         }
     }
 }
@@ -35,8 +179,11 @@ impl InMemoryHistory {
 #[async_trait]
 impl HistoryStore for InMemoryHistory {
     async fn push(&self, nick: String, text: String) -> anyhow::Result<()> {
-        let mut items = self.items.lock().await;
+        let mut guard = self.items.lock().await;
+        let (last_id, items) = &mut *guard;
+        *last_id += 1;
         items.push_back(HistoryItem {
+            id: *last_id,
             nick,
             text,
             ts: now_ts(),
@@ -49,20 +196,202 @@ impl HistoryStore for InMemoryHistory {
 
     async fn list(&self) -> anyhow::Result<Vec<HistoryItem>> {
         let items = self.items.lock().await;
-        Ok(items.iter().cloned().collect())
+        Ok(items.1.iter().cloned().collect())
+    }
+
+    async fn range(&self, query: HistoryQuery) -> anyhow::Result<Vec<HistoryItem>> {
+        let items = self.items.lock().await;
+        Ok(query.select(items.1.iter()))
+    }
+
+    async fn search(&self, query: &SearchQuery) -> anyhow::Result<Vec<HistoryItem>> {
+        let items = self.items.lock().await;
+        Ok(query.scan(items.1.iter()))
+    }
+
+    async fn purge(&self, from: u64, until: u64) -> anyhow::Result<usize> {
+        let mut items = self.items.lock().await;
+        let before = items.1.len();
+        items.1.retain(|item| item.ts < from || item.ts >= until);
+        Ok(before - items.1.len())
     }
 }
 
 #[cfg(feature = "redis")]
 pub mod redis_history {
     use super::*;
+    use redis::aio::MultiplexedConnection;
     use redis::AsyncCommands;
 
-    #[derive(Clone)]
+    /// Moves the newest-first list older versions kept (KEYS[3]) into the sorted set (KEYS[1])
+    /// and id counter (KEYS[2]), numbering messages oldest first. Does nothing once either of
+    /// the new keys exists, so concurrent instances cannot migrate twice.
+    const MIGRATE_LIST: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 1 or redis.call('EXISTS', KEYS[2]) == 1 then
+  return 0
+end
+local raws = redis.call('LRANGE', KEYS[3], 0, -1)
+local n = 0
+for i = #raws, 1, -1 do
+  local ok, item = pcall(cjson.decode, raws[i])
+  if ok and type(item) == 'table' then
+    n = n + 1
+    item['id'] = n
+    redis.call('ZADD', KEYS[1], n, cjson.encode(item))
+  end
+end
+if n > 0 then
+  redis.call('SET', KEYS[2], n)
+end
+redis.call('DEL', KEYS[3])
+return n
+"#;
+
+    // Search index: `<key>:term:<word>` holds the ids of the messages containing `word`,
+    // `<key>:vocab` every indexed word (for prefix lookups) and `<key>:words` the words of
+    // each id, so a dropped message can be taken out of its term sets again.
+
+    /// Defines `index(id, ts, first)`, which indexes a message under the words in
+    /// `ARGV[first..]`, and `drop(id)`, which deletes a message and its index entries.
+    const INDEXING: &str = r#"
+local function index(id, ts, first)
+  redis.call('ZADD', KEYS[1] .. ':ts', ts, id)
+  local words = {}
+  for i = first, #ARGV do
+    words[#words + 1] = ARGV[i]
+    redis.call('ZADD', KEYS[1] .. ':term:' .. ARGV[i], id, id)
+    redis.call('ZADD', KEYS[1] .. ':vocab', 0, ARGV[i])
+  end
+  if #words > 0 then
+    redis.call('HSET', KEYS[1] .. ':words', id, cjson.encode(words))
+  end
+end
+local function drop(id)
+  local raw = redis.call('HGET', KEYS[1] .. ':words', id)
+  if raw then
+    for _, word in ipairs(cjson.decode(raw)) do
+      local term = KEYS[1] .. ':term:' .. word
+      redis.call('ZREM', term, id)
+      if redis.call('EXISTS', term) == 0 then
+        redis.call('ZREM', KEYS[1] .. ':vocab', word)
+      end
+    end
+    redis.call('HDEL', KEYS[1] .. ':words', id)
+  end
+  redis.call('ZREMRANGEBYSCORE', KEYS[1], id, id)
+  redis.call('ZREM', KEYS[1] .. ':ts', id)
+end
+"#;
+
+    /// Stores `ARGV[1]` (nick) and `ARGV[2]` (text) sent at `ARGV[3]` under the next id and
+    /// indexes it under the words in `ARGV[5..]`, then drops the oldest messages beyond
+    /// `ARGV[4]`. Returns the new id.
+    const PUSH: &str = r#"
+local id = redis.call('INCR', KEYS[1] .. ':seq')
+local item = {id = id, nick = ARGV[1], text = ARGV[2], ts = tonumber(ARGV[3])}
+redis.call('ZADD', KEYS[1], id, cjson.encode(item))
+index(id, ARGV[3], 5)
+local excess = redis.call('ZCARD', KEYS[1]) - tonumber(ARGV[4])
+if excess > 0 then
+  local old = redis.call('ZRANGE', KEYS[1], 0, excess - 1, 'WITHSCORES')
+  for i = 2, #old, 2 do
+    drop(old[i])
+  end
+end
+return id
+"#;
+
+    /// Indexes message `ARGV[1]`, sent at `ARGV[2]`, under the words in `ARGV[3..]` unless it
+    /// already is; for messages stored before the indexes existed.
+    const INDEX: &str = r#"
+if redis.call('ZSCORE', KEYS[1] .. ':ts', ARGV[1]) then
+  return 0
+end
+index(ARGV[1], ARGV[2], 3)
+return 1
+"#;
+
+    /// Removes the messages with `ARGV[1] <= ts < ARGV[2]`, found through the timestamp index
+    /// so clock skew between instances cannot make it miss any.
+    const PURGE: &str = r#"
+local ids = redis.call('ZRANGEBYSCORE', KEYS[1] .. ':ts', ARGV[1], '(' .. ARGV[2])
+for _, id in ipairs(ids) do
+  drop(id)
+end
+return #ids
+"#;
+
+    /// Returns up to `ARGV[1]` messages, newest first, that contain a word starting with each
+    /// of `ARGV[6..]`, sent by `ARGV[2]` (ASCII case ignored) and at or after `ARGV[3]` and
+    /// before `ARGV[4]`; empty filters match everything. KEYS[2] is the vocabulary, KEYS[3]
+    /// a scratch set and KEYS[4..] one scratch set per term. Fails with `TOOBROAD <term>` when a
+    /// term starts more than `ARGV[5]` indexed words.
+    const SEARCH: &str = r#"
+local most = tonumber(ARGV[5])
+local sets = {}
+for i = 6, #ARGV do
+  local words = redis.call('ZRANGEBYLEX', KEYS[2], '[' .. ARGV[i], '[' .. ARGV[i] .. '\255', 'LIMIT', 0, most + 1)
+  if #words == 0 or #words > most then
+    if #sets > 0 then
+      redis.call('DEL', unpack(sets))
+    end
+    if #words == 0 then
+      return {}
+    end
+    return redis.error_reply('TOOBROAD ' .. ARGV[i])
+  end
+  local set = KEYS[i - 2]
+  local args = {'ZUNIONSTORE', set, #words}
+  for _, word in ipairs(words) do
+    args[#args + 1] = KEYS[1] .. ':term:' .. word
+  end
+  args[#args + 1] = 'AGGREGATE'
+  args[#args + 1] = 'MAX'
+  redis.call(unpack(args))
+  sets[#sets + 1] = set
+end
+local args = {'ZINTERSTORE', KEYS[3], #sets}
+for _, set in ipairs(sets) do
+  args[#args + 1] = set
+end
+args[#args + 1] = 'AGGREGATE'
+args[#args + 1] = 'MAX'
+redis.call(unpack(args))
+local ids = redis.call('ZREVRANGE', KEYS[3], 0, -1)
+redis.call('DEL', KEYS[3], unpack(sets))
+local limit = tonumber(ARGV[1])
+local nick = string.lower(ARGV[2])
+local since = tonumber(ARGV[3])
+local before = tonumber(ARGV[4])
+local found = {}
+for _, id in ipairs(ids) do
+  if #found >= limit then
+    break
+  end
+  local raw = redis.call('ZRANGEBYSCORE', KEYS[1], id, id)[1]
+  local ok, item = pcall(cjson.decode, raw or '')
+  if ok and type(item) == 'table' then
+    local ts = tonumber(item['ts']) or 0
+    if (nick == '' or string.lower(tostring(item['nick'])) == nick)
+      and (not since or ts >= since) and (not before or ts < before) then
+      found[#found + 1] = raw
+    end
+  end
+end
+return found
+"#;
+
+    /// Messages in a sorted set scored by id, with the id counter in `<key>:seq`, each id
+    /// scored by send time in `<key>:ts` and a word index for search.
     pub struct RedisHistory {
         client: redis::Client,
         key: String,
         max: usize,
+        push: redis::Script,
+        index: redis::Script,
+        purge: redis::Script,
+        search: redis::Script,
+        conn: Mutex<Option<MultiplexedConnection>>,
     }
 
     impl RedisHistory {
@@ -71,36 +400,213 @@ pub mod redis_history {
                 client,
                 key: key.into(),
                 max,
+                push: redis::Script::new(&format!("{INDEXING}{PUSH}")),
+                index: redis::Script::new(&format!("{INDEXING}{INDEX}")),
+                purge: redis::Script::new(&format!("{INDEXING}{PURGE}")),
+                search: redis::Script::new(SEARCH),
+                conn: Mutex::new(None),
             }
         }
+
+        /// Reuses one multiplexed connection; see [`checked`](Self::checked).
+        async fn connection(&self) -> redis::RedisResult<MultiplexedConnection> {
+            let mut conn = self.conn.lock().await;
+            if let Some(conn) = conn.as_ref() {
+                return Ok(conn.clone());
+            }
+            let fresh = self.client.get_multiplexed_async_connection().await?;
+            *conn = Some(fresh.clone());
+            Ok(fresh)
+        }
+
+        /// Drops the connection after a failed call so the next one reconnects.
+        async fn checked<T>(&self, result: redis::RedisResult<T>) -> redis::RedisResult<T> {
+            if result.is_err() {
+                *self.conn.lock().await = None;
+            }
+            result
+        }
+
+        /// One-time upgrade from the list `legacy` that older versions wrote; returns how many
+        /// messages were moved. The list is deleted afterwards so purges cannot miss it, and
+        /// any message not indexed yet is added to the indexes.
+        pub async fn migrate_list(&self, legacy: &str) -> anyhow::Result<usize> {
+            let mut conn = self.connection().await?;
+            let result = redis::Script::new(MIGRATE_LIST)
+                .key(&self.key)
+                .key(format!("{}:seq", self.key))
+                .key(legacy)
+                .invoke_async(&mut conn)
+                .await;
+            let moved: usize = self.checked(result).await?;
+            for item in self.list().await? {
+                let mut invocation = self.index.key(&self.key);
+                invocation.arg(item.id).arg(item.ts).arg(index_words(&item.text));
+                let _: bool = self.checked(invocation.invoke_async(&mut conn).await).await?;
+            }
+            Ok(moved)
+        }
     }
 
     #[async_trait]
     impl HistoryStore for RedisHistory {
         async fn push(&self, nick: String, text: String) -> anyhow::Result<()> {
-            let mut conn = self.client.get_async_connection().await?;
-            let item = HistoryItem {
-                nick,
-                text,
-                ts: now_ts(),
-            };
-            let raw = serde_json::to_string(&item)?;
-            let _: () = conn.lpush(&self.key, raw).await?;
-            let _: () = conn.ltrim(&self.key, 0, (self.max as isize) - 1).await?;
+            let mut conn = self.connection().await?;
+            let words = index_words(&text);
+            let result = self
+                .push
+                .key(&self.key)
+                .arg(nick)
+                .arg(text)
+                .arg(now_ts())
+                .arg(self.max)
+                .arg(words)
+                .invoke_async(&mut conn)
+                .await;
+            let _: u64 = self.checked(result).await?;
             Ok(())
         }
 
         async fn list(&self) -> anyhow::Result<Vec<HistoryItem>> {
-            let mut conn = self.client.get_async_connection().await?;
-            let raws: Vec<String> = conn.lrange(&self.key, 0, -1).await?;
-            let mut out = Vec::new();
-            for raw in raws {
-                if let Ok(item) = serde_json::from_str::<HistoryItem>(&raw) {
-                    out.push(item);
+            let mut conn = self.connection().await?;
+            let raws: Vec<String> = self.checked(conn.zrange(&self.key, 0, -1).await).await?;
+            Ok(parse_items(raws))
+        }
+
+        async fn range(&self, query: HistoryQuery) -> anyhow::Result<Vec<HistoryItem>> {
+            let mut conn = self.connection().await?;
+            let min = query.after.map_or("-inf".to_string(), |id| format!("({id}"));
+            let max = query.before.map_or("+inf".to_string(), |id| format!("({id}"));
+            let limit = query.limit as isize;
+            let result = if query.forward() {
+                conn.zrangebyscore_limit(&self.key, min, max, 0, limit).await
+            } else {
+                conn.zrevrangebyscore_limit(&self.key, max, min, 0, limit).await
+            };
+            let mut items = parse_items(self.checked(result).await?);
+            if !query.forward() {
+                items.reverse();
+            }
+            Ok(items)
+        }
+
+        async fn search(&self, query: &SearchQuery) -> anyhow::Result<Vec<HistoryItem>> {
+            let mut conn = self.connection().await?;
+            let bound = |ts: Option<u64>| ts.map_or_else(String::new, |ts| ts.to_string());
+            let mut invocation = self.search.key(&self.key);
+            invocation
+                .key(format!("{}:vocab", self.key))
+                .key(format!("{}:search", self.key));
+            for i in 0..query.terms.len() {
+                invocation.key(format!("{}:search:{i}", self.key));
+            }
+            invocation
+                .arg(query.limit)
+                .arg(query.nick.as_deref().unwrap_or(""))
+                .arg(bound(query.since))
+                .arg(bound(query.until))
+                .arg(SEARCH_MAX_WORDS)
+                .arg(&query.terms);
+            let result = invocation.invoke_async(&mut conn).await;
+            if let Err(err) = &result {
+                if err.code() == Some("TOOBROAD") {
+                    return Err(TooBroad(err.detail().unwrap_or_default().to_string()).into());
                 }
             }
-            out.reverse();
-            Ok(out)
+            let raws: Vec<String> = self.checked(result).await?;
+            Ok(parse_items(raws))
+        }
+
+        async fn purge(&self, from: u64, until: u64) -> anyhow::Result<usize> {
+            let mut conn = self.connection().await?;
+            let result = self.purge.key(&self.key).arg(from).arg(until).invoke_async(&mut conn).await;
+            let removed: usize = self.checked(result).await?;
+            Ok(removed)
         }
     }
+
+    /// The distinct search terms of `text`.
+    fn index_words(text: &str) -> Vec<String> {
+        let mut words = search_terms(text);
+        words.sort();
+        words.dedup();
+        words
+    }
+
+    fn parse_items(raws: Vec<String>) -> Vec<HistoryItem> {
+        raws.iter()
+            .filter_map(|raw| serde_json::from_str::<HistoryItem>(raw).ok())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn pages_by_id() {
+        let history = InMemoryHistory::new(5);
+        for n in 1..=7 {
+            history.push("alice".into(), format!("m{n}")).await.unwrap();
+        }
+        let ids = |items: Vec<HistoryItem>| items.into_iter().map(|item| item.id).collect::<Vec<_>>();
+
+        // The oldest two were trimmed.
+        assert_eq!(ids(history.range(HistoryQuery::latest(2)).await.unwrap()), [6, 7]);
+        let older = HistoryQuery {
+            before: Some(6),
+            ..HistoryQuery::latest(10)
+        };
+        assert_eq!(ids(history.range(older).await.unwrap()), [3, 4, 5]);
+        let newer = HistoryQuery {
+            after: Some(3),
+            ..HistoryQuery::latest(2)
+        };
+        assert_eq!(ids(history.range(newer).await.unwrap()), [4, 5]);
+        let between = HistoryQuery {
+            before: Some(7),
+            after: Some(3),
+            limit: 2,
+        };
+        assert_eq!(ids(history.range(between).await.unwrap()), [5, 6]);
+    }
+
+    #[tokio::test]
+    async fn search_matches_word_prefixes() {
+        let history = InMemoryHistory::new(10);
+        history.push("alice".into(), "run cargo build --release".into()).await.unwrap();
+        history.push("bob".into(), "Cargo.toml needs a bump".into()).await.unwrap();
+        history.push("alice".into(), "lunch?".into()).await.unwrap();
+
+        let query = |text: &str| SearchQuery {
+            terms: search_terms(text),
+            limit: 10,
+            ..SearchQuery::default()
+        };
+        let found = history.search(&query("carg")).await.unwrap();
+        assert_eq!(found.iter().map(|item| item.id).collect::<Vec<_>>(), [2, 1]);
+        assert!(history.search(&query("argo")).await.unwrap().is_empty());
+        let from_alice = SearchQuery {
+            nick: Some("ALICE".into()),
+            ..query("cargo rel")
+        };
+        assert_eq!(history.search(&from_alice).await.unwrap().len(), 1);
+        let later = SearchQuery {
+            since: Some(now_ts() + 60),
+            ..query("cargo")
+        };
+        assert!(history.search(&later).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn purge_removes_time_range() {
+        let history = InMemoryHistory::new(10);
+        history.push("alice".into(), "one".into()).await.unwrap();
+        history.push("alice".into(), "two".into()).await.unwrap();
+        let now = now_ts();
+        assert_eq!(history.purge(0, now.saturating_sub(60)).await.unwrap(), 0);
+        assert_eq!(history.purge(now.saturating_sub(60), now + 1).await.unwrap(), 2);
+        assert!(history.list().await.unwrap().is_empty());
+    }
 }
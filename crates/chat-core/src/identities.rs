@@ -1,10 +1,13 @@
-use crate::util::{atomic_write, now_ts};
+use crate::addr::canonical_key;
+use crate::nick::nick_key;
+use crate::roles::Role;
+use crate::util::{atomic_write, load_toml, now_ts};
 use anyhow::Context;
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::net::IpAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tokio::sync::Mutex;
 use tracing::warn;
 
@@ -12,20 +15,73 @@ use tracing::warn;
 pub struct IdentityRecord {
     pub nick: String,
     pub updated: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<Role>,
 }
 
 #[async_trait]
 pub trait IdentityStore: Send + Sync {
     async fn get(&self, ip: IpAddr) -> anyhow::Result<Option<IdentityRecord>>;
     async fn set(&self, ip: IpAddr, nick: String) -> anyhow::Result<()>;
+    async fn set_role(&self, ip: IpAddr, role: Option<Role>) -> anyhow::Result<()>;
+    /// Stores `rec` as-is, replacing any existing identity for `ip`.
+    async fn put(&self, ip: IpAddr, rec: IdentityRecord) -> anyhow::Result<()>;
+    async fn touch(&self, ip: IpAddr) -> anyhow::Result<()>;
+    /// Writes out batched `touch` updates; stores that write through have nothing to do.
+    async fn flush(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
     async fn remove(&self, ip: IpAddr) -> anyhow::Result<()>;
     async fn list(&self) -> anyhow::Result<Vec<(IpAddr, IdentityRecord)>>;
+    /// Removes identities last updated before `cutoff` and returns them.
+    /// Identities with an explicit role are never pruned.
+    async fn prune(&self, cutoff: u64) -> anyhow::Result<Vec<(IpAddr, IdentityRecord)>>;
+    /// Returns an IP other than `except` whose stored nickname matches `nick`, compared by
+    /// confusable skeleton. Stores never drop records over a clash, so check before saving.
+    async fn nick_holder(&self, nick: &str, except: IpAddr) -> anyhow::Result<Option<IpAddr>> {
+        let key = nick_key(nick);
+        Ok(self
+            .list()
+            .await?
+            .into_iter()
+            .find(|(ip, rec)| *ip != except && nick_key(&rec.nick) == key)
+            .map(|(ip, _)| ip))
+    }
+}
+
+impl IdentityRecord {
+    pub fn expired(&self, cutoff: u64) -> bool {
+        self.role.is_none() && self.updated < cutoff
+    }
+}
+
+/// Serializes identities in the `identities.toml` layout, for backups and backend migration.
+pub fn export_toml(records: &[(IpAddr, IdentityRecord)]) -> anyhow::Result<String> {
+    let map: BTreeMap<String, &IdentityRecord> = records
+        .iter()
+        .map(|(ip, rec)| (ip.to_string(), rec))
+        .collect();
+    Ok(toml::to_string_pretty(&map)?)
+}
+
+pub fn import_toml(raw: &str) -> anyhow::Result<Vec<(IpAddr, IdentityRecord)>> {
+    let map = toml::from_str::<BTreeMap<String, IdentityRecord>>(raw).context("parse identities")?;
+    map.into_iter()
+        .map(|(ip, rec)| {
+            let addr = ip
+                .parse::<IpAddr>()
+                .with_context(|| format!("invalid ip {ip:?} in identities"))?;
+            Ok((addr, rec))
+        })
+        .collect()
 }
 
+/// Joins only queue their last-seen time; it reaches the file with the next write or `flush`.
 #[derive(Debug)]
 pub struct FileIdentityStore {
     path: PathBuf,
     lock: Mutex<()>,
+    touched: std::sync::Mutex<BTreeMap<String, u64>>,
 }
 
 impl FileIdentityStore {
@@ -33,37 +89,58 @@ impl FileIdentityStore {
         Self {
             path,
             lock: Mutex::new(()),
+            touched: std::sync::Mutex::new(BTreeMap::new()),
         }
     }
 
-    fn load_inner(path: &PathBuf) -> anyhow::Result<BTreeMap<String, IdentityRecord>> {
-        if !path.exists() {
-            return Ok(BTreeMap::new());
+    fn touched(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, u64>> {
+        self.touched.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn load_touched(
+        &self,
+        touched: &BTreeMap<String, u64>,
+    ) -> anyhow::Result<BTreeMap<String, IdentityRecord>> {
+        let mut map = Self::load_inner(&self.path)?;
+        for (ip, ts) in touched {
+            if let Some(rec) = map.get_mut(ip) {
+                rec.updated = rec.updated.max(*ts);
+            }
         }
-        let raw = std::fs::read_to_string(path).context("read identities")?;
-        let parsed = toml::from_str::<BTreeMap<String, IdentityRecord>>(&raw)
-            .unwrap_or_else(|_| BTreeMap::new());
-        Ok(parsed)
+        Ok(map)
     }
 
-    fn save_inner(path: &PathBuf, map: BTreeMap<String, IdentityRecord>) -> anyhow::Result<()> {
-        let mut nick_index: BTreeMap<String, (String, IdentityRecord)> = BTreeMap::new();
-        for (ip, rec) in map.into_iter() {
-            let key = rec.nick.to_lowercase();
-            match nick_index.get(&key) {
-                Some((_, existing)) if existing.updated >= rec.updated => {
-                    continue;
-                }
+    fn save_touched(
+        &self,
+        map: BTreeMap<String, IdentityRecord>,
+        touched: &mut BTreeMap<String, u64>,
+    ) -> anyhow::Result<()> {
+        Self::save_inner(&self.path, map)?;
+        touched.clear();
+        Ok(())
+    }
+
+    fn load_inner(path: &Path) -> anyhow::Result<BTreeMap<String, IdentityRecord>> {
+        let parsed: BTreeMap<String, IdentityRecord> = load_toml(path)?;
+        // Older files may key IPv4 clients as `::ffff:a.b.c.d`; keep the newer record.
+        let mut map: BTreeMap<String, IdentityRecord> = BTreeMap::new();
+        for (ip, rec) in parsed {
+            if ip.parse::<IpAddr>().is_err() {
+                anyhow::bail!("{}: invalid ip {ip:?}", path.display());
+            }
+            let key = canonical_key(&ip);
+            match map.get(&key) {
+                Some(existing) if existing.updated >= rec.updated => {}
                 _ => {
-                    nick_index.insert(key, (ip, rec));
+                    map.insert(key, rec);
                 }
             }
         }
-        let mut cleaned = BTreeMap::new();
-        for (_nick, (ip, rec)) in nick_index {
-            cleaned.insert(ip, rec);
-        }
-        let data = toml::to_string_pretty(&cleaned)?;
+        Ok(map)
+    }
+
+    fn save_inner(path: &Path, map: BTreeMap<String, IdentityRecord>) -> anyhow::Result<()> {
+        let data = toml::to_string_pretty(&map)?;
         atomic_write(path, data.as_bytes())
     }
 }
@@ -72,33 +149,73 @@ impl FileIdentityStore {
 impl IdentityStore for FileIdentityStore {
     async fn get(&self, ip: IpAddr) -> anyhow::Result<Option<IdentityRecord>> {
         let _guard = self.lock.lock().await;
-        let map = Self::load_inner(&self.path)?;
+        let map = self.load_touched(&self.touched())?;
         Ok(map.get(&ip.to_string()).cloned())
     }
 
     async fn set(&self, ip: IpAddr, nick: String) -> anyhow::Result<()> {
         let _guard = self.lock.lock().await;
-        let mut map = Self::load_inner(&self.path)?;
+        let mut touched = self.touched();
+        let mut map = self.load_touched(&touched)?;
+        let key = ip.to_string();
+        let role = map.get(&key).and_then(|rec| rec.role);
         map.insert(
-            ip.to_string(),
+            key,
             IdentityRecord {
                 nick,
                 updated: now_ts(),
+                role,
             },
         );
-        Self::save_inner(&self.path, map)
+        self.save_touched(map, &mut touched)
+    }
+
+    async fn set_role(&self, ip: IpAddr, role: Option<Role>) -> anyhow::Result<()> {
+        let _guard = self.lock.lock().await;
+        let mut touched = self.touched();
+        let mut map = self.load_touched(&touched)?;
+        let Some(rec) = map.get_mut(&ip.to_string()) else {
+            anyhow::bail!("no identity for {ip}");
+        };
+        rec.role = role;
+        self.save_touched(map, &mut touched)
+    }
+
+    async fn put(&self, ip: IpAddr, rec: IdentityRecord) -> anyhow::Result<()> {
+        let _guard = self.lock.lock().await;
+        let mut touched = self.touched();
+        let mut map = self.load_touched(&touched)?;
+        touched.remove(&ip.to_string());
+        map.insert(ip.to_string(), rec);
+        self.save_touched(map, &mut touched)
+    }
+
+    async fn touch(&self, ip: IpAddr) -> anyhow::Result<()> {
+        self.touched().insert(ip.to_string(), now_ts());
+        Ok(())
+    }
+
+    async fn flush(&self) -> anyhow::Result<()> {
+        let _guard = self.lock.lock().await;
+        let mut touched = self.touched();
+        if touched.is_empty() {
+            return Ok(());
+        }
+        let map = self.load_touched(&touched)?;
+        self.save_touched(map, &mut touched)
     }
 
     async fn remove(&self, ip: IpAddr) -> anyhow::Result<()> {
         let _guard = self.lock.lock().await;
-        let mut map = Self::load_inner(&self.path)?;
+        let mut touched = self.touched();
+        let mut map = self.load_touched(&touched)?;
         map.remove(&ip.to_string());
-        Self::save_inner(&self.path, map)
+        self.save_touched(map, &mut touched)
     }
 
     async fn list(&self) -> anyhow::Result<Vec<(IpAddr, IdentityRecord)>> {
         let _guard = self.lock.lock().await;
-        let map = Self::load_inner(&self.path)?;
+        let map = self.load_touched(&self.touched())?;
         let mut out = Vec::new();
         for (ip, rec) in map {
             match ip.parse::<IpAddr>() {
@@ -108,17 +225,88 @@ impl IdentityStore for FileIdentityStore {
         }
         Ok(out)
     }
+
+    async fn prune(&self, cutoff: u64) -> anyhow::Result<Vec<(IpAddr, IdentityRecord)>> {
+        let _guard = self.lock.lock().await;
+        let mut touched = self.touched();
+        let map = self.load_touched(&touched)?;
+        let (expired, kept): (BTreeMap<_, _>, BTreeMap<_, _>) =
+            map.into_iter().partition(|(_, rec)| rec.expired(cutoff));
+        if expired.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.save_touched(kept, &mut touched)?;
+        Ok(expired
+            .into_iter()
+            .filter_map(|(ip, rec)| ip.parse::<IpAddr>().ok().map(|addr| (addr, rec)))
+            .collect())
+    }
 }
 
 #[cfg(feature = "redis")]
 pub mod redis_store {
     use super::*;
+    use redis::aio::MultiplexedConnection;
     use redis::AsyncCommands;
+    use tokio::sync::Mutex;
+
+    // Read-modify-write updates run as scripts so a concurrent touch or role change is never lost.
+
+    /// Sets the nickname of `ARGV[1]`, keeping any role already stored.
+    const SET: &str = r#"
+local rec = {nick = ARGV[2], updated = tonumber(ARGV[3])}
+local raw = redis.call('HGET', KEYS[1], ARGV[1])
+if raw then
+  local ok, old = pcall(cjson.decode, raw)
+  if ok and type(old.role) == 'string' then rec.role = old.role end
+end
+redis.call('HSET', KEYS[1], ARGV[1], cjson.encode(rec))
+"#;
+
+    /// Replaces the role of `ARGV[1]` (an empty `ARGV[2]` clears it); returns 0 when there is no record.
+    const SET_ROLE: &str = r#"
+local raw = redis.call('HGET', KEYS[1], ARGV[1])
+if not raw then return 0 end
+local rec = cjson.decode(raw)
+if ARGV[2] == '' then rec.role = nil else rec.role = ARGV[2] end
+redis.call('HSET', KEYS[1], ARGV[1], cjson.encode(rec))
+return 1
+"#;
+
+    const TOUCH: &str = r#"
+local raw = redis.call('HGET', KEYS[1], ARGV[1])
+if not raw then return 0 end
+local rec = cjson.decode(raw)
+rec.updated = tonumber(ARGV[2])
+redis.call('HSET', KEYS[1], ARGV[1], cjson.encode(rec))
+return 1
+"#;
+
+    /// Deletes role-less records updated before `ARGV[1]`, returning them as field/value pairs.
+    const PRUNE: &str = r#"
+local all = redis.call('HGETALL', KEYS[1])
+local cutoff = tonumber(ARGV[1])
+local expired = {}
+for i = 1, #all, 2 do
+  local ok, rec = pcall(cjson.decode, all[i + 1])
+  local updated = ok and tonumber(rec.updated)
+  if updated and type(rec.role) ~= 'string' and updated < cutoff then
+    redis.call('HDEL', KEYS[1], all[i])
+    expired[#expired + 1] = all[i]
+    expired[#expired + 1] = all[i + 1]
+  end
+end
+return expired
+"#;
 
-    #[derive(Clone)]
     pub struct RedisIdentityStore {
         client: redis::Client,
         key: String,
+        set: redis::Script,
+        set_role: redis::Script,
+        touch: redis::Script,
+        prune: redis::Script,
+        conn: Mutex<Option<MultiplexedConnection>>,
     }
 
     impl RedisIdentityStore {
@@ -126,41 +314,105 @@ pub mod redis_store {
             Self {
                 client,
                 key: key.into(),
+                set: redis::Script::new(SET),
+                set_role: redis::Script::new(SET_ROLE),
+                touch: redis::Script::new(TOUCH),
+                prune: redis::Script::new(PRUNE),
+                conn: Mutex::new(None),
+            }
+        }
+
+        /// Reuses one multiplexed connection; see [`checked`](Self::checked).
+        async fn connection(&self) -> redis::RedisResult<MultiplexedConnection> {
+            let mut conn = self.conn.lock().await;
+            if let Some(conn) = conn.as_ref() {
+                return Ok(conn.clone());
             }
+            let fresh = self.client.get_multiplexed_async_connection().await?;
+            *conn = Some(fresh.clone());
+            Ok(fresh)
+        }
+
+        /// Drops the connection after a failed call so the next one reconnects.
+        async fn checked<T>(&self, result: redis::RedisResult<T>) -> redis::RedisResult<T> {
+            if result.is_err() {
+                *self.conn.lock().await = None;
+            }
+            result
         }
     }
 
     #[async_trait]
     impl IdentityStore for RedisIdentityStore {
         async fn get(&self, ip: IpAddr) -> anyhow::Result<Option<IdentityRecord>> {
-            let mut conn = self.client.get_async_connection().await?;
-            let raw: Option<String> = conn.hget(&self.key, ip.to_string()).await?;
+            let mut conn = self.connection().await?;
+            let raw: Option<String> = self.checked(conn.hget(&self.key, ip.to_string()).await).await?;
             Ok(raw.map(|s| serde_json::from_str(&s).unwrap_or(IdentityRecord {
                 nick: String::new(),
                 updated: 0,
+                role: None,
             })))
         }
 
         async fn set(&self, ip: IpAddr, nick: String) -> anyhow::Result<()> {
-            let mut conn = self.client.get_async_connection().await?;
-            let rec = IdentityRecord {
-                nick,
-                updated: now_ts(),
-            };
+            let mut conn = self.connection().await?;
+            let result = self
+                .set
+                .key(&self.key)
+                .arg(ip.to_string())
+                .arg(nick)
+                .arg(now_ts())
+                .invoke_async(&mut conn)
+                .await;
+            let _: () = self.checked(result).await?;
+            Ok(())
+        }
+
+        async fn set_role(&self, ip: IpAddr, role: Option<Role>) -> anyhow::Result<()> {
+            let mut conn = self.connection().await?;
+            let result = self
+                .set_role
+                .key(&self.key)
+                .arg(ip.to_string())
+                .arg(role.map_or("", |r| r.as_str()))
+                .invoke_async(&mut conn)
+                .await;
+            let found: bool = self.checked(result).await?;
+            if !found {
+                anyhow::bail!("no identity for {ip}");
+            }
+            Ok(())
+        }
+
+        async fn put(&self, ip: IpAddr, rec: IdentityRecord) -> anyhow::Result<()> {
+            let mut conn = self.connection().await?;
             let raw = serde_json::to_string(&rec)?;
-            let _: () = conn.hset(&self.key, ip.to_string(), raw).await?;
+            let _: () = self.checked(conn.hset(&self.key, ip.to_string(), raw).await).await?;
+            Ok(())
+        }
+
+        async fn touch(&self, ip: IpAddr) -> anyhow::Result<()> {
+            let mut conn = self.connection().await?;
+            let result = self
+                .touch
+                .key(&self.key)
+                .arg(ip.to_string())
+                .arg(now_ts())
+                .invoke_async(&mut conn)
+                .await;
+            let _: bool = self.checked(result).await?;
             Ok(())
         }
 
         async fn remove(&self, ip: IpAddr) -> anyhow::Result<()> {
-            let mut conn = self.client.get_async_connection().await?;
-            let _: () = conn.hdel(&self.key, ip.to_string()).await?;
+            let mut conn = self.connection().await?;
+            let _: () = self.checked(conn.hdel(&self.key, ip.to_string()).await).await?;
             Ok(())
         }
 
         async fn list(&self) -> anyhow::Result<Vec<(IpAddr, IdentityRecord)>> {
-            let mut conn = self.client.get_async_connection().await?;
-            let map: BTreeMap<String, String> = conn.hgetall(&self.key).await?;
+            let mut conn = self.connection().await?;
+            let map: BTreeMap<String, String> = self.checked(conn.hgetall(&self.key).await).await?;
             let mut out = Vec::new();
             for (ip, raw) in map {
                 if let Ok(addr) = ip.parse::<IpAddr>() {
@@ -171,6 +423,16 @@ pub mod redis_store {
             }
             Ok(out)
         }
+
+        async fn prune(&self, cutoff: u64) -> anyhow::Result<Vec<(IpAddr, IdentityRecord)>> {
+            let mut conn = self.connection().await?;
+            let result = self.prune.key(&self.key).arg(cutoff).invoke_async(&mut conn).await;
+            let pairs: Vec<(String, String)> = self.checked(result).await?;
+            Ok(pairs
+                .into_iter()
+                .filter_map(|(ip, raw)| Some((ip.parse().ok()?, serde_json::from_str(&raw).ok()?)))
+                .collect())
+        }
     }
 }
 
@@ -191,4 +453,96 @@ mod tests {
         let rec = store.get(ip).await.unwrap().unwrap();
         assert_eq!(rec.nick, "alice");
     }
+
+    #[tokio::test]
+    async fn prune_drops_stale_identities() {
+        let dir = tempdir().unwrap();
+        let store = FileIdentityStore::new(dir.path().join("identities.toml"));
+        let old = IpAddr::from_str("10.0.0.1").unwrap();
+        let staff = IpAddr::from_str("10.0.0.2").unwrap();
+        let fresh = IpAddr::from_str("10.0.0.3").unwrap();
+        store.set(old, "old".into()).await.unwrap();
+        store.set(staff, "staff".into()).await.unwrap();
+        store.set_role(staff, Some(Role::Admin)).await.unwrap();
+        store.set(fresh, "fresh".into()).await.unwrap();
+
+        let pruned = store.prune(now_ts() + 1).await.unwrap();
+        let ips: Vec<IpAddr> = pruned.into_iter().map(|(ip, _)| ip).collect();
+        assert_eq!(ips, vec![old, fresh]);
+        assert!(store.get(staff).await.unwrap().is_some());
+        assert!(store.prune(now_ts() + 1).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn touch_is_batched_until_flush() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("identities.toml");
+        let store = FileIdentityStore::new(path.clone());
+        let ip = IpAddr::from_str("10.0.0.1").unwrap();
+        store
+            .put(ip, IdentityRecord { nick: "old".into(), updated: 1, role: None })
+            .await
+            .unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+
+        store.touch(ip).await.unwrap();
+        store.touch(IpAddr::from_str("10.0.0.9").unwrap()).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), written);
+        assert!(store.get(ip).await.unwrap().unwrap().updated > 1);
+        assert!(store.prune(2).await.unwrap().is_empty());
+
+        store.flush().await.unwrap();
+        let reopened = FileIdentityStore::new(path);
+        assert!(reopened.get(ip).await.unwrap().unwrap().updated > 1);
+        assert_eq!(reopened.list().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn export_import_roundtrip() {
+        let dir = tempdir().unwrap();
+        let src = FileIdentityStore::new(dir.path().join("a.toml"));
+        let ip = IpAddr::from_str("192.0.2.7").unwrap();
+        src.set(ip, "carol".into()).await.unwrap();
+        src.set_role(ip, Some(Role::Moderator)).await.unwrap();
+        let raw = export_toml(&src.list().await.unwrap()).unwrap();
+
+        let dst = FileIdentityStore::new(dir.path().join("b.toml"));
+        for (ip, rec) in import_toml(&raw).unwrap() {
+            dst.put(ip, rec).await.unwrap();
+        }
+        let rec = dst.get(ip).await.unwrap().unwrap();
+        assert_eq!(rec.nick, "carol");
+        assert_eq!(rec.role, Some(Role::Moderator));
+        assert!(import_toml("[\"not-an-ip\"]\nnick = \"x\"\nupdated = 0\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn role_survives_nick_change() {
+        let dir = tempdir().unwrap();
+        let store = FileIdentityStore::new(dir.path().join("identities.toml"));
+        let ip = IpAddr::from_str("127.0.0.1").unwrap();
+        assert!(store.set_role(ip, Some(Role::Admin)).await.is_err());
+        store.set(ip, "alice".into()).await.unwrap();
+        store.set_role(ip, Some(Role::Admin)).await.unwrap();
+        store.set(ip, "alicia".into()).await.unwrap();
+        let rec = store.get(ip).await.unwrap().unwrap();
+        assert_eq!(rec.nick, "alicia");
+        assert_eq!(rec.role, Some(Role::Admin));
+    }
+
+    #[tokio::test]
+    async fn lookalike_nick_keeps_other_identity() {
+        let dir = tempdir().unwrap();
+        let store = FileIdentityStore::new(dir.path().join("identities.toml"));
+        let alice = IpAddr::from_str("10.0.0.1").unwrap();
+        let other = IpAddr::from_str("10.0.0.2").unwrap();
+        store.set(alice, "alice".into()).await.unwrap();
+        store.set_role(alice, Some(Role::Admin)).await.unwrap();
+        assert_eq!(store.nick_holder("a1ice", other).await.unwrap(), Some(alice));
+        assert_eq!(store.nick_holder("alice", alice).await.unwrap(), None);
+        // Saving a clashing record must not silently delete the older holder.
+        store.set(other, "a1ice".into()).await.unwrap();
+        let rec = store.get(alice).await.unwrap().unwrap();
+        assert_eq!(rec.role, Some(Role::Admin));
+    }
 }
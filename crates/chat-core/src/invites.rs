@@ -0,0 +1,246 @@
+use crate::util::{atomic_write, load_toml, now_ts, random_code};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub const INVITE_CODE_LEN: usize = 12;
+/// Failed redemptions allowed per client key within `REDEEM_WINDOW` seconds.
+pub const REDEEM_FAILURES_MAX: u32 = 5;
+pub const REDEEM_WINDOW: u64 = 60 * 60;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Redemption {
+    pub ip: String,
+    pub at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Invite {
+    pub code: String,
+    /// Number of redemptions allowed in total.
+    pub uses: u32,
+    pub created: u64,
+    pub created_by: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub revoked: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub redemptions: Vec<Redemption>,
+}
+
+impl Invite {
+    pub fn remaining(&self) -> u32 {
+        self.uses.saturating_sub(self.redemptions.len() as u32)
+    }
+
+    /// Why the invite cannot be redeemed at `now`, if it cannot.
+    pub fn unusable(&self, now: u64) -> Option<&'static str> {
+        if self.revoked {
+            Some("revoked")
+        } else if self.expires.map(|exp| exp <= now).unwrap_or(false) {
+            Some("expired")
+        } else if self.remaining() == 0 {
+            Some("used up")
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct InviteList {
+    #[serde(default)]
+    pub invite: Vec<Invite>,
+}
+
+impl InviteList {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        load_toml(path)
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let data = toml::to_string_pretty(self)?;
+        atomic_write(path, data.as_bytes())
+    }
+}
+
+/// `invites.toml` plus tracking of failed redemptions per client key (see `IpKeying`).
+#[derive(Debug)]
+pub struct Invites {
+    path: PathBuf,
+    lock: Mutex<()>,
+    failures: Mutex<HashMap<IpAddr, (u64, u32)>>,
+}
+
+impl Invites {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            lock: Mutex::new(()),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn create(
+        &self,
+        uses: u32,
+        expires: Option<u64>,
+        created_by: &str,
+        note: Option<String>,
+    ) -> anyhow::Result<Invite> {
+        if uses == 0 {
+            anyhow::bail!("an invite needs at least one use");
+        }
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut list = InviteList::load(&self.path)?;
+        let invite = Invite {
+            code: random_code(INVITE_CODE_LEN),
+            uses,
+            created: now_ts(),
+            created_by: created_by.to_string(),
+            note,
+            expires,
+            revoked: false,
+            redemptions: Vec::new(),
+        };
+        list.invite.push(invite.clone());
+        list.save(&self.path)?;
+        Ok(invite)
+    }
+
+    pub fn revoke(&self, code: &str) -> anyhow::Result<bool> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut list = InviteList::load(&self.path)?;
+        let Some(invite) = list.invite.iter_mut().find(|i| i.code.eq_ignore_ascii_case(code)) else {
+            return Ok(false);
+        };
+        invite.revoked = true;
+        list.save(&self.path)?;
+        Ok(true)
+    }
+
+    pub fn list(&self) -> anyhow::Result<Vec<Invite>> {
+        Ok(InviteList::load(&self.path)?.invite)
+    }
+
+    /// Whether `key` has used up its failed attempts for now.
+    pub fn locked_out(&self, key: IpAddr) -> bool {
+        let now = now_ts();
+        let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        failures.retain(|_, (since, _)| since.saturating_add(REDEEM_WINDOW) > now);
+        failures
+            .get(&key)
+            .map(|(_, count)| *count >= REDEEM_FAILURES_MAX)
+            .unwrap_or(false)
+    }
+
+    /// Consumes one use of `code` for `ip` and returns the invite as it was before.
+    /// Failures count against `key`, so IPv6 clients cannot dodge the lockout by
+    /// rotating addresses within their prefix.
+    pub fn redeem(&self, code: &str, ip: IpAddr, key: IpAddr) -> anyhow::Result<Invite> {
+        if self.locked_out(key) {
+            anyhow::bail!("too many failed attempts");
+        }
+        let result = self.redeem_inner(code.trim(), ip);
+        if result.is_err() {
+            let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+            failures.entry(key).or_insert((now_ts(), 0)).1 += 1;
+        }
+        result
+    }
+
+    /// Hands back the use `ip` took of `code`, for when the redemption could not be completed.
+    pub fn unredeem(&self, code: &str, ip: IpAddr) -> anyhow::Result<()> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut list = InviteList::load(&self.path)?;
+        let invite = list
+            .invite
+            .iter_mut()
+            .find(|i| i.code.eq_ignore_ascii_case(code))
+            .context("unknown invite code")?;
+        let ip = ip.to_string();
+        let pos = invite
+            .redemptions
+            .iter()
+            .rposition(|r| r.ip == ip)
+            .with_context(|| format!("no redemption of {code} by {ip}"))?;
+        invite.redemptions.remove(pos);
+        list.save(&self.path)
+    }
+
+    fn redeem_inner(&self, code: &str, ip: IpAddr) -> anyhow::Result<Invite> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let now = now_ts();
+        let mut list = InviteList::load(&self.path)?;
+        let invite = list
+            .invite
+            .iter_mut()
+            .find(|i| i.code.eq_ignore_ascii_case(code))
+            .context("unknown invite code")?;
+        if let Some(why) = invite.unusable(now) {
+            anyhow::bail!("invite {why}");
+        }
+        let before = invite.clone();
+        invite.redemptions.push(Redemption {
+            ip: ip.to_string(),
+            at: now,
+        });
+        list.save(&self.path)?;
+        Ok(before)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::addr::IpKeying;
+    use tempfile::tempdir;
+
+    #[test]
+    fn redeem_consumes_uses() {
+        let dir = tempdir().unwrap();
+        let invites = Invites::new(dir.path().join("invites.toml"));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let invite = invites.create(1, None, "ops", None).unwrap();
+        assert_eq!(invite.code.len(), INVITE_CODE_LEN);
+
+        let used = invites.redeem(&invite.code.to_lowercase(), ip, ip).unwrap();
+        assert_eq!(used.created_by, "ops");
+        assert!(invites.redeem(&invite.code, ip, ip).is_err());
+        assert_eq!(invites.list().unwrap()[0].redemptions[0].ip, "192.0.2.1");
+        invites.unredeem(&invite.code, ip).unwrap();
+        assert_eq!(invites.list().unwrap()[0].remaining(), 1);
+        assert!(invites.unredeem(&invite.code, ip).is_err());
+
+        let revoked = invites.create(3, None, "ops", None).unwrap();
+        assert!(invites.revoke(&revoked.code).unwrap());
+        assert!(invites.redeem(&revoked.code, ip, ip).is_err());
+
+        let expired = invites.create(1, Some(now_ts() - 1), "ops", None).unwrap();
+        assert!(invites.redeem(&expired.code, ip, ip).is_err());
+    }
+
+    #[test]
+    fn failed_redemptions_lock_out() {
+        let dir = tempdir().unwrap();
+        let invites = Invites::new(dir.path().join("invites.toml"));
+        let keying = IpKeying::new(64).unwrap();
+        let invite = invites.create(1, None, "ops", None).unwrap();
+        for n in 0..REDEEM_FAILURES_MAX {
+            // Each attempt from a different address in the same /64.
+            let ip: IpAddr = format!("2001:db8::{}", n + 1).parse().unwrap();
+            assert!(invites.redeem("WRONGWRONG22", ip, keying.key(ip)).is_err());
+        }
+        let ip: IpAddr = "2001:db8::ff".parse().unwrap();
+        assert!(invites.locked_out(keying.key(ip)));
+        assert!(invites.redeem(&invite.code, ip, keying.key(ip)).is_err());
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        assert!(invites.redeem(&invite.code, other, keying.key(other)).is_ok());
+    }
+}
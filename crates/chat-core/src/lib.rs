@@ -1,12 +1,37 @@
+pub mod access;
+pub mod addr;
 pub mod allowlist;
+pub mod audit;
+pub mod bans;
+pub mod conns;
 pub mod history;
 pub mod identities;
+pub mod invites;
+pub mod nick;
 pub mod protocol;
+pub mod proxy;
 pub mod rate;
+pub mod roles;
+pub mod slow;
+pub mod spam;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
 pub mod util;
 
-pub use allowlist::{AllowedList, PendingEntry, PendingList};
-pub use history::{HistoryItem, HistoryStore, InMemoryHistory};
+pub use access::{AccessCache, AccessSnapshot};
+pub use addr::{canonical_ip, IpKeying};
+pub use allowlist::{Access, AllowEntry, AllowedList, DenyEntry, DenyList, Knock, ListStore, PendingEntry, PendingList};
+pub use audit::{AuditEvent, AuditLog};
+pub use bans::{Ban, BanPolicy, Offense, Offenders};
+pub use conns::{AcceptLimiter, AcceptStats, ConnGuard, ConnLimits, ConnRefusal};
+pub use history::{search_terms, HistoryItem, HistoryQuery, HistoryStore, InMemoryHistory, RetentionPolicy, SearchQuery};
 pub use identities::{FileIdentityStore, IdentityRecord, IdentityStore};
+pub use invites::{Invite, InviteList, Invites};
+pub use nick::{NickError, NickPolicy};
 pub use protocol::{ClientMsg, ServerMsg, MAX_LINE, MAX_NICK};
-pub use rate::{RateLimiter, RateWindow};
+pub use rate::{
+    CommandCosts, Limiter, RateAlgorithm, RateBackend, RateConfig, RateLimiter, RateWindow, SharedLimiter, TokenBucket,
+};
+pub use roles::{Permission, Role, RolesConfig};
+pub use slow::SlowMode;
+pub use spam::{SpamAction, SpamActions, SpamFilter, SpamKind, SpamPolicy, Verdict};
@@ -0,0 +1,184 @@
+use crate::protocol::MAX_NICK;
+use crate::util::load_toml;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::{skeleton, GeneralSecurityProfile, MixedScript};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CharClass {
+    /// ASCII letters and digits only.
+    #[default]
+    Ascii,
+    /// Letters and digits allowed in identifiers by UTS #39, from a single script.
+    Unicode,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NickError {
+    Empty,
+    TooShort,
+    TooLong,
+    InvalidChars,
+    MixedScript,
+    Reserved,
+    Blocked,
+}
+
+impl fmt::Display for NickError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            NickError::Empty => "invalid nickname",
+            NickError::TooShort => "nickname too short",
+            NickError::TooLong => "nickname too long",
+            NickError::InvalidChars => "nickname contains invalid characters",
+            NickError::MixedScript => "nickname mixes scripts",
+            NickError::Reserved => "nickname is reserved",
+            NickError::Blocked => "nickname is not allowed",
+        };
+        f.write_str(text)
+    }
+}
+
+impl std::error::Error for NickError {}
+
+/// Rules a nickname must pass before it can be taken, loaded from `nick_policy.toml`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NickPolicy {
+    pub min_len: usize,
+    pub max_len: usize,
+    pub charset: CharClass,
+    /// Punctuation allowed in addition to the character class.
+    pub extra_chars: String,
+    /// Names nobody may take, compared by confusable skeleton.
+    pub reserved: Vec<String>,
+    /// Words that may not appear anywhere in a nickname.
+    pub blocked: Vec<String>,
+}
+
+impl Default for NickPolicy {
+    fn default() -> Self {
+        Self {
+            min_len: 1,
+            max_len: MAX_NICK,
+            charset: CharClass::Ascii,
+            extra_chars: "_-.".into(),
+            reserved: [
+                "admin", "administrator", "root", "system", "sys", "server", "chatd", "owner",
+                "moderator", "mod", "operator",
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect(),
+            blocked: Vec::new(),
+        }
+    }
+}
+
+impl NickPolicy {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        load_toml(path)
+    }
+
+    /// Normalizes `raw` and checks it against the policy, returning the nickname to use.
+    pub fn validate(&self, raw: &str) -> Result<String, NickError> {
+        let nick = normalize(raw);
+        if nick.is_empty() {
+            return Err(NickError::Empty);
+        }
+        let len = nick.chars().count();
+        if len < self.min_len {
+            return Err(NickError::TooShort);
+        }
+        if len > self.max_len || nick.len() > MAX_NICK {
+            return Err(NickError::TooLong);
+        }
+        if !nick.chars().all(|c| self.char_allowed(c)) {
+            return Err(NickError::InvalidChars);
+        }
+        if self.charset == CharClass::Unicode && !nick.as_str().is_single_script() {
+            return Err(NickError::MixedScript);
+        }
+        let key = nick_key(&nick);
+        if self.reserved.iter().any(|r| nick_key(r) == key) {
+            return Err(NickError::Reserved);
+        }
+        if self.blocked.iter().any(|b| key.contains(&nick_key(b))) {
+            return Err(NickError::Blocked);
+        }
+        Ok(nick)
+    }
+
+    fn char_allowed(&self, c: char) -> bool {
+        if self.extra_chars.contains(c) {
+            return true;
+        }
+        match self.charset {
+            CharClass::Ascii => c.is_ascii_alphanumeric(),
+            CharClass::Unicode => c.is_alphanumeric() && c.identifier_allowed(),
+        }
+    }
+}
+
+/// NFKC-normalizes a nickname and trims surrounding whitespace.
+pub fn normalize(nick: &str) -> String {
+    nick.trim().nfkc().collect()
+}
+
+/// Uniqueness key: two nicknames collide when their keys are equal.
+///
+/// This is the lowercased UTS #39 confusable skeleton, so `b0b` and `BOB` map
+/// to the same key, as does `alice` spelled with a Cyrillic `а`.
+pub fn nick_key(nick: &str) -> String {
+    skeleton(&normalize(nick).to_lowercase())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_policy() {
+        let policy = NickPolicy::default();
+        assert_eq!(policy.validate("  alice_1 ").unwrap(), "alice_1");
+        assert_eq!(policy.validate("Admin"), Err(NickError::Reserved));
+        assert_eq!(policy.validate("ali\u{200b}ce"), Err(NickError::InvalidChars));
+        assert_eq!(policy.validate("élise"), Err(NickError::InvalidChars));
+        assert_eq!(policy.validate(&"a".repeat(MAX_NICK + 1)), Err(NickError::TooLong));
+    }
+
+    #[test]
+    fn unicode_policy() {
+        let policy = NickPolicy {
+            charset: CharClass::Unicode,
+            blocked: vec!["spam".into()],
+            ..NickPolicy::default()
+        };
+        assert_eq!(policy.validate("élise").unwrap(), "élise");
+        assert_eq!(policy.validate("Ｍａｒｋ").unwrap(), "Mark");
+        // Latin "p" followed by Cyrillic "а"
+        assert_eq!(policy.validate("p\u{0430}ul"), Err(NickError::MixedScript));
+        assert_eq!(policy.validate("R00T"), Err(NickError::Reserved));
+        assert_eq!(policy.validate("xSpamx"), Err(NickError::Blocked));
+        assert!(policy.validate("ali\u{200b}ce").is_err());
+    }
+
+    #[test]
+    fn policy_rejects_unknown_keys() {
+        assert!(toml::from_str::<NickPolicy>("max_len = 16\nreserverd = [\"root\"]\n").is_err());
+    }
+
+    #[test]
+    fn confusable_keys() {
+        assert_eq!(nick_key("bob"), nick_key("B0B"));
+        assert_eq!(nick_key("alice"), nick_key("ALICE"));
+        assert_eq!(nick_key("alice"), nick_key("a1ice"));
+        assert_eq!(nick_key("alice"), nick_key("\u{0430}lice"));
+        assert_ne!(nick_key("alice"), nick_key("alicia"));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::roles::Role;
 use serde::{Deserialize, Serialize};
 
 pub const MAX_LINE: usize = 1024;
@@ -10,15 +11,52 @@ pub enum ClientMsg {
     Who,
     Quit,
     Prompt { id: String, answer: String },
+    Whois { nick: String },
+    Kick { nick: String, reason: Option<String> },
+    Role { nick: String, role: Role },
+    Pending,
+    Approve { ip: String },
+    Reject { ip: String },
+    Conns,
+    Offenders,
+    /// Shows the slow-mode interval, or sets it in seconds (0 turns it off).
+    Slow { seconds: Option<u64> },
+    /// Asks for a page of history; the cursors are message ids from HIST2 frames.
+    History {
+        before: Option<u64>,
+        after: Option<u64>,
+        limit: Option<usize>,
+    },
+    /// Full-text search of history; `since`/`until` are unix seconds.
+    Search {
+        query: String,
+        nick: Option<String>,
+        since: Option<u64>,
+        until: Option<u64>,
+    },
+    /// Deletes stored messages sent in `from..until`, unix seconds.
+    Purge { from: u64, until: u64 },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ServerMsg {
     Sys { text: String },
     Msg { nick: String, text: String },
+    /// A replayed message in the layout clients before HIST2 parse; only sent on join.
     Hist { nick: String, text: String },
+    /// A history message with its id (the paging cursor) and send time in unix seconds.
+    Hist2 { id: u64, ts: u64, nick: String, text: String },
+    /// Ends a page of HIST2 frames; `more` is set when older (or, paging forward, newer) messages remain.
+    HistEnd { more: bool },
+    /// A search result, kept apart from HIST2 so clients can render it differently.
+    Found { id: u64, ts: u64, nick: String, text: String },
+    FoundEnd { count: usize },
     Who { count: usize, nicks: Vec<String> },
     Prompt { id: String, text: String },
+    Whois { nick: String, role: Role },
+    Pending { ip: String, attempts: u64 },
+    /// Refusal with a machine-readable reason code, sent right before the server hangs up.
+    Error { code: String, text: String },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -84,20 +122,189 @@ pub fn parse_client_line(line: &str) -> Result<ClientMsg, ParseError> {
             }
             Ok(ClientMsg::Prompt { id, answer })
         }
+        "WHOIS" => {
+            if rest.is_empty() {
+                return Err(ParseError::new("missing nickname"));
+            }
+            Ok(ClientMsg::Whois {
+                nick: rest.to_string(),
+            })
+        }
+        "KICK" => {
+            let mut parts = rest.splitn(2, ' ');
+            let nick = parts.next().unwrap_or("").trim().to_string();
+            let reason = parts
+                .next()
+                .map(|r| r.trim().to_string())
+                .filter(|r| !r.is_empty());
+            if nick.is_empty() {
+                return Err(ParseError::new("missing nickname"));
+            }
+            Ok(ClientMsg::Kick { nick, reason })
+        }
+        "ROLE" => {
+            let mut parts = rest.split_whitespace();
+            let nick = parts.next().unwrap_or("").to_string();
+            let role = parts.next().unwrap_or("");
+            if nick.is_empty() || role.is_empty() {
+                return Err(ParseError::new("invalid ROLE"));
+            }
+            let role = role
+                .parse::<Role>()
+                .map_err(|_| ParseError::new("unknown role"))?;
+            Ok(ClientMsg::Role { nick, role })
+        }
+        "PENDING" => Ok(ClientMsg::Pending),
+        "CONNS" => Ok(ClientMsg::Conns),
+        "OFFENDERS" => Ok(ClientMsg::Offenders),
+        "SLOW" => {
+            if rest.is_empty() {
+                return Ok(ClientMsg::Slow { seconds: None });
+            }
+            let seconds = rest
+                .parse::<u64>()
+                .map_err(|_| ParseError::new("invalid SLOW"))?;
+            Ok(ClientMsg::Slow {
+                seconds: Some(seconds),
+            })
+        }
+        "HISTORY" => {
+            let (mut before, mut after, mut limit) = (None, None, None);
+            for part in rest.split_whitespace() {
+                let invalid = || ParseError::new("invalid HISTORY");
+                let (key, value) = part.split_once('=').ok_or_else(invalid)?;
+                match key.to_lowercase().as_str() {
+                    "before" => before = Some(value.parse().map_err(|_| invalid())?),
+                    "after" => after = Some(value.parse().map_err(|_| invalid())?),
+                    "limit" => limit = Some(value.parse().map_err(|_| invalid())?),
+                    _ => return Err(invalid()),
+                }
+            }
+            Ok(ClientMsg::History { before, after, limit })
+        }
+        "SEARCH" => {
+            let (mut nick, mut since, mut until) = (None, None, None);
+            let mut query = rest;
+            // Leading key=value words are filters; the rest is the query.
+            loop {
+                let (part, tail) = query.split_once(' ').unwrap_or((query, ""));
+                let invalid = || ParseError::new("invalid SEARCH");
+                match part.split_once('=') {
+                    Some(("nick", value)) if !value.is_empty() => nick = Some(value.to_string()),
+                    Some(("since", value)) => since = Some(value.parse().map_err(|_| invalid())?),
+                    Some(("until", value)) => until = Some(value.parse().map_err(|_| invalid())?),
+                    _ => break,
+                }
+                query = tail.trim_start();
+            }
+            if query.is_empty() {
+                return Err(ParseError::new("missing search query"));
+            }
+            Ok(ClientMsg::Search {
+                query: query.to_string(),
+                nick,
+                since,
+                until,
+            })
+        }
+        "PURGE" => {
+            let mut parts = rest.split_whitespace();
+            let from = parts.next().and_then(|v| v.parse::<u64>().ok());
+            let until = parts.next().and_then(|v| v.parse::<u64>().ok());
+            match (from, until, parts.next()) {
+                (Some(from), Some(until), None) if from < until => Ok(ClientMsg::Purge { from, until }),
+                _ => Err(ParseError::new("invalid PURGE")),
+            }
+        }
+        "APPROVE" | "REJECT" => {
+            if rest.is_empty() || rest.contains(' ') {
+                return Err(ParseError::new("missing ip"));
+            }
+            let ip = rest.to_string();
+            if cmd.eq_ignore_ascii_case("APPROVE") {
+                Ok(ClientMsg::Approve { ip })
+            } else {
+                Ok(ClientMsg::Reject { ip })
+            }
+        }
         _ => Err(ParseError::new("unknown command")),
     }
 }
 
+pub fn format_client_msg(msg: &ClientMsg) -> String {
+    match msg {
+        ClientMsg::Nick { nick } => format!("NICK {}", nick),
+        ClientMsg::Say { text } => format!("SAY {}", text),
+        ClientMsg::Who => "WHO".into(),
+        ClientMsg::Quit => "QUIT".into(),
+        ClientMsg::Prompt { id, answer } => format!("PROMPT {} {}", id, answer),
+        ClientMsg::Whois { nick } => format!("WHOIS {}", nick),
+        ClientMsg::Kick { nick, reason } => match reason {
+            Some(reason) => format!("KICK {} {}", nick, reason),
+            None => format!("KICK {}", nick),
+        },
+        ClientMsg::Role { nick, role } => format!("ROLE {} {}", nick, role),
+        ClientMsg::Pending => "PENDING".into(),
+        ClientMsg::Approve { ip } => format!("APPROVE {}", ip),
+        ClientMsg::Reject { ip } => format!("REJECT {}", ip),
+        ClientMsg::Conns => "CONNS".into(),
+        ClientMsg::Offenders => "OFFENDERS".into(),
+        ClientMsg::Slow { seconds } => match seconds {
+            Some(seconds) => format!("SLOW {}", seconds),
+            None => "SLOW".into(),
+        },
+        ClientMsg::History { before, after, limit } => {
+            let mut line = String::from("HISTORY");
+            if let Some(before) = before {
+                line.push_str(&format!(" before={}", before));
+            }
+            if let Some(after) = after {
+                line.push_str(&format!(" after={}", after));
+            }
+            if let Some(limit) = limit {
+                line.push_str(&format!(" limit={}", limit));
+            }
+            line
+        }
+        ClientMsg::Search {
+            query,
+            nick,
+            since,
+            until,
+        } => {
+            let mut line = String::from("SEARCH");
+            if let Some(nick) = nick {
+                line.push_str(&format!(" nick={}", nick));
+            }
+            if let Some(since) = since {
+                line.push_str(&format!(" since={}", since));
+            }
+            if let Some(until) = until {
+                line.push_str(&format!(" until={}", until));
+            }
+            format!("{} {}", line, query)
+        }
+        ClientMsg::Purge { from, until } => format!("PURGE {} {}", from, until),
+    }
+}
+
 pub fn format_server_msg(msg: &ServerMsg) -> String {
     match msg {
         ServerMsg::Sys { text } => format!("SYS {}", text),
         ServerMsg::Msg { nick, text } => format!("MSG {} {}", nick, text),
         ServerMsg::Hist { nick, text } => format!("HIST {} {}", nick, text),
+        ServerMsg::Hist2 { id, ts, nick, text } => format!("HIST2 {} {} {} {}", id, ts, nick, text),
+        ServerMsg::HistEnd { more } => format!("HISTEND {}", if *more { "more" } else { "done" }),
+        ServerMsg::Found { id, ts, nick, text } => format!("FOUND {} {} {} {}", id, ts, nick, text),
+        ServerMsg::FoundEnd { count } => format!("FOUNDEND {}", count),
         ServerMsg::Who { count, nicks } => {
             let list = nicks.join(" ");
             format!("WHO {} {}", count, list)
         }
         ServerMsg::Prompt { id, text } => format!("PROMPT {} {}", id, text),
+        ServerMsg::Whois { nick, role } => format!("WHOIS {} {}", nick, role),
+        ServerMsg::Pending { ip, attempts } => format!("PENDING {} {}", ip, attempts),
+        ServerMsg::Error { code, text } => format!("ERROR {} {}", code, text),
     }
 }
 
@@ -130,6 +337,40 @@ pub fn parse_server_line(line: &str) -> Result<ServerMsg, ParseError> {
             }
             Ok(ServerMsg::Hist { nick, text })
         }
+        "HIST2" => {
+            let mut parts = rest.splitn(4, ' ');
+            let id = parts.next().unwrap_or("").parse::<u64>();
+            let ts = parts.next().unwrap_or("").parse::<u64>();
+            let nick = parts.next().unwrap_or("").to_string();
+            let text = parts.next().unwrap_or("").to_string();
+            match (id, ts) {
+                (Ok(id), Ok(ts)) if !nick.is_empty() && !text.is_empty() => Ok(ServerMsg::Hist2 { id, ts, nick, text }),
+                _ => Err(ParseError::new("invalid HIST2")),
+            }
+        }
+        "FOUND" => {
+            let mut parts = rest.splitn(4, ' ');
+            let id = parts.next().unwrap_or("").parse::<u64>();
+            let ts = parts.next().unwrap_or("").parse::<u64>();
+            let nick = parts.next().unwrap_or("").to_string();
+            let text = parts.next().unwrap_or("").to_string();
+            match (id, ts) {
+                (Ok(id), Ok(ts)) if !nick.is_empty() && !text.is_empty() => Ok(ServerMsg::Found { id, ts, nick, text }),
+                _ => Err(ParseError::new("invalid FOUND")),
+            }
+        }
+        "FOUNDEND" => {
+            let count = rest
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| ParseError::new("invalid FOUNDEND"))?;
+            Ok(ServerMsg::FoundEnd { count })
+        }
+        "HISTEND" => match rest.trim() {
+            "more" => Ok(ServerMsg::HistEnd { more: true }),
+            "done" => Ok(ServerMsg::HistEnd { more: false }),
+            _ => Err(ParseError::new("invalid HISTEND")),
+        },
         "WHO" => {
             let mut parts = rest.splitn(2, ' ');
             let count_str = parts.next().unwrap_or("0");
@@ -151,6 +392,37 @@ pub fn parse_server_line(line: &str) -> Result<ServerMsg, ParseError> {
             }
             Ok(ServerMsg::Prompt { id, text })
         }
+        "WHOIS" => {
+            let mut parts = rest.split_whitespace();
+            let nick = parts.next().unwrap_or("").to_string();
+            let role = parts
+                .next()
+                .unwrap_or("")
+                .parse::<Role>()
+                .map_err(|_| ParseError::new("invalid WHOIS"))?;
+            if nick.is_empty() {
+                return Err(ParseError::new("invalid WHOIS"));
+            }
+            Ok(ServerMsg::Whois { nick, role })
+        }
+        "PENDING" => {
+            let mut parts = rest.split_whitespace();
+            let ip = parts.next().unwrap_or("").to_string();
+            let attempts = parts.next().unwrap_or("0").parse::<u64>().unwrap_or(0);
+            if ip.is_empty() {
+                return Err(ParseError::new("invalid PENDING"));
+            }
+            Ok(ServerMsg::Pending { ip, attempts })
+        }
+        "ERROR" => {
+            let mut parts = rest.splitn(2, ' ');
+            let code = parts.next().unwrap_or("").to_string();
+            let text = parts.next().unwrap_or("").to_string();
+            if code.is_empty() || text.is_empty() {
+                return Err(ParseError::new("invalid ERROR"));
+            }
+            Ok(ServerMsg::Error { code, text })
+        }
         _ => Err(ParseError::new("unknown command")),
     }
 }
@@ -171,9 +443,105 @@ mod tests {
         assert_eq!(msg, ClientMsg::Say { text: "hello".into() });
     }
 
+    #[test]
+    fn client_admin_roundtrip() {
+        for msg in [
+            ClientMsg::Kick {
+                nick: "bob".into(),
+                reason: Some("spamming links".into()),
+            },
+            ClientMsg::Kick {
+                nick: "bob".into(),
+                reason: None,
+            },
+            ClientMsg::Role {
+                nick: "bob".into(),
+                role: Role::Moderator,
+            },
+            ClientMsg::Pending,
+            ClientMsg::Approve {
+                ip: "203.0.113.7".into(),
+            },
+            ClientMsg::Reject {
+                ip: "203.0.113.7".into(),
+            },
+            ClientMsg::Conns,
+            ClientMsg::Offenders,
+            ClientMsg::Slow { seconds: None },
+            ClientMsg::Slow { seconds: Some(30) },
+            ClientMsg::History {
+                before: None,
+                after: None,
+                limit: None,
+            },
+            ClientMsg::History {
+                before: Some(120),
+                after: Some(80),
+                limit: Some(20),
+            },
+            ClientMsg::Search {
+                query: "cargo build".into(),
+                nick: None,
+                since: None,
+                until: None,
+            },
+            ClientMsg::Search {
+                query: "release notes".into(),
+                nick: Some("alice".into()),
+                since: Some(1760000000),
+                until: Some(1760600000),
+            },
+            ClientMsg::Purge {
+                from: 1760000000,
+                until: 1760003600,
+            },
+        ] {
+            assert_eq!(parse_client_line(&format_client_msg(&msg)).unwrap(), msg);
+        }
+        assert!(parse_client_line("ROLE bob wizard").is_err());
+        assert!(parse_client_line("SLOW fast").is_err());
+        assert!(parse_client_line("HISTORY before=last").is_err());
+        assert!(parse_client_line("SEARCH nick=alice").is_err());
+        assert!(parse_client_line("PURGE 200 100").is_err());
+    }
+
     #[test]
     fn format_server_msg_line() {
         let line = format_server_msg(&ServerMsg::Sys { text: "hi".into() });
         assert_eq!(line, "SYS hi");
+        let err = ServerMsg::Error {
+            code: "server_full".into(),
+            text: "Server is full (2 clients).".into(),
+        };
+        assert_eq!(parse_server_line(&format_server_msg(&err)).unwrap(), err);
+        assert_eq!(
+            format_server_msg(&ServerMsg::Hist {
+                nick: "alice".into(),
+                text: "see you at 10".into(),
+            }),
+            "HIST alice see you at 10"
+        );
+        for msg in [
+            ServerMsg::Hist {
+                nick: "alice".into(),
+                text: "see you at 10".into(),
+            },
+            ServerMsg::Hist2 {
+                id: 42,
+                ts: 1760000000,
+                nick: "alice".into(),
+                text: "see you at 10".into(),
+            },
+            ServerMsg::HistEnd { more: true },
+            ServerMsg::Found {
+                id: 7,
+                ts: 1760000000,
+                nick: "bob".into(),
+                text: "cargo build --release".into(),
+            },
+            ServerMsg::FoundEnd { count: 1 },
+        ] {
+            assert_eq!(parse_server_line(&format_server_msg(&msg)).unwrap(), msg);
+        }
     }
 }
@@ -0,0 +1,141 @@
+use anyhow::Context;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// First twelve bytes of every PROXY protocol v2 header.
+pub const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest possible v1 header, including the trailing CRLF.
+const V1_MAX: usize = 107;
+/// Upper bound on the v2 address block we are willing to buffer (addresses plus TLVs).
+const V2_MAX_LEN: usize = 4096;
+
+/// Reads a PROXY protocol v1 or v2 header from the start of `reader`.
+///
+/// Returns the original client address, or `None` when the proxy reports an
+/// unknown source or a local connection, i.e. one the proxy opened itself for a
+/// health check. Nothing past the header is consumed.
+pub async fn read_proxy_header<R: AsyncRead + Unpin>(reader: &mut R) -> anyhow::Result<Option<SocketAddr>> {
+    let mut start = [0u8; 6];
+    reader.read_exact(&mut start).await.context("read proxy header")?;
+    if &start == b"PROXY " {
+        read_v1(reader).await
+    } else if start[..] == V2_SIGNATURE[..6] {
+        read_v2(reader).await
+    } else {
+        anyhow::bail!("missing proxy protocol header")
+    }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(reader: &mut R) -> anyhow::Result<Option<SocketAddr>> {
+    let mut line = Vec::with_capacity(V1_MAX);
+    line.extend_from_slice(b"PROXY ");
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX {
+            anyhow::bail!("proxy v1 header too long");
+        }
+        line.push(reader.read_u8().await.context("read proxy v1 header")?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2]).context("proxy v1 header is not ascii")?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", proto @ ("TCP4" | "TCP6"), src, _dst, sport, _dport] => {
+            let ip: IpAddr = src.parse().context("invalid proxy v1 source address")?;
+            if ip.is_ipv4() != (*proto == "TCP4") {
+                anyhow::bail!("proxy v1 address does not match {proto}");
+            }
+            let port: u16 = sport.parse().context("invalid proxy v1 source port")?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => anyhow::bail!("malformed proxy v1 header"),
+    }
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(reader: &mut R) -> anyhow::Result<Option<SocketAddr>> {
+    let mut rest = [0u8; 10];
+    reader.read_exact(&mut rest).await.context("read proxy v2 header")?;
+    if rest[..6] != V2_SIGNATURE[6..] {
+        anyhow::bail!("invalid proxy v2 signature");
+    }
+    let ver_cmd = rest[6];
+    let family = rest[7];
+    let len = u16::from_be_bytes([rest[8], rest[9]]) as usize;
+    if ver_cmd >> 4 != 2 {
+        anyhow::bail!("unsupported proxy protocol version {}", ver_cmd >> 4);
+    }
+    if len > V2_MAX_LEN {
+        anyhow::bail!("proxy v2 header too long");
+    }
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).await.context("read proxy v2 addresses")?;
+
+    match ver_cmd & 0x0f {
+        0x0 => return Ok(None),
+        0x1 => {}
+        cmd => anyhow::bail!("unsupported proxy v2 command {cmd}"),
+    }
+    match family >> 4 {
+        0x1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        0x2 if body.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)))
+        }
+        0x0 => Ok(None),
+        _ => anyhow::bail!("unsupported or truncated proxy v2 address family {family:#04x}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(mut input: &[u8]) -> (anyhow::Result<Option<SocketAddr>>, Vec<u8>) {
+        let result = read_proxy_header(&mut input).await;
+        (result, input.to_vec())
+    }
+
+    #[tokio::test]
+    async fn parses_v1() {
+        let (addr, rest) = parse(b"PROXY TCP4 192.0.2.7 10.0.0.1 51000 5555\r\nhello").await;
+        assert_eq!(addr.unwrap(), Some("192.0.2.7:51000".parse().unwrap()));
+        assert_eq!(rest, b"hello");
+
+        let (addr, _) = parse(b"PROXY TCP6 2001:db8::7 2001:db8::1 51000 5555\r\n").await;
+        assert_eq!(addr.unwrap(), Some("[2001:db8::7]:51000".parse().unwrap()));
+
+        let (addr, _) = parse(b"PROXY UNKNOWN\r\n").await;
+        assert_eq!(addr.unwrap(), None);
+
+        assert!(parse(b"PROXY TCP4 2001:db8::7 10.0.0.1 1 2\r\n").await.0.is_err());
+        assert!(parse(b"GET / HTTP/1.1\r\n").await.0.is_err());
+        assert!(parse(&[b"PROXY ".as_slice(), &[b'x'; 200]].concat()).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn parses_v2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0x00, 0x0c]);
+        header.extend_from_slice(&[192, 0, 2, 7, 10, 0, 0, 1]);
+        header.extend_from_slice(&51000u16.to_be_bytes());
+        header.extend_from_slice(&5555u16.to_be_bytes());
+        header.extend_from_slice(b"hello");
+        let (addr, rest) = parse(&header).await;
+        assert_eq!(addr.unwrap(), Some("192.0.2.7:51000".parse().unwrap()));
+        assert_eq!(rest, b"hello");
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        assert_eq!(parse(&local).await.0.unwrap(), None);
+
+        let mut truncated = V2_SIGNATURE.to_vec();
+        truncated.extend_from_slice(&[0x21, 0x11, 0x00, 0x04, 1, 2, 3, 4]);
+        assert!(parse(&truncated).await.0.is_err());
+    }
+}
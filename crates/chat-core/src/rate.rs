@@ -1,3 +1,11 @@
+use crate::protocol::ClientMsg;
+use crate::util::load_toml;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
 use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone)]
@@ -6,6 +14,7 @@ pub struct RateWindow {
     pub window: Duration,
 }
 
+/// Fixed-window counter: allows `limit` events per window, then resets to zero.
 #[derive(Debug, Clone)]
 pub struct RateLimiter {
     window: RateWindow,
@@ -23,12 +32,423 @@ impl RateLimiter {
     }
 
     pub fn check(&mut self) -> bool {
+        self.check_cost(1)
+    }
+
+    /// Counts `cost` events at once; a cost above the limit uses up the whole window.
+    pub fn check_cost(&mut self, cost: u32) -> bool {
         let now = Instant::now();
         if now.duration_since(self.start) >= self.window.window {
             self.start = now;
             self.count = 0;
         }
-        self.count += 1;
+        self.count = self.count.saturating_add(cost.min(self.window.limit.max(1)));
         self.count <= self.window.limit
     }
 }
+
+/// Token bucket: holds up to `burst` tokens and refills at `rate` tokens per second.
+///
+/// Unlike `RateLimiter` there is no window boundary to game, and a short burst
+/// (a pasted paragraph) is absorbed as long as the sustained rate stays under `rate`.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Starts full.
+    pub fn new(rate: u32, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        Self {
+            rate: f64::from(rate),
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn check(&mut self) -> bool {
+        self.check_at(Instant::now())
+    }
+
+    pub fn check_at(&mut self, now: Instant) -> bool {
+        self.take_at(now, 1)
+    }
+
+    /// Takes `cost` tokens if available. A cost above the burst size needs a full bucket.
+    pub fn take(&mut self, cost: u32) -> bool {
+        self.take_at(Instant::now(), cost)
+    }
+
+    pub fn take_at(&mut self, now: Instant, cost: u32) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now.max(self.last);
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        let cost = f64::from(cost).min(self.burst);
+        if self.tokens >= cost {
+            self.tokens -= cost;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Which limiter `RateConfig` builds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RateAlgorithm {
+    /// The original per-second counter, kept for deployments tuned against it.
+    FixedWindow,
+    #[default]
+    TokenBucket,
+}
+
+impl fmt::Display for RateAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RateAlgorithm::FixedWindow => "fixed-window",
+            RateAlgorithm::TokenBucket => "token-bucket",
+        })
+    }
+}
+
+impl FromStr for RateAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed-window" => Ok(RateAlgorithm::FixedWindow),
+            "token-bucket" => Ok(RateAlgorithm::TokenBucket),
+            _ => anyhow::bail!("unknown rate algorithm {s}, expected fixed-window or token-bucket"),
+        }
+    }
+}
+
+/// Sustained rate (per second) and burst size for one kind of limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateConfig {
+    pub algorithm: RateAlgorithm,
+    pub rate: u32,
+    /// Bucket size; ignored by the fixed window.
+    pub burst: u32,
+}
+
+impl RateConfig {
+    pub fn limiter(&self) -> Limiter {
+        match self.algorithm {
+            RateAlgorithm::FixedWindow => Limiter::Window(RateLimiter::new(self.rate, Duration::from_secs(1))),
+            RateAlgorithm::TokenBucket => Limiter::Bucket(TokenBucket::new(self.rate, self.burst)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Limiter {
+    Window(RateLimiter),
+    Bucket(TokenBucket),
+}
+
+impl Limiter {
+    pub fn check(&mut self) -> bool {
+        self.check_cost(1)
+    }
+
+    pub fn check_cost(&mut self, cost: u32) -> bool {
+        match self {
+            Limiter::Window(limiter) => limiter.check_cost(cost),
+            Limiter::Bucket(bucket) => bucket.take(cost),
+        }
+    }
+}
+
+/// Where per-IP message counters live.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RateBackend {
+    /// In this process; each chatd instance grants the full allowance.
+    #[default]
+    Local,
+    /// In Redis, shared by every instance using the same server.
+    Redis,
+}
+
+impl fmt::Display for RateBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RateBackend::Local => "local",
+            RateBackend::Redis => "redis",
+        })
+    }
+}
+
+impl FromStr for RateBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "local" => Ok(RateBackend::Local),
+            "redis" => Ok(RateBackend::Redis),
+            _ => anyhow::bail!("unknown rate backend {s}, expected local or redis"),
+        }
+    }
+}
+
+/// Per-key message limit kept outside the process, so it holds across a cluster.
+#[async_trait]
+pub trait SharedLimiter: Send + Sync {
+    /// Same contract as `Limiter::check_cost`, for the counter of `key`.
+    async fn check_cost(&self, key: IpAddr, cost: u32) -> anyhow::Result<bool>;
+}
+
+#[cfg(feature = "redis")]
+pub mod redis_rate {
+    use super::*;
+    use redis::aio::MultiplexedConnection;
+    use tokio::sync::Mutex;
+
+    /// Token bucket in a hash (`tokens`, `ts`), refilled using the Redis server clock so
+    /// instances with skewed clocks agree. Returns 1 when the cost was taken.
+    const BUCKET: &str = r#"
+local rate = tonumber(ARGV[1])
+local burst = tonumber(ARGV[2])
+local cost = tonumber(ARGV[3])
+local t = redis.call('TIME')
+local now = tonumber(t[1]) + tonumber(t[2]) / 1000000
+local state = redis.call('HMGET', KEYS[1], 'tokens', 'ts')
+local tokens = tonumber(state[1]) or burst
+local ts = tonumber(state[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - ts) * rate)
+local ok = 0
+if tokens >= cost then
+  tokens = tokens - cost
+  ok = 1
+end
+redis.call('HSET', KEYS[1], 'tokens', string.format('%.6f', tokens), 'ts', string.format('%.6f', now))
+redis.call('EXPIRE', KEYS[1], math.ceil(burst / math.max(rate, 1)) + 1)
+return ok
+"#;
+
+    /// One-second fixed window. Returns the count including this cost.
+    const WINDOW: &str = r#"
+local count = redis.call('INCRBY', KEYS[1], ARGV[1])
+if count == tonumber(ARGV[1]) then
+  redis.call('PEXPIRE', KEYS[1], 1000)
+end
+return count
+"#;
+
+    pub struct RedisRateLimiter {
+        client: redis::Client,
+        prefix: String,
+        config: RateConfig,
+        bucket: redis::Script,
+        window: redis::Script,
+        conn: Mutex<Option<MultiplexedConnection>>,
+    }
+
+    impl RedisRateLimiter {
+        pub fn new(client: redis::Client, prefix: impl Into<String>, config: RateConfig) -> Self {
+            Self {
+                client,
+                prefix: prefix.into(),
+                config,
+                bucket: redis::Script::new(BUCKET),
+                window: redis::Script::new(WINDOW),
+                conn: Mutex::new(None),
+            }
+        }
+
+        /// Reuses one multiplexed connection; a failed call drops it so the next one reconnects.
+        async fn connection(&self) -> redis::RedisResult<MultiplexedConnection> {
+            let mut conn = self.conn.lock().await;
+            if let Some(conn) = conn.as_ref() {
+                return Ok(conn.clone());
+            }
+            let fresh = self.client.get_multiplexed_async_connection().await?;
+            *conn = Some(fresh.clone());
+            Ok(fresh)
+        }
+
+        async fn invoke(&self, key: IpAddr, cost: u32) -> redis::RedisResult<bool> {
+            let mut conn = self.connection().await?;
+            let RateConfig { algorithm, rate, burst } = self.config;
+            match algorithm {
+                RateAlgorithm::TokenBucket => {
+                    let key = format!("{}:{}", self.prefix, key);
+                    let cost = cost.min(burst.max(1));
+                    let ok: i64 = self
+                        .bucket
+                        .key(key)
+                        .arg(rate)
+                        .arg(burst.max(1))
+                        .arg(cost)
+                        .invoke_async(&mut conn)
+                        .await?;
+                    Ok(ok == 1)
+                }
+                RateAlgorithm::FixedWindow => {
+                    let key = format!("{}:{}:window", self.prefix, key);
+                    let cost = cost.min(rate.max(1));
+                    let count: u64 = self.window.key(key).arg(cost).invoke_async(&mut conn).await?;
+                    Ok(count <= u64::from(rate))
+                }
+            }
+        }
+    }
+
+    #[async_trait]
+    impl SharedLimiter for RedisRateLimiter {
+        async fn check_cost(&self, key: IpAddr, cost: u32) -> anyhow::Result<bool> {
+            let result = self.invoke(key, cost).await;
+            if result.is_err() {
+                *self.conn.lock().await = None;
+            }
+            Ok(result?)
+        }
+    }
+}
+
+/// Rate-limit cost of each command and the nickname cooldown, loaded from `rate_costs.toml`.
+///
+/// PROMPT replies and QUIT are always free.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CommandCosts {
+    /// Seconds a client has to wait between nickname changes.
+    pub nick_cooldown: u64,
+    pub say: u32,
+    pub nick: u32,
+    pub who: u32,
+    pub whois: u32,
+    pub kick: u32,
+    pub role: u32,
+    pub pending: u32,
+    pub approve: u32,
+    pub reject: u32,
+    pub conns: u32,
+    pub offenders: u32,
+    pub slow: u32,
+    pub history: u32,
+    pub search: u32,
+    pub purge: u32,
+}
+
+impl Default for CommandCosts {
+    fn default() -> Self {
+        Self {
+            nick_cooldown: 30,
+            say: 1,
+            nick: 2,
+            who: 2,
+            whois: 1,
+            kick: 1,
+            role: 1,
+            pending: 1,
+            approve: 1,
+            reject: 1,
+            conns: 1,
+            offenders: 1,
+            slow: 1,
+            history: 2,
+            search: 3,
+            purge: 1,
+        }
+    }
+}
+
+impl CommandCosts {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        load_toml(path)
+    }
+
+    pub fn cost(&self, msg: &ClientMsg) -> u32 {
+        match msg {
+            ClientMsg::Say { .. } => self.say,
+            ClientMsg::Nick { .. } => self.nick,
+            ClientMsg::Who => self.who,
+            ClientMsg::Whois { .. } => self.whois,
+            ClientMsg::Kick { .. } => self.kick,
+            ClientMsg::Role { .. } => self.role,
+            ClientMsg::Pending => self.pending,
+            ClientMsg::Approve { .. } => self.approve,
+            ClientMsg::Reject { .. } => self.reject,
+            ClientMsg::Conns => self.conns,
+            ClientMsg::Offenders => self.offenders,
+            ClientMsg::Slow { .. } => self.slow,
+            ClientMsg::History { .. } => self.history,
+            ClientMsg::Search { .. } => self.search,
+            ClientMsg::Purge { .. } => self.purge,
+            ClientMsg::Quit | ClientMsg::Prompt { .. } => 0,
+        }
+    }
+
+    pub fn nick_cooldown(&self) -> Duration {
+        Duration::from_secs(self.nick_cooldown)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_burst_then_sustained_rate() {
+        let mut bucket = TokenBucket::new(2, 5);
+        let start = Instant::now();
+        for _ in 0..5 {
+            assert!(bucket.check_at(start));
+        }
+        assert!(!bucket.check_at(start));
+
+        let half = start + Duration::from_millis(500);
+        assert!(bucket.check_at(half));
+        assert!(!bucket.check_at(half));
+
+        // Idle time refills up to the burst size, not beyond.
+        let later = start + Duration::from_secs(60);
+        assert_eq!((0..10).filter(|_| bucket.check_at(later)).count(), 5);
+
+        let refill = later + Duration::from_secs(60);
+        assert!(bucket.take_at(refill, 3));
+        assert!(!bucket.take_at(refill, 3));
+        assert!(bucket.take_at(refill, 2));
+        // Costs above the burst size drain a full bucket instead of never passing.
+        assert!(bucket.take_at(refill + Duration::from_secs(60), 50));
+    }
+
+    #[test]
+    fn command_costs() {
+        let costs = toml::from_str::<CommandCosts>("who = 10\nnick_cooldown = 0\n").unwrap();
+        assert_eq!(costs.cost(&ClientMsg::Who), 10);
+        assert_eq!(costs.cost(&ClientMsg::Say { text: "hi".into() }), 1);
+        assert_eq!(costs.cost(&ClientMsg::Quit), 0);
+        assert_eq!(costs.nick_cooldown(), Duration::ZERO);
+        assert!(toml::from_str::<CommandCosts>("shout = 1\n").is_err());
+    }
+
+    #[test]
+    fn config_builds_either_limiter() {
+        let cfg = RateConfig {
+            algorithm: "fixed-window".parse().unwrap(),
+            rate: 2,
+            burst: 10,
+        };
+        let mut limiter = cfg.limiter();
+        assert!(matches!(limiter, Limiter::Window(_)));
+        assert_eq!((0..5).filter(|_| limiter.check()).count(), 2);
+
+        let cfg = RateConfig {
+            algorithm: RateAlgorithm::default(),
+            ..cfg
+        };
+        let mut limiter = cfg.limiter();
+        assert_eq!((0..20).filter(|_| limiter.check()).count(), 10);
+        assert!("leaky".parse::<RateAlgorithm>().is_err());
+        assert_eq!("redis".parse::<RateBackend>().unwrap(), RateBackend::Redis);
+        assert_eq!(RateBackend::default().to_string(), "local");
+        assert!("memcached".parse::<RateBackend>().is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use crate::util::load_toml;
+use anyhow::Context;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Roles ordered by rank: a higher role outranks every role below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Guest,
+    #[default]
+    Member,
+    Moderator,
+    Admin,
+    Owner,
+}
+
+impl Role {
+    pub const ALL: [Role; 5] = [
+        Role::Guest,
+        Role::Member,
+        Role::Moderator,
+        Role::Admin,
+        Role::Owner,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Guest => "guest",
+            Role::Member => "member",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+            Role::Owner => "owner",
+        }
+    }
+
+    /// Prefix shown in front of a nickname in WHO replies.
+    pub fn sigil(&self) -> &'static str {
+        match self {
+            Role::Owner => "~",
+            Role::Admin => "&",
+            Role::Moderator => "@",
+            Role::Member | Role::Guest => "",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Role::ALL
+            .into_iter()
+            .find(|role| role.as_str().eq_ignore_ascii_case(s))
+            .with_context(|| format!("unknown role {s}"))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    Say,
+    Nick,
+    Who,
+    Whois,
+    Kick,
+    SetRole,
+    Approve,
+    /// See live connection counts and offense scores.
+    Stats,
+    /// Set the slow-mode interval; holders are not slowed down themselves.
+    SlowMode,
+    /// Delete stored history in a time range.
+    Purge,
+}
+
+/// Permission matrix and default role, loaded from `roles.toml`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RolesConfig {
+    pub default_role: Role,
+    /// Roles listed in the file replace their default permissions; the others keep them.
+    #[serde(deserialize_with = "merge_permissions")]
+    pub permissions: BTreeMap<Role, BTreeSet<Permission>>,
+}
+
+fn merge_permissions<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<Role, BTreeSet<Permission>>, D::Error> {
+    let mut permissions = RolesConfig::default().permissions;
+    permissions.extend(BTreeMap::<Role, BTreeSet<Permission>>::deserialize(deserializer)?);
+    Ok(permissions)
+}
+
+impl Default for RolesConfig {
+    fn default() -> Self {
+        use Permission::*;
+        let guest = BTreeSet::from([Who, Whois]);
+        let member = BTreeSet::from([Say, Nick, Who, Whois]);
+        let mut moderator = member.clone();
+        moderator.insert(Kick);
+        moderator.insert(SlowMode);
+        let mut admin = moderator.clone();
+        admin.insert(SetRole);
+        admin.insert(Approve);
+        admin.insert(Stats);
+        admin.insert(Purge);
+        let owner = admin.clone();
+        Self {
+            default_role: Role::Member,
+            permissions: BTreeMap::from([
+                (Role::Guest, guest),
+                (Role::Member, member),
+                (Role::Moderator, moderator),
+                (Role::Admin, admin),
+                (Role::Owner, owner),
+            ]),
+        }
+    }
+}
+
+impl RolesConfig {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        load_toml(path)
+    }
+
+    pub fn allows(&self, role: Role, perm: Permission) -> bool {
+        self.permissions
+            .get(&role)
+            .map(|perms| perms.contains(&perm))
+            .unwrap_or(false)
+    }
+
+    /// Resolves the effective role for an identity without an explicit assignment.
+    pub fn effective(&self, role: Option<Role>) -> Role {
+        role.unwrap_or(self.default_role)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_matrix() {
+        let cfg = RolesConfig::default();
+        assert!(!cfg.allows(Role::Guest, Permission::Say));
+        assert!(cfg.allows(Role::Member, Permission::Say));
+        assert!(!cfg.allows(Role::Member, Permission::Kick));
+        assert!(cfg.allows(Role::Moderator, Permission::Kick));
+        assert!(cfg.allows(Role::Moderator, Permission::SlowMode));
+        assert!(!cfg.allows(Role::Member, Permission::SlowMode));
+        assert!(cfg.allows(Role::Admin, Permission::Purge));
+        assert!(!cfg.allows(Role::Moderator, Permission::Purge));
+        assert!(cfg.allows(Role::Owner, Permission::SetRole));
+        assert!(!cfg.allows(Role::Moderator, Permission::Approve));
+        assert!(cfg.allows(Role::Admin, Permission::Approve));
+        assert!(Role::Owner > Role::Admin && Role::Moderator > Role::Member);
+    }
+
+    #[test]
+    fn parse_roles_config() {
+        let raw = r#"
+default_role = "guest"
+
+[permissions]
+guest = ["who"]
+member = ["say", "who", "kick"]
+"#;
+        let cfg = toml::from_str::<RolesConfig>(raw).unwrap();
+        assert_eq!(cfg.effective(None), Role::Guest);
+        assert!(cfg.allows(Role::Member, Permission::Kick));
+        assert!(!cfg.allows(Role::Member, Permission::Nick));
+        // Roles left out of the table keep their defaults.
+        assert!(cfg.allows(Role::Admin, Permission::Say));
+        assert!(cfg.allows(Role::Owner, Permission::SetRole));
+        assert!(!cfg.allows(Role::Moderator, Permission::Approve));
+        assert!(toml::from_str::<RolesConfig>("default_rol = \"guest\"").is_err());
+        assert_eq!("Admin".parse::<Role>().unwrap(), Role::Admin);
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Senders are forgotten once this many are tracked and their interval has passed.
+const PRUNE_AT: usize = 4096;
+
+/// Minimum time between two chat messages from one client key, set at runtime by moderators.
+///
+/// There is a single room, so one interval applies to everyone. This is separate from the
+/// abuse rate limits: it is a moderation tool and blocked messages are not offenses.
+#[derive(Debug, Default)]
+pub struct SlowMode {
+    interval: AtomicU64,
+    slots: Mutex<Slots>,
+}
+
+#[derive(Debug, Default)]
+struct Slots {
+    last: HashMap<IpAddr, Instant>,
+    /// Keys with a message between [`SlowMode::reserve`] and delivery.
+    reserved: HashSet<IpAddr>,
+}
+
+/// A key's claim on its next message, from [`SlowMode::reserve`].
+///
+/// [`commit`](Self::commit) it once the message is delivered; dropping it instead lets the
+/// key send again without a wait.
+#[must_use]
+#[derive(Debug)]
+pub struct SlowToken<'a> {
+    slow: &'a SlowMode,
+    key: Option<IpAddr>,
+}
+
+impl SlowMode {
+    /// `secs` of 0 starts with slow mode off.
+    pub fn new(secs: u64) -> Self {
+        Self {
+            interval: AtomicU64::new(secs),
+            slots: Mutex::new(Slots::default()),
+        }
+    }
+
+    /// Current interval in seconds; 0 when off.
+    pub fn interval(&self) -> u64 {
+        self.interval.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, secs: u64) {
+        self.interval.store(secs, Ordering::Relaxed);
+        if secs == 0 {
+            // Reservations are left to their tokens, which release them shortly.
+            self.slots().last.clear();
+        }
+    }
+
+    pub fn reserve(&self, key: IpAddr) -> Result<SlowToken<'_>, Duration> {
+        self.reserve_at(key, Instant::now())
+    }
+
+    /// Claims the next message for `key`, or returns how long it still has to wait.
+    ///
+    /// Checking and claiming happen under one lock, so two sessions of the same key cannot
+    /// both get through; the second waits the full interval while the first is in flight.
+    pub fn reserve_at(&self, key: IpAddr, now: Instant) -> Result<SlowToken<'_>, Duration> {
+        let secs = self.interval();
+        if secs == 0 {
+            return Ok(SlowToken { slow: self, key: None });
+        }
+        let interval = Duration::from_secs(secs);
+        let mut slots = self.slots();
+        if slots.reserved.contains(&key) {
+            return Err(interval);
+        }
+        if let Some(at) = slots.last.get(&key) {
+            let since = now.saturating_duration_since(*at);
+            if since < interval {
+                return Err(interval - since);
+            }
+        }
+        slots.reserved.insert(key);
+        Ok(SlowToken { slow: self, key: Some(key) })
+    }
+
+    fn slots(&self) -> std::sync::MutexGuard<'_, Slots> {
+        self.slots.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl SlowToken<'_> {
+    pub fn commit(self) {
+        self.commit_at(Instant::now())
+    }
+
+    /// Starts the wait for the key; call once its message has actually been delivered.
+    pub fn commit_at(mut self, now: Instant) {
+        let Some(key) = self.key.take() else {
+            return;
+        };
+        let secs = self.slow.interval();
+        let mut slots = self.slow.slots();
+        slots.reserved.remove(&key);
+        if secs == 0 {
+            return;
+        }
+        let interval = Duration::from_secs(secs);
+        if slots.last.len() >= PRUNE_AT {
+            slots.last.retain(|_, at| now.saturating_duration_since(*at) < interval);
+        }
+        slots.last.insert(key, now);
+    }
+}
+
+impl Drop for SlowToken<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.slow.slots().reserved.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enforces_interval_per_key() {
+        let slow = SlowMode::new(0);
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+        let start = Instant::now();
+        assert!((0..5).all(|_| slow.reserve_at(a, start).map(|token| token.commit_at(start)).is_ok()));
+
+        slow.set(10);
+        assert_eq!(slow.interval(), 10);
+        slow.reserve_at(a, start).unwrap().commit_at(start);
+        // Releasing, as for a message that is then dropped, does not start a wait.
+        drop(slow.reserve_at(b, start).unwrap());
+        assert!(slow.reserve_at(b, start).is_ok());
+        assert_eq!(slow.reserve_at(a, start + Duration::from_secs(4)).unwrap_err(), Duration::from_secs(6));
+        // A blocked message does not restart the wait.
+        assert!(slow.reserve_at(a, start + Duration::from_secs(10)).is_ok());
+
+        slow.set(0);
+        assert!(slow.reserve_at(a, start + Duration::from_secs(10)).is_ok());
+    }
+
+    #[test]
+    fn one_reservation_per_key() {
+        let slow = SlowMode::new(10);
+        let a: IpAddr = "2001:db8::".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+        let start = Instant::now();
+
+        let first = slow.reserve_at(a, start).unwrap();
+        assert_eq!(slow.reserve_at(a, start).unwrap_err(), Duration::from_secs(10));
+        assert!(slow.reserve_at(b, start).is_ok());
+        drop(first);
+
+        let first = slow.reserve_at(a, start).unwrap();
+        first.commit_at(start);
+        assert_eq!(slow.reserve_at(a, start + Duration::from_secs(1)).unwrap_err(), Duration::from_secs(9));
+
+        // A message in flight while slow mode is toggled still holds its key until delivered.
+        let held = slow.reserve_at(b, start).unwrap();
+        slow.set(0);
+        slow.set(10);
+        assert!(slow.reserve_at(b, start).is_err());
+        held.commit_at(start);
+        assert_eq!(slow.reserve_at(b, start).unwrap_err(), Duration::from_secs(10));
+    }
+}
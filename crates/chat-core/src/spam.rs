@@ -0,0 +1,445 @@
+use crate::util::load_toml;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Messages remembered per sender for the repeat check.
+const PER_SENDER: usize = 16;
+/// Messages remembered across all senders for the cross-user check.
+const RECENT: usize = 256;
+
+/// What a spam check looks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SpamKind {
+    /// The same or a near-identical message sent again by one client.
+    Repeat,
+    /// The same or a near-identical message sent by several clients.
+    CrossUser,
+    /// Too many nicknames mentioned in one message.
+    Mentions,
+    /// One character repeated many times in a row.
+    CharRun,
+}
+
+impl SpamKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SpamKind::Repeat => "repeat",
+            SpamKind::CrossUser => "cross_user",
+            SpamKind::Mentions => "mentions",
+            SpamKind::CharRun => "char_run",
+        }
+    }
+}
+
+impl fmt::Display for SpamKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// What happens to a message flagged as spam.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpamAction {
+    /// Deliver it, but tell the sender it looked like spam.
+    Warn,
+    /// Discard it and tell the sender.
+    Drop,
+    /// Discard it and silence the sender for `mute_for` seconds.
+    Mute,
+    /// Discard it and disconnect the sender.
+    Kick,
+}
+
+impl SpamAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SpamAction::Warn => "warn",
+            SpamAction::Drop => "drop",
+            SpamAction::Mute => "mute",
+            SpamAction::Kick => "kick",
+        }
+    }
+}
+
+impl fmt::Display for SpamAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Action taken for each kind of spam.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpamActions {
+    pub repeat: SpamAction,
+    pub cross_user: SpamAction,
+    pub mentions: SpamAction,
+    pub char_run: SpamAction,
+}
+
+impl Default for SpamActions {
+    fn default() -> Self {
+        Self {
+            repeat: SpamAction::Drop,
+            cross_user: SpamAction::Drop,
+            mentions: SpamAction::Drop,
+            char_run: SpamAction::Drop,
+        }
+    }
+}
+
+impl SpamActions {
+    pub fn action(&self, kind: SpamKind) -> SpamAction {
+        match kind {
+            SpamKind::Repeat => self.repeat,
+            SpamKind::CrossUser => self.cross_user,
+            SpamKind::Mentions => self.mentions,
+            SpamKind::CharRun => self.char_run,
+        }
+    }
+}
+
+/// Spam filter thresholds, loaded from `spam.toml`. Times are in seconds; a limit of 0 disables that check.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpamPolicy {
+    pub enabled: bool,
+    /// How long a message counts towards the repeat and cross-user checks.
+    pub window: u64,
+    /// Similar messages from one client within `window`, this one included, that count as spam.
+    pub repeat_limit: usize,
+    /// Distinct clients sending a similar message within `window` that count as spam.
+    pub cross_user_limit: usize,
+    /// Share of character trigrams two messages must have in common to count as near-identical.
+    pub similarity: f64,
+    /// Messages with fewer letters and digits than this skip the repeat and cross-user checks,
+    /// so short replies such as "ok" or "+1" from several people are not flagged.
+    pub min_length: usize,
+    /// Most distinct online nicknames one message may mention.
+    pub max_mentions: usize,
+    /// Longest run of one repeated character.
+    pub max_char_run: usize,
+    pub mute_for: u64,
+    pub actions: SpamActions,
+}
+
+impl Default for SpamPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            window: 60,
+            repeat_limit: 3,
+            cross_user_limit: 3,
+            similarity: 0.8,
+            min_length: 12,
+            max_mentions: 5,
+            max_char_run: 20,
+            mute_for: 5 * 60,
+            actions: SpamActions::default(),
+        }
+    }
+}
+
+impl SpamPolicy {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let policy: Self = load_toml(path)?;
+        if !(policy.similarity > 0.0 && policy.similarity <= 1.0) {
+            anyhow::bail!("{}: similarity must be in (0, 1]", path.display());
+        }
+        if policy.window == 0 {
+            anyhow::bail!("{}: window must be positive", path.display());
+        }
+        Ok(policy)
+    }
+}
+
+/// Outcome of `SpamFilter::check`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// The sender is muted for this many more seconds.
+    Muted { left: u64 },
+    Spam { kind: SpamKind, action: SpamAction },
+}
+
+/// Sorted, deduplicated hashes of a message's character trigrams, after normalizing case and punctuation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Fingerprint(Vec<u64>);
+
+impl Fingerprint {
+    fn new(text: &str) -> Self {
+        let mut norm: Vec<char> = Vec::new();
+        for c in text.chars().flat_map(char::to_lowercase) {
+            if c.is_alphanumeric() {
+                norm.push(c);
+            } else if !norm.last().map(|l| *l == ' ').unwrap_or(true) {
+                norm.push(' ');
+            }
+        }
+        while norm.last() == Some(&' ') {
+            norm.pop();
+        }
+        if norm.is_empty() {
+            norm = text.trim().chars().collect();
+        }
+        let hash = |chunk: &[char]| {
+            let mut hasher = DefaultHasher::new();
+            chunk.hash(&mut hasher);
+            hasher.finish()
+        };
+        let mut grams: Vec<u64> = if norm.len() < 3 {
+            vec![hash(&norm)]
+        } else {
+            norm.windows(3).map(hash).collect()
+        };
+        grams.sort_unstable();
+        grams.dedup();
+        Self(grams)
+    }
+
+    /// Jaccard similarity of the two trigram sets.
+    fn similarity(&self, other: &Self) -> f64 {
+        let (a, b) = (&self.0, &other.0);
+        let (mut i, mut j, mut common) = (0, 0, 0usize);
+        while i < a.len() && j < b.len() {
+            match a[i].cmp(&b[j]) {
+                std::cmp::Ordering::Less => i += 1,
+                std::cmp::Ordering::Greater => j += 1,
+                std::cmp::Ordering::Equal => {
+                    common += 1;
+                    i += 1;
+                    j += 1;
+                }
+            }
+        }
+        let union = a.len() + b.len() - common;
+        if union == 0 {
+            1.0
+        } else {
+            common as f64 / union as f64
+        }
+    }
+}
+
+fn longest_run(text: &str) -> usize {
+    let mut best = 0;
+    let mut run = 0;
+    let mut prev = None;
+    for c in text.chars() {
+        if c.is_whitespace() {
+            prev = None;
+            run = 0;
+            continue;
+        }
+        run = if prev == Some(c) { run + 1 } else { 1 };
+        prev = Some(c);
+        best = best.max(run);
+    }
+    best
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    per_sender: HashMap<IpAddr, VecDeque<(u64, Fingerprint)>>,
+    recent: VecDeque<(u64, IpAddr, Fingerprint)>,
+    muted: HashMap<IpAddr, u64>,
+}
+
+/// Recent messages per sender and across senders, plus active mutes.
+#[derive(Debug)]
+pub struct SpamFilter {
+    policy: SpamPolicy,
+    inner: Mutex<Inner>,
+}
+
+impl SpamFilter {
+    pub fn new(policy: SpamPolicy) -> Self {
+        Self {
+            policy,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    pub fn policy(&self) -> &SpamPolicy {
+        &self.policy
+    }
+
+    /// Checks a message from `sender` and remembers it for later checks.
+    ///
+    /// `is_nick` tells whether a word names someone online; each nickname counts once.
+    pub fn check(&self, sender: IpAddr, text: &str, is_nick: impl Fn(&str) -> bool, now: u64) -> Verdict {
+        if !self.policy.enabled {
+            return Verdict::Allow;
+        }
+        let policy = &self.policy;
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(until) = inner.muted.get(&sender).copied() {
+            if until > now {
+                return Verdict::Muted { left: until - now };
+            }
+            inner.muted.remove(&sender);
+        }
+
+        let window = policy.window;
+        inner.recent.retain(|(at, _, _)| at + window > now);
+        if let Some(history) = inner.per_sender.get_mut(&sender) {
+            history.retain(|(at, _)| at + window > now);
+        }
+
+        let fp = Fingerprint::new(text);
+        let similar = |other: &Fingerprint| fp.similarity(other) >= policy.similarity;
+        let compared = text.chars().filter(|c| c.is_alphanumeric()).count() >= policy.min_length;
+        let mut kind = None;
+        if policy.max_char_run > 0 && longest_run(text) > policy.max_char_run {
+            kind = Some(SpamKind::CharRun);
+        }
+        if kind.is_none() && policy.max_mentions > 0 {
+            let mentioned: HashSet<&str> = text
+                .split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-'))
+                .filter(|word| !word.is_empty() && is_nick(word))
+                .collect();
+            if mentioned.len() > policy.max_mentions {
+                kind = Some(SpamKind::Mentions);
+            }
+        }
+        if compared && kind.is_none() && policy.repeat_limit > 0 {
+            let repeats = inner
+                .per_sender
+                .get(&sender)
+                .map(|history| history.iter().filter(|(_, other)| similar(other)).count())
+                .unwrap_or(0);
+            if repeats + 1 >= policy.repeat_limit {
+                kind = Some(SpamKind::Repeat);
+            }
+        }
+        if compared && kind.is_none() && policy.cross_user_limit > 0 {
+            let senders: HashSet<IpAddr> = inner
+                .recent
+                .iter()
+                .filter(|(_, ip, other)| *ip != sender && similar(other))
+                .map(|(_, ip, _)| *ip)
+                .collect();
+            if senders.len() + 1 >= policy.cross_user_limit {
+                kind = Some(SpamKind::CrossUser);
+            }
+        }
+
+        // Flagged messages are remembered too, so repeating them keeps tripping the filter.
+        if compared {
+            let history = inner.per_sender.entry(sender).or_default();
+            history.push_back((now, fp.clone()));
+            if history.len() > PER_SENDER {
+                history.pop_front();
+            }
+            inner.recent.push_back((now, sender, fp));
+            if inner.recent.len() > RECENT {
+                inner.recent.pop_front();
+            }
+        }
+
+        let Some(kind) = kind else {
+            return Verdict::Allow;
+        };
+        let action = policy.actions.action(kind);
+        if action == SpamAction::Mute {
+            inner.muted.insert(sender, now.saturating_add(policy.mute_for));
+        }
+        Verdict::Spam { kind, action }
+    }
+
+    /// Forgets expired mutes and senders with nothing recent.
+    pub fn prune(&self, now: u64) {
+        let window = self.policy.window;
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.muted.retain(|_, until| *until > now);
+        inner.per_sender.retain(|_, history| {
+            history.retain(|(at, _)| at + window > now);
+            !history.is_empty()
+        });
+        inner.recent.retain(|(at, _, _)| at + window > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, last])
+    }
+
+    #[test]
+    fn repeats_and_near_duplicates() {
+        let filter = SpamFilter::new(SpamPolicy::default());
+        let none = |_: &str| false;
+        assert_eq!(filter.check(ip(1), "Buy cheap followers at example.com", none, 0), Verdict::Allow);
+        assert_eq!(filter.check(ip(1), "something else entirely", none, 1), Verdict::Allow);
+        assert_eq!(filter.check(ip(1), "buy cheap followers at example.com", none, 1), Verdict::Allow);
+        assert_eq!(
+            filter.check(ip(1), "buy CHEAP followers at example.com!!", none, 2),
+            Verdict::Spam {
+                kind: SpamKind::Repeat,
+                action: SpamAction::Drop,
+            }
+        );
+        // Old messages fall out of the window.
+        assert_eq!(filter.check(ip(1), "Buy cheap followers at example.com", none, 100), Verdict::Allow);
+
+        // Two other clients already sent it: the third is flagged.
+        assert_eq!(filter.check(ip(2), "buy cheap followers at example.com", none, 101), Verdict::Allow);
+        assert!(matches!(
+            filter.check(ip(3), "Buy cheap followers at example.com.", none, 102),
+            Verdict::Spam {
+                kind: SpamKind::CrossUser,
+                ..
+            }
+        ));
+        filter.prune(1000);
+        assert_eq!(filter.check(ip(3), "Buy cheap followers at example.com", none, 1000), Verdict::Allow);
+
+        // Short replies are never compared.
+        for n in 1..=5 {
+            assert_eq!(filter.check(ip(n), "+1 thanks", none, 1001), Verdict::Allow);
+            assert_eq!(filter.check(ip(n), "ok", none, 1001), Verdict::Allow);
+        }
+    }
+
+    #[test]
+    fn mentions_runs_and_mutes() {
+        let policy = toml::from_str::<SpamPolicy>(
+            "max_mentions = 2\nmax_char_run = 5\nmute_for = 30\n[actions]\nchar_run = \"mute\"\nmentions = \"warn\"\n",
+        )
+        .unwrap();
+        let filter = SpamFilter::new(policy);
+        let nicks = |w: &str| ["alice", "bob", "carol"].contains(&w);
+        assert_eq!(filter.check(ip(1), "alice: alice, bob?", nicks, 0), Verdict::Allow);
+        assert_eq!(
+            filter.check(ip(1), "@alice @bob @carol look", nicks, 1),
+            Verdict::Spam {
+                kind: SpamKind::Mentions,
+                action: SpamAction::Warn,
+            }
+        );
+        assert_eq!(filter.check(ip(1), "hmmmmm ok", nicks, 2), Verdict::Allow);
+        assert_eq!(
+            filter.check(ip(1), "hmmmmmm ok", nicks, 3),
+            Verdict::Spam {
+                kind: SpamKind::CharRun,
+                action: SpamAction::Mute,
+            }
+        );
+        assert_eq!(filter.check(ip(1), "sorry", nicks, 13), Verdict::Muted { left: 20 });
+        assert_eq!(filter.check(ip(2), "hi all", nicks, 13), Verdict::Allow);
+        assert_eq!(filter.check(ip(1), "sorry", nicks, 33), Verdict::Allow);
+
+        assert!(toml::from_str::<SpamPolicy>("[actions]\nrepeat = \"ban\"\n").is_err());
+        assert!(toml::from_str::<SpamPolicy>("max_links = 1\n").is_err());
+    }
+}
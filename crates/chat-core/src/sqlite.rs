@@ -0,0 +1,734 @@
+use crate::allowlist::{AllowEntry, AllowedList, DenyEntry, DenyList, Knock, PendingEntry, PendingList};
+use crate::history::{HistoryItem, HistoryQuery, HistoryStore, SearchQuery};
+use crate::identities::{IdentityRecord, IdentityStore};
+use crate::nick::nick_key;
+use crate::roles::Role;
+use crate::util::now_ts;
+use anyhow::Context;
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, TransactionBehavior};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Schema changes in order. `PRAGMA user_version` records how many have been applied;
+/// append new steps, never edit old ones.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE history (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        nick TEXT NOT NULL,
+        text TEXT NOT NULL,
+        ts INTEGER NOT NULL
+    );
+    CREATE TABLE identities (
+        ip TEXT PRIMARY KEY,
+        nick TEXT NOT NULL,
+        nick_key TEXT NOT NULL,
+        updated INTEGER NOT NULL,
+        role TEXT
+    );
+    CREATE INDEX identities_nick_key ON identities (nick_key);
+    CREATE TABLE allow (
+        id INTEGER PRIMARY KEY,
+        entry TEXT NOT NULL,
+        label TEXT,
+        owner TEXT,
+        added INTEGER NOT NULL,
+        expires INTEGER
+    );
+    CREATE TABLE deny (
+        id INTEGER PRIMARY KEY,
+        entry TEXT NOT NULL,
+        reason TEXT,
+        added INTEGER NOT NULL,
+        expires INTEGER
+    );
+    CREATE TABLE pending (
+        ip TEXT PRIMARY KEY,
+        first_seen INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL,
+        attempts INTEGER NOT NULL,
+        knock_name TEXT,
+        knock_reason TEXT,
+        knock_code TEXT,
+        knock_at INTEGER
+    );
+    CREATE TABLE meta (rules_version INTEGER NOT NULL);
+    INSERT INTO meta (rules_version) VALUES (0);
+    CREATE TRIGGER allow_insert AFTER INSERT ON allow BEGIN UPDATE meta SET rules_version = rules_version + 1; END;
+    CREATE TRIGGER allow_update AFTER UPDATE ON allow BEGIN UPDATE meta SET rules_version = rules_version + 1; END;
+    CREATE TRIGGER allow_delete AFTER DELETE ON allow BEGIN UPDATE meta SET rules_version = rules_version + 1; END;
+    CREATE TRIGGER deny_insert AFTER INSERT ON deny BEGIN UPDATE meta SET rules_version = rules_version + 1; END;
+    CREATE TRIGGER deny_update AFTER UPDATE ON deny BEGIN UPDATE meta SET rules_version = rules_version + 1; END;
+    CREATE TRIGGER deny_delete AFTER DELETE ON deny BEGIN UPDATE meta SET rules_version = rules_version + 1; END;",
+    // Full-text index over message text, kept in step with the history table.
+    "CREATE VIRTUAL TABLE history_fts USING fts5(text, content = 'history', content_rowid = 'id');
+    INSERT INTO history_fts (history_fts) VALUES ('rebuild');
+    CREATE TRIGGER history_fts_insert AFTER INSERT ON history BEGIN
+        INSERT INTO history_fts (rowid, text) VALUES (new.id, new.text);
+    END;
+    CREATE TRIGGER history_fts_delete AFTER DELETE ON history BEGIN
+        INSERT INTO history_fts (history_fts, rowid, text) VALUES ('delete', old.id, old.text);
+    END;",
+];
+
+/// Embedded database holding history, identities and the access lists for single-node deployments.
+///
+/// Clones share one connection. The async stores run their queries on the blocking pool.
+#[derive(Debug, Clone)]
+pub struct SqliteDb {
+    path: PathBuf,
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteDb {
+    /// Opens or creates the database and applies pending migrations.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut conn = Connection::open(path).with_context(|| format!("open {}", path.display()))?;
+        conn.busy_timeout(std::time::Duration::from_secs(5))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        migrate(&mut conn).with_context(|| format!("migrate {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Opens an existing database without creating, migrating or writing to it, for
+    /// `chatd check`. Fails if the file is missing or its schema is not the current one.
+    pub fn open_read_only(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            anyhow::bail!("{} does not exist; chatd creates it on first start", path.display());
+        }
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX | OpenFlags::SQLITE_OPEN_URI;
+        let conn = Connection::open_with_flags(path, flags).with_context(|| format!("open {}", path.display()))?;
+        conn.busy_timeout(std::time::Duration::from_secs(5))?;
+        let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version > MIGRATIONS.len() {
+            anyhow::bail!(
+                "schema version {version} is newer than this chatd supports ({})",
+                MIGRATIONS.len()
+            );
+        }
+        if version < MIGRATIONS.len() {
+            anyhow::bail!(
+                "schema version {version} is out of date (current is {}); chatd migrates it on start",
+                MIGRATIONS.len()
+            );
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Runs `f` on tokio's blocking pool, so a slow statement or disk never stalls a worker thread.
+    async fn blocking<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&SqliteDb) -> anyhow::Result<T> + Send + 'static,
+    {
+        let db = self.clone();
+        tokio::task::spawn_blocking(move || f(&db))
+            .await
+            .context("sqlite task panicked")?
+    }
+
+    /// Bumped by triggers on every change to the allow or deny table, from any connection.
+    pub fn rules_version(&self) -> anyhow::Result<i64> {
+        Ok(self.conn().query_row("SELECT rules_version FROM meta", [], |row| row.get(0))?)
+    }
+
+    pub fn load_allow(&self) -> anyhow::Result<AllowedList> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT entry, label, owner, added, expires FROM allow ORDER BY id")?;
+        let allow = stmt
+            .query_map([], |row| {
+                Ok(AllowEntry {
+                    entry: row.get(0)?,
+                    label: row.get(1)?,
+                    owner: row.get(2)?,
+                    added: row.get(3)?,
+                    expires: row.get(4)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(AllowedList { allow })
+    }
+
+    pub fn save_allow(&self, list: &AllowedList) -> anyhow::Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM allow", [])?;
+        for e in &list.allow {
+            tx.execute(
+                "INSERT INTO allow (entry, label, owner, added, expires) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![e.entry, e.label, e.owner, e.added, e.expires],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn load_deny(&self) -> anyhow::Result<DenyList> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT entry, reason, added, expires FROM deny ORDER BY id")?;
+        let deny = stmt
+            .query_map([], |row| {
+                Ok(DenyEntry {
+                    entry: row.get(0)?,
+                    reason: row.get(1)?,
+                    added: row.get(2)?,
+                    expires: row.get(3)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(DenyList { deny })
+    }
+
+    pub fn save_deny(&self, list: &DenyList) -> anyhow::Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM deny", [])?;
+        for e in &list.deny {
+            tx.execute(
+                "INSERT INTO deny (entry, reason, added, expires) VALUES (?1, ?2, ?3, ?4)",
+                params![e.entry, e.reason, e.added, e.expires],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn load_pending(&self) -> anyhow::Result<PendingList> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT ip, first_seen, last_seen, attempts, knock_name, knock_reason, knock_code, knock_at
+             FROM pending ORDER BY ip",
+        )?;
+        let pending = stmt
+            .query_map([], |row| {
+                let knock = match row.get::<_, Option<String>>(4)? {
+                    Some(name) => Some(Knock {
+                        name,
+                        reason: row.get::<_, Option<String>>(5)?.unwrap_or_default(),
+                        code: row.get::<_, Option<String>>(6)?.unwrap_or_default(),
+                        at: row.get::<_, Option<u64>>(7)?.unwrap_or(0),
+                    }),
+                    None => None,
+                };
+                let entry = PendingEntry {
+                    first_seen: row.get(1)?,
+                    last_seen: row.get(2)?,
+                    attempts: row.get(3)?,
+                    knock,
+                };
+                Ok((row.get::<_, String>(0)?, entry))
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(PendingList { pending })
+    }
+
+    pub fn save_pending(&self, list: &PendingList) -> anyhow::Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM pending", [])?;
+        for (ip, e) in &list.pending {
+            let knock = e.knock.as_ref();
+            tx.execute(
+                "INSERT INTO pending (ip, first_seen, last_seen, attempts, knock_name, knock_reason, knock_code, knock_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    ip,
+                    e.first_seen,
+                    e.last_seen,
+                    e.attempts,
+                    knock.map(|k| &k.name),
+                    knock.map(|k| &k.reason),
+                    knock.map(|k| &k.code),
+                    knock.map(|k| k.at),
+                ],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_identity(&self, ip: IpAddr) -> anyhow::Result<Option<IdentityRecord>> {
+        let conn = self.conn();
+        let row = conn
+            .query_row(
+                "SELECT nick, updated, role FROM identities WHERE ip = ?1",
+                params![ip.to_string()],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, u64>(1)?, row.get::<_, Option<String>>(2)?)),
+            )
+            .optional()?;
+        row.map(|(nick, updated, role)| {
+            Ok(IdentityRecord {
+                nick,
+                updated,
+                role: role.map(|r| r.parse::<Role>()).transpose()?,
+            })
+        })
+        .transpose()
+    }
+
+    /// Stores `rec` for `ip`. Nickname clashes are checked when the nickname is chosen
+    /// (see `IdentityStore::nick_holder`), never resolved here by deleting another IP's record.
+    fn put_identity(&self, ip: IpAddr, rec: &IdentityRecord) -> anyhow::Result<()> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO identities (ip, nick, nick_key, updated, role) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![ip.to_string(), rec.nick, nick_key(&rec.nick), rec.updated, rec.role.map(|r| r.as_str())],
+        )?;
+        Ok(())
+    }
+
+    fn list_identities(&self, query: &str, args: impl rusqlite::Params) -> anyhow::Result<Vec<(IpAddr, IdentityRecord)>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(query)?;
+        let rows = stmt
+            .query_map(args, |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, u64>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows.into_iter()
+            .map(|(ip, nick, updated, role)| {
+                let ip = ip
+                    .parse::<IpAddr>()
+                    .with_context(|| format!("{}: invalid ip {ip:?}", self.path.display()))?;
+                let role = role.map(|r| r.parse::<Role>()).transpose()?;
+                Ok((ip, IdentityRecord { nick, updated, role }))
+            })
+            .collect()
+    }
+}
+
+fn migrate(conn: &mut Connection) -> anyhow::Result<()> {
+    // Take the write lock before reading the version so two processes starting at once
+    // cannot both apply the same step.
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let version: usize = tx.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        anyhow::bail!(
+            "schema version {version} is newer than this chatd supports ({})",
+            MIGRATIONS.len()
+        );
+    }
+    for (idx, sql) in MIGRATIONS.iter().enumerate().skip(version) {
+        tx.execute_batch(sql)
+            .with_context(|| format!("migration {}", idx + 1))?;
+        tx.pragma_update(None, "user_version", idx + 1)?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Message history in SQLite, trimmed to the newest `max` messages.
+#[derive(Debug, Clone)]
+pub struct SqliteHistory {
+    db: SqliteDb,
+    max: usize,
+}
+
+impl SqliteHistory {
+    pub fn new(db: SqliteDb, max: usize) -> Self {
+        Self { db, max }
+    }
+}
+
+#[async_trait]
+impl HistoryStore for SqliteHistory {
+    async fn push(&self, nick: String, text: String) -> anyhow::Result<()> {
+        let max = self.max as i64;
+        self.db
+            .blocking(move |db| {
+                let mut conn = db.conn();
+                let tx = conn.transaction()?;
+                tx.execute(
+                    "INSERT INTO history (nick, text, ts) VALUES (?1, ?2, ?3)",
+                    params![nick, text, now_ts()],
+                )?;
+                tx.execute(
+                    "DELETE FROM history WHERE id <= (SELECT MAX(id) FROM history) - ?1",
+                    params![max],
+                )?;
+                tx.commit()?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<HistoryItem>> {
+        self.db
+            .blocking(|db| {
+                let conn = db.conn();
+                let mut stmt = conn.prepare("SELECT id, nick, text, ts FROM history ORDER BY id")?;
+                let items = stmt.query_map([], history_row)?.collect::<Result<_, _>>()?;
+                Ok(items)
+            })
+            .await
+    }
+
+    async fn range(&self, query: HistoryQuery) -> anyhow::Result<Vec<HistoryItem>> {
+        self.db.blocking(move |db| range_items(db, query)).await
+    }
+
+    async fn purge(&self, from: u64, until: u64) -> anyhow::Result<usize> {
+        let from = from.min(i64::MAX as u64) as i64;
+        let until = until.min(i64::MAX as u64) as i64;
+        self.db
+            .blocking(move |db| {
+                let removed = db
+                    .conn()
+                    .execute("DELETE FROM history WHERE ts >= ?1 AND ts < ?2", params![from, until])?;
+                Ok(removed)
+            })
+            .await
+    }
+
+    async fn search(&self, query: &SearchQuery) -> anyhow::Result<Vec<HistoryItem>> {
+        if query.terms.is_empty() {
+            return Ok(Vec::new());
+        }
+        let query = query.clone();
+        self.db.blocking(move |db| search_items(db, &query)).await
+    }
+}
+
+fn range_items(db: &SqliteDb, query: HistoryQuery) -> anyhow::Result<Vec<HistoryItem>> {
+    let conn = db.conn();
+    let order = if query.forward() { "ASC" } else { "DESC" };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT id, nick, text, ts FROM history WHERE id > ?1 AND id < ?2 ORDER BY id {order} LIMIT ?3"
+    ))?;
+    let after = query.after.map_or(0, |id| id as i64);
+    let before = query.before.map_or(i64::MAX, |id| id.min(i64::MAX as u64) as i64);
+    let mut items: Vec<HistoryItem> = stmt
+        .query_map(params![after, before, query.limit as i64], history_row)?
+        .collect::<Result<_, _>>()?;
+    if !query.forward() {
+        items.reverse();
+    }
+    Ok(items)
+}
+
+fn search_items(db: &SqliteDb, query: &SearchQuery) -> anyhow::Result<Vec<HistoryItem>> {
+    let conn = db.conn();
+    let mut stmt = conn.prepare(
+        "SELECT h.id, h.nick, h.text, h.ts FROM history_fts JOIN history h ON h.id = history_fts.rowid
+         WHERE history_fts MATCH ?1 AND h.ts >= ?2 AND h.ts < ?3 AND (?4 IS NULL OR h.nick = ?4 COLLATE NOCASE)
+         ORDER BY h.id DESC LIMIT ?5",
+    )?;
+    let since = query.since.map_or(0, |ts| ts.min(i64::MAX as u64) as i64);
+    let until = query.until.map_or(i64::MAX, |ts| ts.min(i64::MAX as u64) as i64);
+    let items = stmt
+        .query_map(
+            params![fts_query(&query.terms), since, until, query.nick, query.limit as i64],
+            history_row,
+        )?
+        .collect::<Result<_, _>>()?;
+    Ok(items)
+}
+
+/// Turns search terms into an FTS5 query of quoted prefix tokens, all of which must match.
+fn fts_query(terms: &[String]) -> String {
+    terms
+        .iter()
+        .map(|term| format!("\"{}\"*", term.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn history_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<HistoryItem> {
+    Ok(HistoryItem {
+        id: row.get(0)?,
+        nick: row.get(1)?,
+        text: row.get(2)?,
+        ts: row.get(3)?,
+    })
+}
+
+/// Identities in SQLite.
+#[derive(Debug, Clone)]
+pub struct SqliteIdentityStore {
+    db: SqliteDb,
+}
+
+impl SqliteIdentityStore {
+    pub fn new(db: SqliteDb) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait]
+impl IdentityStore for SqliteIdentityStore {
+    async fn get(&self, ip: IpAddr) -> anyhow::Result<Option<IdentityRecord>> {
+        self.db.blocking(move |db| db.get_identity(ip)).await
+    }
+
+    async fn set(&self, ip: IpAddr, nick: String) -> anyhow::Result<()> {
+        self.db
+            .blocking(move |db| {
+                let role = db.get_identity(ip)?.and_then(|rec| rec.role);
+                let rec = IdentityRecord {
+                    nick,
+                    updated: now_ts(),
+                    role,
+                };
+                db.put_identity(ip, &rec)
+            })
+            .await
+    }
+
+    async fn set_role(&self, ip: IpAddr, role: Option<Role>) -> anyhow::Result<()> {
+        self.db
+            .blocking(move |db| {
+                let changed = db.conn().execute(
+                    "UPDATE identities SET role = ?2 WHERE ip = ?1",
+                    params![ip.to_string(), role.map(|r| r.as_str())],
+                )?;
+                if changed == 0 {
+                    anyhow::bail!("no identity for {ip}");
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    async fn put(&self, ip: IpAddr, rec: IdentityRecord) -> anyhow::Result<()> {
+        self.db.blocking(move |db| db.put_identity(ip, &rec)).await
+    }
+
+    async fn touch(&self, ip: IpAddr) -> anyhow::Result<()> {
+        self.db
+            .blocking(move |db| {
+                db.conn().execute(
+                    "UPDATE identities SET updated = ?2 WHERE ip = ?1",
+                    params![ip.to_string(), now_ts()],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn remove(&self, ip: IpAddr) -> anyhow::Result<()> {
+        self.db
+            .blocking(move |db| {
+                db.conn()
+                    .execute("DELETE FROM identities WHERE ip = ?1", params![ip.to_string()])?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<(IpAddr, IdentityRecord)>> {
+        self.db
+            .blocking(|db| db.list_identities("SELECT ip, nick, updated, role FROM identities ORDER BY ip", []))
+            .await
+    }
+
+    async fn prune(&self, cutoff: u64) -> anyhow::Result<Vec<(IpAddr, IdentityRecord)>> {
+        self.db
+            .blocking(move |db| {
+                // One statement, so a record touched or given a role meanwhile is never deleted
+                // without being returned.
+                let mut expired = db.list_identities(
+                    "DELETE FROM identities WHERE role IS NULL AND updated < ?1 RETURNING ip, nick, updated, role",
+                    params![cutoff],
+                )?;
+                expired.sort_by_cached_key(|(ip, _)| ip.to_string());
+                Ok(expired)
+            })
+            .await
+    }
+
+    async fn nick_holder(&self, nick: &str, except: IpAddr) -> anyhow::Result<Option<IpAddr>> {
+        let key = nick_key(nick);
+        let ip: Option<String> = self
+            .db
+            .blocking(move |db| {
+                let ip = db
+                    .conn()
+                    .query_row(
+                        "SELECT ip FROM identities WHERE nick_key = ?1 AND ip != ?2 ORDER BY ip LIMIT 1",
+                        params![key, except.to_string()],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                Ok(ip)
+            })
+            .await?;
+        ip.map(|ip| ip.parse().with_context(|| format!("invalid ip {ip:?} in identities")))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn stores_survive_reopen() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("chat.db");
+        let db = SqliteDb::open(&path).unwrap();
+        let history = SqliteHistory::new(db.clone(), 2);
+        for text in ["one", "two", "three"] {
+            history.push("alice".into(), text.into()).await.unwrap();
+        }
+
+        let ids = SqliteIdentityStore::new(db.clone());
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+        ids.set(a, "alice".into()).await.unwrap();
+        ids.set_role(a, Some(Role::Moderator)).await.unwrap();
+        ids.set(a, "alicia".into()).await.unwrap();
+        ids.put(
+            b,
+            IdentityRecord {
+                nick: "bob".into(),
+                updated: 1,
+                role: None,
+            },
+        )
+        .await
+        .unwrap();
+        assert!(ids.set_role("192.0.2.9".parse().unwrap(), None).await.is_err());
+        drop((history, ids, db));
+
+        let db = SqliteDb::open(&path).unwrap();
+        let texts: Vec<_> = SqliteHistory::new(db.clone(), 2)
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|item| item.text)
+            .collect();
+        assert_eq!(texts, ["two", "three"]);
+        let older = HistoryQuery {
+            before: Some(3),
+            ..HistoryQuery::latest(5)
+        };
+        let page = SqliteHistory::new(db.clone(), 2).range(older).await.unwrap();
+        assert_eq!(page.iter().map(|item| item.id).collect::<Vec<_>>(), [2]);
+        // "one" was trimmed, so the index must have dropped it too.
+        let search = |text: &str| SearchQuery {
+            terms: crate::history::search_terms(text),
+            limit: 10,
+            ..SearchQuery::default()
+        };
+        let history = SqliteHistory::new(db.clone(), 2);
+        assert!(history.search(&search("one")).await.unwrap().is_empty());
+        let found = history.search(&search("TH")).await.unwrap();
+        assert_eq!(found.iter().map(|item| item.text.as_str()).collect::<Vec<_>>(), ["three"]);
+        let from_bob = SearchQuery {
+            nick: Some("bob".into()),
+            ..search("two")
+        };
+        assert!(history.search(&from_bob).await.unwrap().is_empty());
+        assert_eq!(history.purge(0, now_ts() + 1).await.unwrap(), 2);
+        assert!(history.search(&search("three")).await.unwrap().is_empty());
+
+        let ids = SqliteIdentityStore::new(db.clone());
+        let rec = ids.get(a).await.unwrap().unwrap();
+        assert_eq!((rec.nick.as_str(), rec.role), ("alicia", Some(Role::Moderator)));
+        assert_eq!(ids.nick_holder("Alicia", b).await.unwrap(), Some(a));
+        assert_eq!(ids.nick_holder("alicia", a).await.unwrap(), None);
+        // A clashing record is stored alongside; the older holder and its role stay.
+        ids.set(b, "Alicia".into()).await.unwrap();
+        assert_eq!(ids.get(a).await.unwrap().unwrap().role, Some(Role::Moderator));
+        assert_eq!(ids.prune(now_ts() + 10).await.unwrap().len(), 1);
+        assert_eq!(ids.list().await.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn access_lists_in_database() {
+        use crate::access::AccessCache;
+        use crate::allowlist::{Access, AllowlistFiles, ListStore};
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("chat.db");
+        let files = |db: SqliteDb| AllowlistFiles {
+            allowlist: dir.path().join("allowed.toml"),
+            pending: dir.path().join("pending.toml"),
+            denylist: dir.path().join("denied.toml"),
+            store: ListStore::Sqlite(db),
+        };
+        let server = files(SqliteDb::open(&path).unwrap());
+        server.add_allow("10.0.0.0/8", Some("office".into()), None, None).unwrap();
+        server.add_deny("10.9.0.0/16", Some("lab".into()), None).unwrap();
+        let cache = AccessCache::load(server.clone()).unwrap();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(cache.check("10.1.2.3".parse().unwrap()), Access::Allowed);
+        assert_eq!(cache.check("10.9.2.3".parse().unwrap()), Access::Denied);
+        assert_eq!(cache.check(ip), Access::Pending);
+        cache.knock(ip, ip, "Dana", "new laptop");
+        cache.flush_pending().unwrap();
+        let pending = server.list_pending().unwrap();
+        assert_eq!(pending[0].0, "192.0.2.1");
+        assert_eq!(pending[0].1.knock.as_ref().unwrap().name, "Dana");
+        assert!(!std::path::Path::new(&server.pending).exists());
+
+        // A write from another connection, like a `chatd allow add`, is picked up.
+        assert!(!cache.reload_if_changed().unwrap());
+        let admin = files(SqliteDb::open(&path).unwrap());
+        admin.add_allow("192.0.2.0/24", None, None, None).unwrap();
+        assert!(admin.remove_pending("192.0.2.1").unwrap());
+        assert!(cache.reload_if_changed().unwrap());
+        assert_eq!(cache.check(ip), Access::Allowed);
+        assert_eq!(server.list_allow().unwrap()[0].label.as_deref(), Some("office"));
+        assert!(server.list_pending().unwrap().is_empty());
+    }
+
+    #[test]
+    fn migrations_are_recorded() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("chat.db");
+        SqliteDb::open(&path).unwrap();
+        let conn = Connection::open(&path).unwrap();
+        let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() + 1).unwrap();
+        drop(conn);
+        let err = SqliteDb::open(&path).unwrap_err();
+        assert!(format!("{err:#}").contains("newer than this chatd"), "{err:#}");
+    }
+
+    #[test]
+    fn read_only_open_never_migrates() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("chat.db");
+        let err = SqliteDb::open_read_only(&path).unwrap_err();
+        assert!(err.to_string().contains("does not exist"), "{err:#}");
+        assert!(!path.exists());
+
+        let conn = Connection::open(&path).unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+        let err = SqliteDb::open_read_only(&path).unwrap_err();
+        assert!(err.to_string().contains("out of date"), "{err:#}");
+        let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap();
+        assert_eq!(version, 1);
+        drop(conn);
+
+        std::fs::remove_file(&path).unwrap();
+        SqliteDb::open(&path).unwrap();
+        let db = SqliteDb::open_read_only(&path).unwrap();
+        assert!(db.load_allow().unwrap().allow.is_empty());
+        assert!(db.save_allow(&AllowedList::default()).is_err());
+    }
+}
@@ -1,7 +1,11 @@
 use anyhow::Context;
+use serde::de::DeserializeOwned;
 use std::fs;
 use std::path::Path;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// 32 symbols without the easily confused 0/O and 1/I.
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
 
 pub fn now_ts() -> u64 {
     SystemTime::now()
@@ -16,3 +20,62 @@ pub fn atomic_write(path: &Path, data: &[u8]) -> anyhow::Result<()> {
     fs::rename(&tmp, path).context("rename temp file")?;
     Ok(())
 }
+
+/// Reads and parses a TOML file, or returns `T::default()` if it does not exist.
+///
+/// Parse errors name the file and carry the line and column from the TOML parser.
+pub fn load_toml<T: DeserializeOwned + Default>(path: &Path) -> anyhow::Result<T> {
+    if !path.exists() {
+        return Ok(T::default());
+    }
+    let raw = fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+    toml::from_str::<T>(&raw).map_err(|err| anyhow::anyhow!("{}: {}", path.display(), err.to_string().trim_end()))
+}
+
+/// Random code over `CODE_ALPHABET`, for reference and invite codes.
+///
+/// Invite codes are bearer secrets, so the bytes come from the OS CSPRNG. The alphabet has
+/// 32 symbols, so masking each byte to 5 bits keeps the distribution uniform.
+pub fn random_code(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    getrandom::getrandom(&mut bytes).expect("OS random number generator unavailable");
+    bytes
+        .into_iter()
+        .map(|b| CODE_ALPHABET[(b & 31) as usize] as char)
+        .collect()
+}
+
+/// Parses durations like `90`, `45s`, `30m`, `12h`, `7d` or `2w`; a bare number is seconds.
+pub fn parse_duration(raw: &str) -> anyhow::Result<Duration> {
+    let raw = raw.trim();
+    let split = raw
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(raw.len());
+    let (num, unit) = raw.split_at(split);
+    let num: u64 = num
+        .parse()
+        .with_context(|| format!("invalid duration {raw:?}"))?;
+    let secs = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => anyhow::bail!("invalid duration unit {unit:?}, expected s, m, h, d or w"),
+    };
+    Ok(Duration::from_secs(num.saturating_mul(secs)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("30m").unwrap(), Duration::from_secs(1800));
+        assert_eq!(parse_duration("7d").unwrap(), Duration::from_secs(604_800));
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("5y").is_err());
+    }
+}
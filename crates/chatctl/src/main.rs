@@ -1,7 +1,9 @@
 use anyhow::{Context, Result};
-use chat_core::protocol::{clean_line, parse_server_line, ClientMsg, ServerMsg, MAX_LINE};
+use chat_core::protocol::{clean_line, format_client_msg, parse_server_line, ClientMsg, ServerMsg, MAX_LINE};
+use chat_core::roles::Role;
+use chat_core::util::{now_ts, parse_duration};
 use clap::Parser;
-use chrono::Local;
+use chrono::{Local, TimeZone};
 use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
 use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
 use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
@@ -134,7 +136,11 @@ async fn main() -> Result<()> {
     let pending_prompt: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
     let initial_nick = cli.nick.clone();
 
+    // Oldest history id shown so far, where /more continues from.
+    let oldest_hist: Arc<Mutex<Option<u64>>> = Arc::new(Mutex::new(None));
+
     let pending_clone = pending_prompt.clone();
+    let oldest_clone = oldest_hist.clone();
     let reader_task = tokio::spawn(async move {
         while let Ok(Some(line)) = lines.next_line().await {
             if let Ok(msg) = parse_server_line(&line) {
@@ -147,8 +153,25 @@ async fn main() -> Result<()> {
                     ServerMsg::Msg { nick, text } => {
                         println!("{} {}: {}", ts(), nick, text);
                     }
-                    ServerMsg::Hist { nick, text } => {
-                        println!("{} {}: {}", ts(), nick, text);
+                    // Sent for older clients alongside HIST2, which carries the same message.
+                    ServerMsg::Hist { .. } => {}
+                    ServerMsg::Hist2 { id, ts: sent, nick, text } => {
+                        let mut oldest = oldest_clone.lock().await;
+                        *oldest = Some(oldest.map_or(id, |old| old.min(id)));
+                        println!("{} {}: {}", hist_ts(sent), nick, text);
+                    }
+                    ServerMsg::HistEnd { more } => {
+                        if more {
+                            println!("{} [history] /more for older messages", ts());
+                        } else {
+                            println!("{} [history] no older messages", ts());
+                        }
+                    }
+                    ServerMsg::Found { ts: sent, nick, text, .. } => {
+                        println!("[search] {} {}: {}", hist_ts(sent), nick, text);
+                    }
+                    ServerMsg::FoundEnd { count } => {
+                        println!("[search] {} found", count);
                     }
                     ServerMsg::Who { count, nicks } => {
                         println!("{} online: {}", count, nicks.join(", "));
@@ -156,6 +179,15 @@ async fn main() -> Result<()> {
                     ServerMsg::Sys { text } => {
                         println!("{} [sys] {}", ts(), text);
                     }
+                    ServerMsg::Whois { nick, role } => {
+                        println!("{} is {}", nick, role);
+                    }
+                    ServerMsg::Pending { ip, attempts } => {
+                        println!("{} [pending] {} attempts={} (/approve or /reject)", ts(), ip, attempts);
+                    }
+                    ServerMsg::Error { code, text } => {
+                        println!("{} [error] {} ({})", ts(), text, code);
+                    }
                 }
             }
         }
@@ -167,78 +199,74 @@ async fn main() -> Result<()> {
         let mut input = TokioBufReader::new(stdin).lines();
         let mut used_initial = false;
 
-        loop {
-            if let Some(line) = input.next_line().await.ok().flatten() {
-                let Some(clean) = clean_line(&line) else { continue; };
-                if clean.len() > MAX_LINE {
-                    eprintln!("input too long");
-                    continue;
-                }
+        while let Some(line) = input.next_line().await.ok().flatten() {
+            let Some(clean) = clean_line(&line) else { continue; };
+            if clean.len() > MAX_LINE {
+                eprintln!("input too long");
+                continue;
+            }
 
-                let mut pending = pending_clone.lock().await;
-                if let Some(prompt_id) = pending.take() {
-                    if let Some(nick) = initial_nick.as_ref() {
-                        if !used_initial && prompt_id == "nick" {
-                            used_initial = true;
-                            let msg = ClientMsg::Prompt {
-                                id: prompt_id,
-                                answer: nick.clone(),
-                            };
-                            let line = format_client_msg(msg);
-                            if writer.write_all(line.as_bytes()).await.is_err() {
-                                break;
-                            }
-                            if writer.write_all(b"\n").await.is_err() {
-                                break;
-                            }
-                            continue;
+            let mut pending = pending_clone.lock().await;
+            if let Some(prompt_id) = pending.take() {
+                if let Some(nick) = initial_nick.as_ref() {
+                    if !used_initial && prompt_id == "nick" {
+                        used_initial = true;
+                        let msg = ClientMsg::Prompt {
+                            id: prompt_id,
+                            answer: nick.clone(),
+                        };
+                        let line = format_client_msg(&msg);
+                        if writer.write_all(line.as_bytes()).await.is_err() {
+                            break;
                         }
-                        if !used_initial && prompt_id == "keep_nick" {
-                            let msg = ClientMsg::Prompt {
-                                id: prompt_id,
-                                answer: "y".into(),
-                            };
-                            let line = format_client_msg(msg);
-                            if writer.write_all(line.as_bytes()).await.is_err() {
-                                break;
-                            }
-                            if writer.write_all(b"\n").await.is_err() {
-                                break;
-                            }
-                            continue;
+                        if writer.write_all(b"\n").await.is_err() {
+                            break;
                         }
+                        continue;
                     }
-
-                    let msg = ClientMsg::Prompt {
-                        id: prompt_id,
-                        answer: clean.clone(),
-                    };
-                    let line = format_client_msg(msg);
-                    if writer.write_all(line.as_bytes()).await.is_err() {
-                        break;
-                    }
-                    if writer.write_all(b"\n").await.is_err() {
-                        break;
-                    }
-                    continue;
-                }
-
-                if clean.starts_with('/') {
-                    if handle_local_command(&clean, &mut writer).await? {
-                        break;
+                    if !used_initial && prompt_id == "keep_nick" {
+                        let msg = ClientMsg::Prompt {
+                            id: prompt_id,
+                            answer: "y".into(),
+                        };
+                        let line = format_client_msg(&msg);
+                        if writer.write_all(line.as_bytes()).await.is_err() {
+                            break;
+                        }
+                        if writer.write_all(b"\n").await.is_err() {
+                            break;
+                        }
+                        continue;
                     }
-                    continue;
                 }
 
-                let msg = ClientMsg::Say { text: clean };
-                let line = format_client_msg(msg);
+                let msg = ClientMsg::Prompt {
+                    id: prompt_id,
+                    answer: clean.clone(),
+                };
+                let line = format_client_msg(&msg);
                 if writer.write_all(line.as_bytes()).await.is_err() {
                     break;
                 }
                 if writer.write_all(b"\n").await.is_err() {
                     break;
                 }
-            } else {
+                continue;
+            }
+
+            if clean.starts_with('/') {
+                if handle_local_command(&clean, &mut writer, &oldest_hist).await? {
+                    break;
+                }
+                continue;
+            }
+
+            let msg = ClientMsg::Say { text: clean };
+            let line = format_client_msg(&msg);
+            if writer.write_all(line.as_bytes()).await.is_err() {
+                break;
+            }
+            if writer.write_all(b"\n").await.is_err() {
                 break;
             }
         }
@@ -251,27 +279,29 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-fn format_client_msg(msg: ClientMsg) -> String {
-    match msg {
-        ClientMsg::Nick { nick } => format!("NICK {}", nick),
-        ClientMsg::Say { text } => format!("SAY {}", text),
-        ClientMsg::Who => "WHO".into(),
-        ClientMsg::Quit => "QUIT".into(),
-        ClientMsg::Prompt { id, answer } => format!("PROMPT {} {}", id, answer),
-    }
-}
-
 fn ts() -> String {
     Local::now().format("%H:%M:%S").to_string()
 }
 
-async fn handle_local_command(line: &str, writer: &mut tokio::io::WriteHalf<tokio_rustls::client::TlsStream<TcpStream>>) -> Result<bool> {
+/// History lines carry the date too, since they can be days old.
+fn hist_ts(secs: u64) -> String {
+    match Local.timestamp_opt(secs as i64, 0).single() {
+        Some(at) => at.format("%m-%d %H:%M").to_string(),
+        None => "--".to_string(),
+    }
+}
+
+async fn handle_local_command(
+    line: &str,
+    writer: &mut tokio::io::WriteHalf<tokio_rustls::client::TlsStream<TcpStream>>,
+    oldest_hist: &Mutex<Option<u64>>,
+) -> Result<bool> {
     let mut parts = line.splitn(2, ' ');
     let cmd = parts.next().unwrap_or("");
     let rest = parts.next().unwrap_or("");
     match cmd {
         "/help" => {
-            println!("/help /nick <name> /who /quit");
+            println!("/help /nick <name> /who /whois <nick> /kick <nick> [reason] /role <nick> <role> /pending /approve <ip> /reject <ip> /conns /offenders /slow [seconds] /history [n] /more [n] /search [nick=<nick>] [since=<age>] [until=<age>] <words> /purge <age> [age] /quit");
         }
         "/nick" => {
             let nick = rest.trim();
@@ -281,18 +311,147 @@ async fn handle_local_command(line: &str, writer: &mut tokio::io::WriteHalf<toki
                 let msg = ClientMsg::Nick {
                     nick: nick.to_string(),
                 };
-                let line = format_client_msg(msg);
+                let line = format_client_msg(&msg);
                 writer.write_all(line.as_bytes()).await?;
                 writer.write_all(b"\n").await?;
             }
         }
         "/who" => {
-            let line = format_client_msg(ClientMsg::Who);
+            let line = format_client_msg(&ClientMsg::Who);
             writer.write_all(line.as_bytes()).await?;
             writer.write_all(b"\n").await?;
         }
+        "/whois" => {
+            let nick = rest.trim();
+            if nick.is_empty() {
+                eprintln!("usage: /whois <nick>");
+            } else {
+                let line = format_client_msg(&ClientMsg::Whois { nick: nick.into() });
+                writer.write_all(line.as_bytes()).await?;
+                writer.write_all(b"\n").await?;
+            }
+        }
+        "/kick" => {
+            let mut args = rest.trim().splitn(2, ' ');
+            let nick = args.next().unwrap_or("").trim();
+            let reason = args.next().map(|r| r.trim().to_string()).filter(|r| !r.is_empty());
+            if nick.is_empty() {
+                eprintln!("usage: /kick <nick> [reason]");
+            } else {
+                let line = format_client_msg(&ClientMsg::Kick {
+                    nick: nick.into(),
+                    reason,
+                });
+                writer.write_all(line.as_bytes()).await?;
+                writer.write_all(b"\n").await?;
+            }
+        }
+        "/role" => {
+            let mut args = rest.split_whitespace();
+            match (args.next(), args.next().map(|r| r.parse::<Role>())) {
+                (Some(nick), Some(Ok(role))) => {
+                    let line = format_client_msg(&ClientMsg::Role {
+                        nick: nick.into(),
+                        role,
+                    });
+                    writer.write_all(line.as_bytes()).await?;
+                    writer.write_all(b"\n").await?;
+                }
+                _ => eprintln!("usage: /role <nick> <guest|member|moderator|admin|owner>"),
+            }
+        }
+        "/pending" => {
+            let line = format_client_msg(&ClientMsg::Pending);
+            writer.write_all(line.as_bytes()).await?;
+            writer.write_all(b"\n").await?;
+        }
+        "/offenders" => {
+            let line = format_client_msg(&ClientMsg::Offenders);
+            writer.write_all(line.as_bytes()).await?;
+            writer.write_all(b"\n").await?;
+        }
+        "/slow" => {
+            let arg = rest.trim();
+            let seconds = if arg.is_empty() {
+                Ok(None)
+            } else {
+                parse_duration(arg).map(|d| Some(d.as_secs()))
+            };
+            match seconds {
+                Ok(seconds) => {
+                    let line = format_client_msg(&ClientMsg::Slow { seconds });
+                    writer.write_all(line.as_bytes()).await?;
+                    writer.write_all(b"\n").await?;
+                }
+                Err(_) => eprintln!("usage: /slow [seconds, e.g. 30 or 2m; 0 turns it off]"),
+            }
+        }
+        "/history" | "/more" => {
+            let arg = rest.trim();
+            let limit = if arg.is_empty() { Ok(None) } else { arg.parse::<usize>().map(Some) };
+            match limit {
+                Ok(limit) => {
+                    let mut oldest = oldest_hist.lock().await;
+                    let before = if cmd == "/history" {
+                        // A fresh page of the latest messages; /more starts over from it.
+                        *oldest = None;
+                        None
+                    } else if oldest.is_some() {
+                        *oldest
+                    } else {
+                        eprintln!("no history loaded yet, try /history");
+                        return Ok(false);
+                    };
+                    drop(oldest);
+                    let line = format_client_msg(&ClientMsg::History {
+                        before,
+                        after: None,
+                        limit,
+                    });
+                    writer.write_all(line.as_bytes()).await?;
+                    writer.write_all(b"\n").await?;
+                }
+                Err(_) => eprintln!("usage: {} [count]", cmd),
+            }
+        }
+        "/search" => match parse_search(rest) {
+            Some(msg) => {
+                let line = format_client_msg(&msg);
+                writer.write_all(line.as_bytes()).await?;
+                writer.write_all(b"\n").await?;
+            }
+            None => eprintln!("usage: /search [nick=<nick>] [since=<age, e.g. 7d>] [until=<age>] <words>"),
+        },
+        "/purge" => match parse_purge(rest) {
+            Some(msg) => {
+                let line = format_client_msg(&msg);
+                writer.write_all(line.as_bytes()).await?;
+                writer.write_all(b"\n").await?;
+            }
+            None => eprintln!("usage: /purge <from age> [until age], e.g. /purge 2h 1h; until defaults to now"),
+        },
+        "/conns" => {
+            let line = format_client_msg(&ClientMsg::Conns);
+            writer.write_all(line.as_bytes()).await?;
+            writer.write_all(b"\n").await?;
+        }
+        "/approve" | "/reject" => {
+            let ip = rest.trim();
+            if ip.is_empty() {
+                eprintln!("usage: {} <ip>", cmd);
+            } else {
+                let msg = if cmd == "/approve" {
+                    ClientMsg::Approve { ip: ip.into() }
+                } else {
+                    ClientMsg::Reject { ip: ip.into() }
+                };
+                let line = format_client_msg(&msg);
+                writer.write_all(line.as_bytes()).await?;
+                writer.write_all(b"\n").await?;
+            }
+        }
         "/quit" => {
-            let line = format_client_msg(ClientMsg::Quit);
+            let line = format_client_msg(&ClientMsg::Quit);
             writer.write_all(line.as_bytes()).await?;
             writer.write_all(b"\n").await?;
             return Ok(true);
@@ -304,6 +463,46 @@ async fn handle_local_command(line: &str, writer: &mut tokio::io::WriteHalf<toki
     Ok(false)
 }
 
+/// Reads `/search` arguments; `since` and `until` are ages like `7d`, sent as unix seconds.
+fn parse_search(args: &str) -> Option<ClientMsg> {
+    let (mut nick, mut since, mut until) = (None, None, None);
+    let mut query = args.trim();
+    loop {
+        let (part, tail) = query.split_once(' ').unwrap_or((query, ""));
+        match part.split_once('=') {
+            Some(("nick", value)) if !value.is_empty() => nick = Some(value.to_string()),
+            Some(("since", value)) => since = Some(now_ts().saturating_sub(parse_duration(value).ok()?.as_secs())),
+            Some(("until", value)) => until = Some(now_ts().saturating_sub(parse_duration(value).ok()?.as_secs())),
+            _ => break,
+        }
+        query = tail.trim_start();
+    }
+    if query.is_empty() {
+        return None;
+    }
+    Some(ClientMsg::Search {
+        query: query.to_string(),
+        nick,
+        since,
+        until,
+    })
+}
+
+/// Turns `/purge 2h 1h` into the range from two hours ago to one hour ago.
+fn parse_purge(args: &str) -> Option<ClientMsg> {
+    let now = now_ts();
+    let mut ages = args.split_whitespace();
+    let from = now.saturating_sub(parse_duration(ages.next()?).ok()?.as_secs());
+    let until = match ages.next() {
+        Some(age) => now.saturating_sub(parse_duration(age).ok()?.as_secs()),
+        None => now + 1,
+    };
+    if ages.next().is_some() || from >= until {
+        return None;
+    }
+    Some(ClientMsg::Purge { from, until })
+}
+
 fn build_root_store(ca: Option<&PathBuf>, insecure: bool) -> Result<RootCertStore> {
     let mut root = RootCertStore::empty();
     if !insecure {
@@ -1,19 +1,35 @@
 use anyhow::{Context, Result};
-use chat_core::allowlist::AllowlistFiles;
-use chat_core::history::{HistoryStore, InMemoryHistory};
-use chat_core::identities::{FileIdentityStore, IdentityStore};
+use chat_core::access::AccessCache;
+use chat_core::addr::{canonical_ip, IpKeying};
+use chat_core::allowlist::{parse_net, Access, AllowlistFiles, ListStore};
+use chat_core::audit::AuditLog;
+use chat_core::bans::{Ban, BanPolicy, Offenders, Offense};
+use chat_core::conns::{AcceptLimiter, ConnLimits, ConnRefusal};
+use chat_core::history::{search_terms, HistoryQuery, HistoryStore, InMemoryHistory, RetentionPolicy, SearchQuery, TooBroad, SEARCH_MIN_TERM};
+use chat_core::identities::{export_toml, import_toml, FileIdentityStore, IdentityStore};
+use chat_core::invites::{InviteList, Invites};
+use chat_core::nick::{nick_key, NickPolicy};
 use chat_core::protocol::{clean_line, format_server_msg, parse_client_line, ClientMsg, ServerMsg};
-use chat_core::MAX_NICK;
+use chat_core::proxy::read_proxy_header;
+use chat_core::rate::{CommandCosts, RateAlgorithm, RateBackend, RateConfig, SharedLimiter};
+use chat_core::roles::{Permission, Role, RolesConfig};
+use chat_core::slow::SlowMode;
+use chat_core::spam::{SpamAction, SpamFilter, SpamPolicy, Verdict};
+#[cfg(feature = "sqlite")]
+use chat_core::sqlite::SqliteDb;
+use chat_core::util::{now_ts, parse_duration};
 use clap::{Parser, Subcommand};
+use ipnet::IpNet;
+use std::collections::HashMap;
 use std::net::IpAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, Notify};
 use tokio_rustls::TlsAcceptor;
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
 
 mod state;
 mod tls;
@@ -44,20 +60,108 @@ struct Cli {
     #[arg(long, default_value = "./pending.toml")]
     pending: PathBuf,
 
+    #[arg(long, default_value = "./denied.toml")]
+    denylist: PathBuf,
+
     #[arg(long, default_value = "./identities.toml")]
     identities: PathBuf,
 
+    #[arg(long, default_value = "./roles.toml")]
+    roles: PathBuf,
+
+    #[arg(long, default_value = "./nick_policy.toml")]
+    nick_policy: PathBuf,
+
+    #[arg(long, default_value = "./audit.log")]
+    audit_log: PathBuf,
+
+    #[arg(long, default_value = "./invites.toml")]
+    invites: PathBuf,
+
+    /// Per-command rate-limit costs and the nickname cooldown.
+    #[arg(long, default_value = "./rate_costs.toml")]
+    rate_costs: PathBuf,
+
+    /// Thresholds and backoff for automatic bans.
+    #[arg(long, default_value = "./bans.toml")]
+    ban_policy: PathBuf,
+
+    /// Duplicate-message and flood-pattern checks for chat messages.
+    #[arg(long, default_value = "./spam.toml")]
+    spam_policy: PathBuf,
+
+    /// How many messages history keeps, for how long, and whether it stores any.
+    #[arg(long, default_value = "./retention.toml")]
+    retention: PathBuf,
+
     #[arg(long)]
     redis: Option<String>,
 
+    /// Keep history, identities and the access lists in an embedded database instead: `sqlite:PATH`.
+    #[arg(long, value_parser = parse_db, conflicts_with = "redis")]
+    db: Option<PathBuf>,
+
     #[arg(long, default_value_t = 20)]
     ip_rate: u32,
 
+    /// Messages an IP may send at once before `--ip-rate` applies (defaults to the rate).
+    #[arg(long)]
+    ip_burst: Option<u32>,
+
     #[arg(long, default_value_t = 5)]
     conn_rate: u32,
 
+    /// Messages a connection may send at once before `--conn-rate` applies (defaults to the rate).
+    #[arg(long)]
+    conn_burst: Option<u32>,
+
+    /// `token-bucket`, or `fixed-window` for the old per-second counter.
+    #[arg(long, default_value_t = RateAlgorithm::TokenBucket)]
+    rate_algorithm: RateAlgorithm,
+
+    /// Where per-IP message counters live: `local`, or `redis` (needs `--redis`) to share them between instances.
+    #[arg(long, default_value_t = RateBackend::Local)]
+    rate_backend: RateBackend,
+
     #[arg(long)]
     idle_timeout: Option<u64>,
+
+    /// Seconds each client has to wait between chat messages at startup (0 = off); moderators change it with SLOW.
+    #[arg(long, default_value_t = 0)]
+    slow_mode: u64,
+
+    #[arg(long, value_parser = parse_ttl)]
+    identity_ttl: Option<Duration>,
+
+    /// Track IPv6 clients by this prefix length for identities and rate limits (e.g. 64).
+    #[arg(long, default_value_t = 128)]
+    ipv6_prefix: u8,
+
+    /// Expect a PROXY protocol header on connections from this CIDR (repeatable).
+    #[arg(long = "trusted-proxy", value_parser = parse_proxy_net)]
+    trusted_proxies: Vec<IpNet>,
+
+    /// Simultaneous connections allowed per client key (0 = unlimited).
+    #[arg(long, default_value_t = 10)]
+    max_conns_per_ip: usize,
+
+    /// Simultaneous connections allowed in total (0 = unlimited).
+    #[arg(long, default_value_t = 1000)]
+    max_clients: usize,
+
+    /// New connections per second accepted from one client key (0 = unlimited).
+    #[arg(long, default_value_t = 5)]
+    accept_rate: u32,
+
+    #[arg(long, default_value_t = 20)]
+    accept_burst: u32,
+
+    /// New connections per second accepted in total (0 = unlimited).
+    #[arg(long, default_value_t = 200)]
+    global_accept_rate: u32,
+
+    #[arg(long, default_value_t = 400)]
+    global_accept_burst: u32,
 }
 
 #[derive(Subcommand, Debug)]
@@ -70,15 +174,58 @@ enum Commands {
         #[command(subcommand)]
         command: PendingCommands,
     },
+    Deny {
+        #[command(subcommand)]
+        command: DenyCommands,
+    },
+    Role {
+        #[command(subcommand)]
+        command: RoleCommands,
+    },
+    Identities {
+        #[command(subcommand)]
+        command: IdentityCommands,
+    },
+    Invite {
+        #[command(subcommand)]
+        command: InviteCommands,
+    },
+    /// Validate every state file (and the TLS pair, if given) without starting the server.
+    Check,
 }
 
 #[derive(Subcommand, Debug)]
 enum AllowCommands {
-    Add { entry: String },
+    Add {
+        entry: String,
+        #[arg(long)]
+        label: Option<String>,
+        /// Who the entry belongs to; defaults to $USER.
+        #[arg(long)]
+        owner: Option<String>,
+        #[arg(long, value_parser = parse_duration)]
+        expires: Option<Duration>,
+    },
     Remove { entry: String },
     List,
 }
 
+#[derive(Subcommand, Debug)]
+enum InviteCommands {
+    Create {
+        #[arg(long, default_value_t = 1)]
+        uses: u32,
+        #[arg(long, value_parser = parse_duration)]
+        expires: Option<Duration>,
+        #[arg(long)]
+        note: Option<String>,
+    },
+    Revoke {
+        code: String,
+    },
+    List,
+}
+
 #[derive(Subcommand, Debug)]
 enum PendingCommands {
     List,
@@ -86,6 +233,101 @@ enum PendingCommands {
     Clear,
 }
 
+#[derive(Subcommand, Debug)]
+enum DenyCommands {
+    Add {
+        entry: String,
+        #[arg(long)]
+        reason: Option<String>,
+        #[arg(long, value_parser = parse_duration)]
+        expires: Option<Duration>,
+    },
+    Remove {
+        entry: String,
+    },
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+enum RoleCommands {
+    Set { ip: IpAddr, role: Role },
+    Clear { ip: IpAddr },
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+enum IdentityCommands {
+    List,
+    Show {
+        ip: IpAddr,
+    },
+    Remove {
+        ip: IpAddr,
+    },
+    /// Change the nickname of an identity and/or move it to another IP.
+    Rename {
+        ip: IpAddr,
+        #[arg(long)]
+        nick: Option<String>,
+        #[arg(long)]
+        to: Option<IpAddr>,
+    },
+    /// Load identities from a file; nicknames that fail the nick policy or look like one
+    /// held by another identity are refused unless `--force` is given.
+    Import {
+        file: PathBuf,
+        #[arg(long)]
+        force: bool,
+    },
+    Export {
+        file: Option<PathBuf>,
+    },
+    Prune {
+        #[arg(long, value_parser = parse_duration)]
+        older_than: Duration,
+    },
+}
+
+const IDENTITY_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const IDENTITY_FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+const HISTORY_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+const ACCESS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const PENDING_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+const KNOCK_TIMEOUT: Duration = Duration::from_secs(120);
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(5);
+/// Busiest client keys listed by CONNS and OFFENDERS.
+const CONNS_TOP: usize = 10;
+/// Messages replayed on join, and the page size when HISTORY gives no limit.
+const HISTORY_PAGE: usize = 20;
+const HISTORY_MAX_PAGE: usize = 100;
+/// Newest matches returned for one SEARCH.
+const SEARCH_LIMIT: usize = 20;
+
+#[derive(Clone)]
+struct Ctx {
+    hub: Arc<tokio::sync::Mutex<HubState>>,
+    access: Arc<AccessCache>,
+    invites: Arc<Invites>,
+    ip_keying: IpKeying,
+    conns: Arc<ConnLimits>,
+    accepts: Arc<AcceptLimiter>,
+    offenders: Arc<Offenders>,
+    costs: Arc<CommandCosts>,
+    shared_rate: Option<Arc<dyn SharedLimiter>>,
+    spam: Arc<SpamFilter>,
+    slow: Arc<SlowMode>,
+    history: Arc<dyn HistoryStore>,
+    /// False when retention.toml opts the room out of history.
+    store_history: bool,
+    identities: Arc<dyn IdentityStore>,
+    roles: Arc<RolesConfig>,
+    nick_policy: Arc<NickPolicy>,
+    audit: AuditLog,
+    motd: Option<String>,
+    idle_timeout: Option<u64>,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt()
@@ -97,8 +339,8 @@ async fn main() -> Result<()> {
         return handle_admin(command, &cli).await;
     }
 
-    let cert = cli.cert.context("--cert is required")?;
-    let key = cli.key.context("--key is required")?;
+    let cert = cli.cert.clone().context("--cert is required")?;
+    let key = cli.key.clone().context("--key is required")?;
 
     let tls_config = tls::load_server_config(&cert, &key)?;
     let acceptor = TlsAcceptor::from(Arc::new(tls_config));
@@ -106,153 +348,1030 @@ async fn main() -> Result<()> {
     let listener = TcpListener::bind(&cli.bind).await?;
     info!(bind = %cli.bind, "chatd listening");
 
-    let allow_files = Arc::new(AllowlistFiles {
-        allowlist: cli.allowlist.clone(),
-        pending: cli.pending.clone(),
-    });
+    let access = Arc::new(AccessCache::load(allowlist_files(&cli)?)?);
 
-    let identities: Arc<dyn IdentityStore> = if let Some(url) = cli.redis.clone() {
+    let ip_keying = IpKeying::new(cli.ipv6_prefix)?;
+    let identities = open_identities(&cli)?;
+    tokio::spawn(flush_identities(identities.clone()));
+    if let Some(ttl) = cli.identity_ttl {
+        tokio::spawn(sweep_identities(identities.clone(), ttl));
+    }
+    let roles = Arc::new(RolesConfig::load(&cli.roles)?);
+    let nick_policy = Arc::new(NickPolicy::load(&cli.nick_policy)?);
+
+    let retention = RetentionPolicy::load(&cli.retention)?;
+    let history: Arc<dyn HistoryStore> = if let Some(url) = cli.redis.clone() {
         #[cfg(feature = "redis")]
         {
             let client = redis::Client::open(url)?;
-            let store = chat_core::identities::redis_store::RedisIdentityStore::new(client, "ironchat:identities");
+            let store = chat_core::history::redis_history::RedisHistory::new(client, "ironchat:messages", retention.max_items);
+            match store.migrate_list("ironchat:history").await {
+                Ok(0) => {}
+                Ok(count) => info!(count, "moved history from ironchat:history to ironchat:messages"),
+                Err(err) => warn!(err = %format!("{err:#}"), "migrating ironchat:history failed"),
+            }
             Arc::new(store)
         }
         #[cfg(not(feature = "redis"))]
         {
             let _ = url;
-            warn!("redis feature not enabled, using file identities");
-            Arc::new(FileIdentityStore::new(cli.identities.clone()))
+            Arc::new(InMemoryHistory::new(retention.max_items))
         }
-    } else {
-        Arc::new(FileIdentityStore::new(cli.identities.clone()))
-    };
-
-    let history: Arc<dyn HistoryStore> = if let Some(url) = cli.redis.clone() {
-        #[cfg(feature = "redis")]
+    } else if let Some(path) = &cli.db {
+        #[cfg(feature = "sqlite")]
         {
-            let client = redis::Client::open(url)?;
-            let store = chat_core::history::redis_history::RedisHistory::new(client, "ironchat:history", 100);
-            Arc::new(store)
+            Arc::new(chat_core::sqlite::SqliteHistory::new(SqliteDb::open(path)?, retention.max_items))
         }
-        #[cfg(not(feature = "redis"))]
+        #[cfg(not(feature = "sqlite"))]
         {
-            let _ = url;
-            Arc::new(InMemoryHistory::new(100))
+            let _ = path;
+            Arc::new(InMemoryHistory::new(retention.max_items))
         }
     } else {
-        Arc::new(InMemoryHistory::new(100))
+        Arc::new(InMemoryHistory::new(retention.max_items))
     };
+    if retention.max_age > 0 {
+        tokio::spawn(expire_history(history.clone(), retention.max_age));
+    }
 
+    let shared_rate = open_shared_rate(&cli)?;
     let hub = Arc::new(tokio::sync::Mutex::new(HubState::new(
-        cli.conn_rate,
-        cli.ip_rate,
+        RateConfig {
+            algorithm: cli.rate_algorithm,
+            rate: cli.conn_rate,
+            burst: cli.conn_burst.unwrap_or(cli.conn_rate),
+        },
+        ip_limit(&cli),
     )));
 
+    let offenders = Arc::new(Offenders::new(BanPolicy::load(&cli.ban_policy)?));
+    let spam = Arc::new(SpamFilter::new(SpamPolicy::load(&cli.spam_policy)?));
+    tokio::spawn(watch_access(
+        access.clone(),
+        hub.clone(),
+        roles.clone(),
+        offenders.clone(),
+        spam.clone(),
+    ));
+
+    let ctx = Ctx {
+        hub,
+        access: access.clone(),
+        invites: Arc::new(Invites::new(cli.invites.clone())),
+        ip_keying,
+        conns: ConnLimits::new(cli.max_conns_per_ip, cli.max_clients),
+        accepts: Arc::new(AcceptLimiter::new(
+            (cli.accept_rate, cli.accept_burst),
+            (cli.global_accept_rate, cli.global_accept_burst),
+        )),
+        offenders,
+        costs: Arc::new(CommandCosts::load(&cli.rate_costs)?),
+        shared_rate,
+        spam,
+        slow: Arc::new(SlowMode::new(cli.slow_mode)),
+        history,
+        store_history: retention.store,
+        identities,
+        roles,
+        nick_policy,
+        audit: AuditLog::new(cli.audit_log.clone()),
+        motd: cli.motd.clone(),
+        idle_timeout: cli.idle_timeout,
+    };
+
+    let trusted_proxies = Arc::new(cli.trusted_proxies.clone());
+
     loop {
         let (stream, addr) = listener.accept().await?;
-        let ip = addr.ip();
-        let allow = allow_files.check_or_note(ip)?;
-        if !allow {
-            tokio::spawn(deny_unapproved(stream, acceptor.clone()));
+        if !ctx.accepts.allow_global() {
+            drop(stream);
+            continue;
+        }
+        let peer = canonical_ip(addr.ip());
+        if !trusted_proxies.iter().any(|net| net.contains(&peer)) {
+            dispatch(stream, peer, &acceptor, &ctx);
             continue;
         }
 
         let acceptor = acceptor.clone();
-        let hub = hub.clone();
-        let history = history.clone();
-        let identities = identities.clone();
-        let motd = cli.motd.clone();
-        let idle = cli.idle_timeout;
-
+        let ctx = ctx.clone();
         tokio::spawn(async move {
-            if let Err(err) = handle_client(
-                stream,
-                ip,
-                acceptor,
-                hub,
-                history,
-                identities,
-                motd,
-                idle,
-            )
-            .await
-            {
-                error!(%err, "client error");
+            let mut stream = stream;
+            match tokio::time::timeout(PROXY_HEADER_TIMEOUT, read_proxy_header(&mut stream)).await {
+                Ok(Ok(Some(source))) => dispatch(stream, canonical_ip(source.ip()), &acceptor, &ctx),
+                Ok(Ok(None)) => health_check(stream, peer, acceptor).await,
+                Ok(Err(err)) => warn!(%peer, %err, "bad proxy header"),
+                Err(_) => warn!(%peer, "timed out waiting for proxy header"),
             }
         });
     }
 }
 
-async fn handle_admin(command: &Commands, cli: &Cli) -> Result<()> {
-    let files = AllowlistFiles {
+/// Applies the access rules and connection caps to `ip`, the real client address, and hands the stream off.
+fn dispatch(stream: TcpStream, ip: IpAddr, acceptor: &TlsAcceptor, ctx: &Ctx) {
+    if !ctx.accepts.allow_ip(ctx.ip_keying.key(ip)) {
+        drop(stream);
+        return;
+    }
+    let access = ctx.access.check(ip);
+    if access == Access::Denied {
+        drop(stream);
+        strike(ctx, ip, Offense::Denied);
+        return;
+    }
+    if access == Access::Pending {
+        strike(ctx, ip, Offense::Unapproved);
+    }
+    let acceptor = acceptor.clone();
+    let guard = match ctx.conns.acquire(ctx.ip_keying.key(ip)) {
+        Ok(guard) => guard,
+        Err(refusal) => {
+            warn!(%ip, reason = refusal.code(), "connection refused");
+            tokio::spawn(refuse(stream, acceptor, refusal));
+            return;
+        }
+    };
+
+    let ctx = ctx.clone();
+    tokio::spawn(async move {
+        let _guard = guard;
+        if access == Access::Pending {
+            deny_unapproved(stream, ip, acceptor, ctx).await;
+        } else if let Err(err) = handle_client(stream, ip, acceptor, ctx).await {
+            error!(%err, "client error");
+        }
+    });
+}
+
+/// Counts an offense against the client key of `ip` and applies the ban it earns, if any.
+fn strike(ctx: &Ctx, ip: IpAddr, offense: Offense) {
+    let Some(ban) = ctx.offenders.record(ctx.ip_keying.key(ip), offense, now_ts()) else {
+        return;
+    };
+    let ctx = ctx.clone();
+    tokio::spawn(async move {
+        if let Err(err) = apply_ban(&ctx, ip, ban).await {
+            warn!(%ip, %err, "failed to apply automatic ban");
+        }
+    });
+}
+
+/// Adds a temporary denylist entry for the client key of `ip` and drops its live connections.
+async fn apply_ban(ctx: &Ctx, ip: IpAddr, ban: Ban) -> Result<()> {
+    let entry = ctx.ip_keying.entry(ip);
+    let reason = format!("auto-ban #{}: {}", ban.count, ban.offense);
+    let (deny, until) = (entry.clone(), now_ts() + ban.duration);
+    let extended = access_io(&ctx.access, {
+        let reason = reason.clone();
+        move |access| {
+            if !access.files().extend_deny(&deny, reason, until)? {
+                return Ok(false);
+            }
+            if let Err(err) = access.reload() {
+                warn!(%err, "access reload failed after automatic ban");
+            }
+            Ok(true)
+        }
+    })
+    .await?;
+    if !extended {
+        return Ok(());
+    }
+    ctx.audit.record("chatd", "ban.auto", &format!("{entry} {}s {reason}", ban.duration))?;
+    warn!(%entry, secs = ban.duration, count = ban.count, offense = %ban.offense, "automatic ban");
+
+    let key = ctx.ip_keying.key(ip);
+    let banned: Vec<ClientId> = {
+        let state = ctx.hub.lock().await;
+        state
+            .clients
+            .iter()
+            .filter(|(_, c)| c.ip == key)
+            .map(|(id, c)| {
+                let _ = c.tx.try_send(ServerMsg::Sys {
+                    text: format!("You are banned for {}s ({})", ban.duration, ban.offense),
+                });
+                *id
+            })
+            .collect()
+    };
+    for id in banned {
+        disconnect_client(&ctx.hub, id, "banned").await;
+    }
+    Ok(())
+}
+
+/// Answers a connection the proxy opened itself (a LOCAL or UNKNOWN header), such as a health
+/// check. It has no client address, so it bypasses the access rules, strikes and connection caps
+/// that would otherwise hit the proxy's own address, and is closed after the TLS handshake.
+async fn health_check(stream: TcpStream, peer: IpAddr, acceptor: TlsAcceptor) {
+    let _ = tokio::time::timeout(REFUSAL_TIMEOUT, async {
+        let mut tls = acceptor.accept(stream).await?;
+        tls.shutdown().await
+    })
+    .await;
+    debug!(%peer, "proxy health check");
+}
+
+/// Completes the handshake only to tell the client why it is being turned away.
+async fn refuse(stream: TcpStream, acceptor: TlsAcceptor, refusal: ConnRefusal) {
+    let _ = tokio::time::timeout(REFUSAL_TIMEOUT, async {
+        let mut tls = acceptor.accept(stream).await?;
+        let msg = ServerMsg::Error {
+            code: refusal.code().into(),
+            text: refusal.to_string(),
+        };
+        tls.write_all(format!("{}\n", format_server_msg(&msg)).as_bytes()).await?;
+        tls.shutdown().await
+    })
+    .await;
+}
+
+fn parse_proxy_net(raw: &str) -> Result<IpNet, String> {
+    parse_net(raw).ok_or_else(|| format!("invalid ip or cidr {raw:?}"))
+}
+
+fn parse_ttl(raw: &str) -> Result<Duration, String> {
+    match parse_duration(raw) {
+        Ok(ttl) if ttl.is_zero() => Err("must be longer than 0s (leave it out to keep identities forever)".into()),
+        Ok(ttl) => Ok(ttl),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn parse_db(raw: &str) -> Result<PathBuf, String> {
+    match raw.strip_prefix("sqlite:") {
+        Some(path) if !path.is_empty() => Ok(PathBuf::from(path)),
+        _ => Err(format!("expected sqlite:PATH, got {raw:?}")),
+    }
+}
+
+fn ip_limit(cli: &Cli) -> RateConfig {
+    RateConfig {
+        algorithm: cli.rate_algorithm,
+        rate: cli.ip_rate,
+        burst: cli.ip_burst.unwrap_or(cli.ip_rate),
+    }
+}
+
+fn open_shared_rate(cli: &Cli) -> Result<Option<Arc<dyn SharedLimiter>>> {
+    if cli.rate_backend == RateBackend::Local {
+        return Ok(None);
+    }
+    let Some(url) = cli.redis.clone() else {
+        anyhow::bail!("--rate-backend redis needs --redis");
+    };
+    #[cfg(feature = "redis")]
+    {
+        let client = redis::Client::open(url)?;
+        let limiter = chat_core::rate::redis_rate::RedisRateLimiter::new(client, "ironchat:rate", ip_limit(cli));
+        Ok(Some(Arc::new(limiter)))
+    }
+    #[cfg(not(feature = "redis"))]
+    {
+        let _ = url;
+        warn!("redis feature not enabled, using local rate limits");
+        Ok(None)
+    }
+}
+
+fn open_identities(cli: &Cli) -> Result<Arc<dyn IdentityStore>> {
+    let identities: Arc<dyn IdentityStore> = if let Some(url) = cli.redis.clone() {
+        #[cfg(feature = "redis")]
+        {
+            let client = redis::Client::open(url)?;
+            let store = chat_core::identities::redis_store::RedisIdentityStore::new(client, "ironchat:identities");
+            Arc::new(store)
+        }
+        #[cfg(not(feature = "redis"))]
+        {
+            let _ = url;
+            warn!("redis feature not enabled, using file identities");
+            Arc::new(FileIdentityStore::new(cli.identities.clone()))
+        }
+    } else if let Some(path) = &cli.db {
+        #[cfg(feature = "sqlite")]
+        {
+            Arc::new(chat_core::sqlite::SqliteIdentityStore::new(SqliteDb::open(path)?))
+        }
+        #[cfg(not(feature = "sqlite"))]
+        {
+            let _ = path;
+            warn!("sqlite feature not enabled, using file identities");
+            Arc::new(FileIdentityStore::new(cli.identities.clone()))
+        }
+    } else {
+        Arc::new(FileIdentityStore::new(cli.identities.clone()))
+    };
+    Ok(identities)
+}
+
+fn allowlist_files(cli: &Cli) -> Result<AllowlistFiles> {
+    let store = match &cli.db {
+        #[cfg(feature = "sqlite")]
+        Some(path) => ListStore::Sqlite(SqliteDb::open(path)?),
+        #[cfg(not(feature = "sqlite"))]
+        Some(_) => {
+            warn!("sqlite feature not enabled, using list files");
+            ListStore::Files
+        }
+        None => ListStore::Files,
+    };
+    Ok(access_lists(cli, store))
+}
+
+fn access_lists(cli: &Cli, store: ListStore) -> AllowlistFiles {
+    AllowlistFiles {
         allowlist: cli.allowlist.clone(),
         pending: cli.pending.clone(),
+        denylist: cli.denylist.clone(),
+        store,
+    }
+}
+
+/// The access lists and, unless identities live in Redis, the identity store that `check` reads.
+/// A `--db` database is opened read-only, so checking never creates or migrates it.
+fn check_stores(cli: &Cli) -> Result<(AllowlistFiles, Option<Arc<dyn IdentityStore>>)> {
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = &cli.db {
+        let db = SqliteDb::open_read_only(path)?;
+        let identities: Option<Arc<dyn IdentityStore>> = match cli.redis {
+            Some(_) => None,
+            None => Some(Arc::new(chat_core::sqlite::SqliteIdentityStore::new(db.clone()))),
+        };
+        return Ok((access_lists(cli, ListStore::Sqlite(db)), identities));
+    }
+    let identities = match cli.redis {
+        Some(_) => None,
+        None => Some(open_identities(cli)?),
+    };
+    Ok((allowlist_files(cli)?, identities))
+}
+
+/// Runs access-list I/O on the blocking pool; with `--db` the lists live in SQLite.
+async fn access_io<T, F>(access: &Arc<AccessCache>, f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&AccessCache) -> Result<T> + Send + 'static,
+{
+    let access = Arc::clone(access);
+    tokio::task::spawn_blocking(move || f(&access)).await?
+}
+
+async fn watch_access(
+    access: Arc<AccessCache>,
+    hub: Arc<tokio::sync::Mutex<HubState>>,
+    roles: Arc<RolesConfig>,
+    offenders: Arc<Offenders>,
+    spam: Arc<SpamFilter>,
+) {
+    let mut poll = tokio::time::interval(ACCESS_POLL_INTERVAL);
+    let mut flush = tokio::time::interval(PENDING_FLUSH_INTERVAL);
+    let mut hangup = hangup_signal();
+    loop {
+        tokio::select! {
+            _ = poll.tick() => {
+                if let Err(err) = access_io(&access, |access| access.reload_if_changed()).await {
+                    warn!(%err, "access reload failed, keeping previous rules");
+                }
+            }
+            _ = recv_hangup(&mut hangup) => {
+                info!("SIGHUP received, reloading access rules");
+                if let Err(err) = access_io(&access, |access| access.reload()).await {
+                    warn!(%err, "access reload failed, keeping previous rules");
+                }
+            }
+            _ = flush.tick() => {
+                offenders.prune(now_ts());
+                spam.prune(now_ts());
+                match access_io(&access, |access| access.flush_pending()).await {
+                    Ok(fresh) if !fresh.is_empty() => {
+                        let state = hub.lock().await;
+                        for (ip, entry) in fresh {
+                            let msg = ServerMsg::Pending { ip: ip.to_string(), attempts: entry.attempts };
+                            state.broadcast_where(&msg, |c| roles.allows(c.role, Permission::Approve));
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(err) => warn!(%err, "pending flush failed"),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+type Hangup = Option<tokio::signal::unix::Signal>;
+#[cfg(not(unix))]
+type Hangup = Option<()>;
+
+#[cfg(unix)]
+fn hangup_signal() -> Hangup {
+    use tokio::signal::unix::{signal, SignalKind};
+    signal(SignalKind::hangup()).ok()
+}
+
+#[cfg(not(unix))]
+fn hangup_signal() -> Hangup {
+    None
+}
+
+async fn recv_hangup(hangup: &mut Hangup) {
+    #[cfg(unix)]
+    if let Some(sig) = hangup {
+        sig.recv().await;
+        return;
+    }
+    let _ = hangup;
+    std::future::pending::<()>().await
+}
+
+async fn flush_identities(identities: Arc<dyn IdentityStore>) {
+    let mut tick = tokio::time::interval(IDENTITY_FLUSH_INTERVAL);
+    loop {
+        tick.tick().await;
+        if let Err(err) = identities.flush().await {
+            warn!(%err, "identity flush failed");
+        }
+    }
+}
+
+async fn sweep_identities(identities: Arc<dyn IdentityStore>, ttl: Duration) {
+    let mut tick = tokio::time::interval(IDENTITY_SWEEP_INTERVAL.min(ttl));
+    loop {
+        tick.tick().await;
+        let cutoff = now_ts().saturating_sub(ttl.as_secs());
+        match identities.prune(cutoff).await {
+            Ok(pruned) => {
+                for (ip, rec) in pruned {
+                    info!(%ip, nick = %rec.nick, "identity expired");
+                }
+            }
+            Err(err) => warn!(%err, "identity sweep failed"),
+        }
+    }
+}
+
+async fn expire_history(history: Arc<dyn HistoryStore>, max_age: u64) {
+    let mut tick = tokio::time::interval(HISTORY_EXPIRY_INTERVAL);
+    loop {
+        tick.tick().await;
+        match history.purge(0, now_ts().saturating_sub(max_age)).await {
+            Ok(0) => {}
+            Ok(expired) => info!(expired, "history expired"),
+            Err(err) => warn!(%err, "history expiry failed"),
+        }
+    }
+}
+
+/// Loads each state file the way the server would and reports every failure.
+async fn check_state(cli: &Cli) -> Result<()> {
+    let (mut results, identities): (Vec<(String, Result<()>)>, _) = match check_stores(cli) {
+        Ok((files, identities)) => {
+            let [allow, pending, deny] = files.sources();
+            let results = vec![
+                (allow, files.load_allow().and_then(|list| list.to_nets().map(drop))),
+                (pending, files.load_pending().map(drop)),
+                (deny, files.load_deny().and_then(|list| list.to_nets().map(drop))),
+            ];
+            (results, identities)
+        }
+        Err(err) => {
+            let what = match &cli.db {
+                Some(path) => path.display().to_string(),
+                None => "access lists".to_string(),
+            };
+            (vec![(what, Err(err))], None)
+        }
     };
+    results.extend([
+        (
+            cli.roles.display().to_string(),
+            RolesConfig::load(&cli.roles).map(drop),
+        ),
+        (
+            cli.nick_policy.display().to_string(),
+            NickPolicy::load(&cli.nick_policy).map(drop),
+        ),
+        (
+            cli.invites.display().to_string(),
+            InviteList::load(&cli.invites).map(drop),
+        ),
+        (
+            cli.rate_costs.display().to_string(),
+            CommandCosts::load(&cli.rate_costs).map(drop),
+        ),
+        (
+            cli.ban_policy.display().to_string(),
+            BanPolicy::load(&cli.ban_policy).map(drop),
+        ),
+        (
+            cli.spam_policy.display().to_string(),
+            SpamPolicy::load(&cli.spam_policy).map(drop),
+        ),
+        (
+            cli.retention.display().to_string(),
+            RetentionPolicy::load(&cli.retention).map(drop),
+        ),
+        (
+            format!("ipv6 prefix {}", cli.ipv6_prefix),
+            IpKeying::new(cli.ipv6_prefix).map(drop),
+        ),
+        (
+            format!("rate backend {}", cli.rate_backend),
+            open_shared_rate(cli).map(drop),
+        ),
+    ]);
+    if let Some(store) = identities {
+        let what = match &cli.db {
+            Some(path) => format!("{} identities", path.display()),
+            None => cli.identities.display().to_string(),
+        };
+        results.push((what, store.list().await.map(drop)));
+    }
+    if let (Some(cert), Some(key)) = (&cli.cert, &cli.key) {
+        results.push((
+            format!("{} + {}", cert.display(), key.display()),
+            tls::load_server_config(cert, key).map(drop),
+        ));
+    }
+
+    let mut failed = 0;
+    for (what, result) in results {
+        match result {
+            Ok(()) => println!("ok {what}"),
+            Err(err) => {
+                failed += 1;
+                println!("error {what}: {err:#}");
+            }
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!("{failed} check(s) failed");
+    }
+    Ok(())
+}
+
+async fn handle_admin(command: &Commands, cli: &Cli) -> Result<()> {
+    // Opened per command, so `check` runs before anything creates or migrates the database.
+    let files = || allowlist_files(cli);
+    let audit = AuditLog::new(cli.audit_log.clone());
     match command {
         Commands::Allow { command } => match command {
-            AllowCommands::Add { entry } => {
-                files.add_allow(&entry)?;
+            AllowCommands::Add {
+                entry,
+                label,
+                owner,
+                expires,
+            } => {
+                let owner = owner
+                    .clone()
+                    .or_else(|| std::env::var("USER").ok())
+                    .unwrap_or_else(|| "cli".into());
+                let expires_at = expires.map(|d| now_ts() + d.as_secs());
+                files()?.add_allow(entry, label.clone(), Some(owner.clone()), expires_at)?;
+                audit.record(
+                    "cli",
+                    "allow.add",
+                    &format!("{entry} {owner} {}", label.as_deref().unwrap_or("")),
+                )?;
                 println!("added {entry}");
             }
             AllowCommands::Remove { entry } => {
-                files.remove_allow(&entry)?;
-                println!("removed {entry}");
+                if files()?.remove_allow(entry)? {
+                    audit.record("cli", "allow.remove", entry)?;
+                    println!("removed {entry}");
+                } else {
+                    println!("{entry} not in allowlist");
+                }
             }
             AllowCommands::List => {
-                for entry in files.list_allow()? {
-                    println!("{entry}");
+                let now = now_ts();
+                for entry in files()?.list_allow()? {
+                    let expires = match entry.expires {
+                        Some(ts) if ts <= now => "expired".to_string(),
+                        Some(ts) => ts.to_string(),
+                        None => "never".to_string(),
+                    };
+                    println!(
+                        "{} label={} owner={} added={} expires={}",
+                        entry.entry,
+                        entry.label.as_deref().unwrap_or("-"),
+                        entry.owner.as_deref().unwrap_or("-"),
+                        entry.added,
+                        expires
+                    );
                 }
             }
         },
         Commands::Pending { command } => match command {
             PendingCommands::List => {
-                for (ip, entry) in files.list_pending()? {
-                    println!("{ip} attempts={} last_seen={}", entry.attempts, entry.last_seen);
+                for (ip, entry) in files()?.list_pending()? {
+                    match entry.knock {
+                        Some(knock) => println!(
+                            "{ip} attempts={} last_seen={} ref={} name={:?} reason={:?}",
+                            entry.attempts, entry.last_seen, knock.code, knock.name, knock.reason
+                        ),
+                        None => println!("{ip} attempts={} last_seen={}", entry.attempts, entry.last_seen),
+                    }
                 }
             }
             PendingCommands::Remove { ip } => {
-                files.remove_pending(&ip)?;
+                files()?.remove_pending(ip)?;
                 println!("removed {ip}");
             }
             PendingCommands::Clear => {
-                files.clear_pending()?;
+                files()?.clear_pending()?;
                 println!("cleared pending list");
             }
         },
+        Commands::Deny { command } => {
+            match command {
+                DenyCommands::Add {
+                    entry,
+                    reason,
+                    expires,
+                } => {
+                    let expires_at = expires.map(|d| now_ts() + d.as_secs());
+                    files()?.add_deny(entry, reason.clone(), expires_at)?;
+                    audit.record(
+                        "cli",
+                        "deny.add",
+                        &format!("{entry} {}", reason.as_deref().unwrap_or("")),
+                    )?;
+                    println!("denied {entry}");
+                }
+                DenyCommands::Remove { entry } => {
+                    if files()?.remove_deny(entry)? {
+                        audit.record("cli", "deny.remove", entry)?;
+                        println!("removed {entry}");
+                    } else {
+                        println!("{entry} not in denylist");
+                    }
+                }
+                DenyCommands::List => {
+                    let now = now_ts();
+                    for entry in files()?.list_deny()? {
+                        let expires = match entry.expires {
+                            Some(ts) if ts <= now => "expired".to_string(),
+                            Some(ts) => ts.to_string(),
+                            None => "never".to_string(),
+                        };
+                        println!(
+                            "{} expires={} reason={}",
+                            entry.entry,
+                            expires,
+                            entry.reason.as_deref().unwrap_or("-")
+                        );
+                    }
+                }
+            }
+        }
+        Commands::Role { command } => {
+            let identities = open_identities(cli)?;
+            let keying = IpKeying::new(cli.ipv6_prefix)?;
+            match command {
+                RoleCommands::Set { ip, role } => {
+                    let ip = &keying.key(*ip);
+                    identities.set_role(*ip, Some(*role)).await?;
+                    audit.record("cli", "role.set", &format!("{ip} {role}"))?;
+                    println!("{ip} is now {role}");
+                }
+                RoleCommands::Clear { ip } => {
+                    let ip = &keying.key(*ip);
+                    identities.set_role(*ip, None).await?;
+                    audit.record("cli", "role.clear", &ip.to_string())?;
+                    println!("cleared role for {ip}");
+                }
+                RoleCommands::List => {
+                    let roles = RolesConfig::load(&cli.roles)?;
+                    for (ip, rec) in identities.list().await? {
+                        println!("{ip} {} {}", rec.nick, roles.effective(rec.role));
+                    }
+                }
+            }
+        }
+        Commands::Identities { command } => {
+            let identities = open_identities(cli)?;
+            let roles = RolesConfig::load(&cli.roles)?;
+            let keying = IpKeying::new(cli.ipv6_prefix)?;
+            match command {
+                IdentityCommands::List => {
+                    for (ip, rec) in identities.list().await? {
+                        println!(
+                            "{ip} nick={} role={} updated={}",
+                            rec.nick,
+                            roles.effective(rec.role),
+                            rec.updated
+                        );
+                    }
+                }
+                IdentityCommands::Show { ip } => {
+                    let ip = &keying.key(*ip);
+                    let rec = identities
+                        .get(*ip)
+                        .await?
+                        .with_context(|| format!("no identity for {ip}"))?;
+                    println!("ip: {ip}");
+                    println!("nick: {}", rec.nick);
+                    match rec.role {
+                        Some(role) => println!("role: {role}"),
+                        None => println!("role: {} (default)", roles.default_role),
+                    }
+                    println!("updated: {}", rec.updated);
+                }
+                IdentityCommands::Remove { ip } => {
+                    let ip = &keying.key(*ip);
+                    identities.remove(*ip).await?;
+                    audit.record("cli", "identity.remove", &ip.to_string())?;
+                    println!("removed {ip}");
+                }
+                IdentityCommands::Rename { ip, nick, to } => {
+                    let ip = &keying.key(*ip);
+                    let to = to.map(|to| keying.key(to));
+                    if nick.is_none() && to.is_none() {
+                        anyhow::bail!("nothing to change, pass --nick and/or --to");
+                    }
+                    let mut rec = identities
+                        .get(*ip)
+                        .await?
+                        .with_context(|| format!("no identity for {ip}"))?;
+                    let old_nick = rec.nick.clone();
+                    if let Some(new) = nick {
+                        let new = NickPolicy::load(&cli.nick_policy)?.validate(new)?;
+                        if let Some(other) = identities.nick_holder(&new, *ip).await? {
+                            anyhow::bail!("nickname {new} is held by {other}");
+                        }
+                        rec.nick = new;
+                        rec.updated = now_ts();
+                    }
+                    let dest = to.unwrap_or(*ip);
+                    if dest != *ip {
+                        if identities.get(dest).await?.is_some() {
+                            anyhow::bail!("{dest} already has an identity");
+                        }
+                        identities.remove(*ip).await?;
+                    }
+                    let detail = format!("{ip} {old_nick} -> {dest} {}", rec.nick);
+                    identities.put(dest, rec).await?;
+                    audit.record("cli", "identity.rename", &detail)?;
+                    println!("{detail}");
+                }
+                IdentityCommands::Import { file, force } => {
+                    let raw = std::fs::read_to_string(file)
+                        .with_context(|| format!("read {}", file.display()))?;
+                    let mut records = import_toml(&raw)?;
+                    let count = records.len();
+                    let policy = NickPolicy::load(&cli.nick_policy)?;
+                    let mut problems = Vec::new();
+                    let mut seen: HashMap<String, IpAddr> = HashMap::new();
+                    for (ip, rec) in &mut records {
+                        match policy.validate(&rec.nick) {
+                            Ok(nick) => rec.nick = nick,
+                            Err(err) => problems.push(format!("{ip} {}: {err}", rec.nick)),
+                        }
+                        let holder = match seen.get(&nick_key(&rec.nick)) {
+                            Some(other) => Some(*other),
+                            None => identities.nick_holder(&rec.nick, *ip).await?,
+                        };
+                        if let Some(other) = holder {
+                            problems.push(format!("{ip} {}: looks like the nickname held by {other}", rec.nick));
+                        }
+                        seen.insert(nick_key(&rec.nick), *ip);
+                    }
+                    if !problems.is_empty() {
+                        if !*force {
+                            anyhow::bail!(
+                                "refusing to import {}:\n  {}\nfix the file or pass --force",
+                                file.display(),
+                                problems.join("\n  ")
+                            );
+                        }
+                        for problem in &problems {
+                            eprintln!("warning: {problem}");
+                        }
+                    }
+                    for (ip, rec) in records {
+                        identities.put(ip, rec).await?;
+                    }
+                    audit.record("cli", "identity.import", &format!("{count} from {}", file.display()))?;
+                    println!("imported {count} identities");
+                }
+                IdentityCommands::Export { file } => {
+                    let data = export_toml(&identities.list().await?)?;
+                    match file {
+                        Some(path) => {
+                            std::fs::write(path, data)
+                                .with_context(|| format!("write {}", path.display()))?;
+                        }
+                        None => print!("{data}"),
+                    }
+                }
+                IdentityCommands::Prune { older_than } => {
+                    let cutoff = now_ts().saturating_sub(older_than.as_secs());
+                    let pruned = identities.prune(cutoff).await?;
+                    for (ip, rec) in &pruned {
+                        audit.record("cli", "identity.prune", &format!("{ip} {}", rec.nick))?;
+                        println!("pruned {ip} {}", rec.nick);
+                    }
+                    println!("pruned {} identities", pruned.len());
+                }
+            }
+        }
+        Commands::Check => check_state(cli).await?,
+        Commands::Invite { command } => {
+            let invites = Invites::new(cli.invites.clone());
+            match command {
+                InviteCommands::Create { uses, expires, note } => {
+                    let owner = std::env::var("USER").unwrap_or_else(|_| "cli".into());
+                    let expires_at = expires.map(|d| now_ts() + d.as_secs());
+                    let invite = invites.create(*uses, expires_at, &owner, note.clone())?;
+                    audit.record("cli", "invite.create", &format!("{} uses={}", invite.code, invite.uses))?;
+                    println!("{}", invite.code);
+                }
+                InviteCommands::Revoke { code } => {
+                    if invites.revoke(code)? {
+                        audit.record("cli", "invite.revoke", code)?;
+                        println!("revoked {code}");
+                    } else {
+                        println!("no invite {code}");
+                    }
+                }
+                InviteCommands::List => {
+                    let now = now_ts();
+                    for invite in invites.list()? {
+                        let expires = invite
+                            .expires
+                            .map(|ts| ts.to_string())
+                            .unwrap_or_else(|| "never".into());
+                        println!(
+                            "{} status={} used={}/{} expires={} by={} note={}",
+                            invite.code,
+                            invite.unusable(now).unwrap_or("active"),
+                            invite.redemptions.len(),
+                            invite.uses,
+                            expires,
+                            invite.created_by,
+                            invite.note.as_deref().unwrap_or("-")
+                        );
+                        for redemption in &invite.redemptions {
+                            println!("  redeemed by {} at {}", redemption.ip, redemption.at);
+                        }
+                    }
+                }
+            }
+        }
     }
     Ok(())
 }
 
-async fn deny_unapproved(stream: TcpStream, acceptor: TlsAcceptor) {
-    if let Ok(mut tls) = acceptor.accept(stream).await {
-        let _ = tls
-            .write_all(format_server_msg(&ServerMsg::Sys {
-                text: "Not approved. Ask admin.".into(),
-            })
-            .as_bytes())
-            .await;
-        let _ = tls.write_all(b"\n").await;
+type ServerLines = tokio::io::Lines<BufReader<tokio::io::ReadHalf<tokio_rustls::server::TlsStream<TcpStream>>>>;
+type ServerWriter = tokio::io::WriteHalf<tokio_rustls::server::TlsStream<TcpStream>>;
+
+async fn deny_unapproved(stream: TcpStream, ip: IpAddr, acceptor: TlsAcceptor, ctx: Ctx) {
+    let Ok(tls) = acceptor.accept(stream).await else {
+        return;
+    };
+    let (reader, mut writer) = tokio::io::split(tls);
+    let mut lines = BufReader::new(reader).lines();
+    let result = async {
+        write_frame(&mut writer, ServerMsg::Sys { text: "Not approved. Ask admin.".into() }).await?;
+        if redeem_invite(&mut lines, &mut writer, ip, &ctx).await? {
+            return Ok(());
+        }
+        knock(&mut lines, &mut writer, ip, ctx.ip_keying.key(ip), &ctx.access).await
+    }
+    .await;
+    if let Err(err) = result {
+        warn!(%ip, %err, "unapproved client");
     }
 }
 
-async fn handle_client(
-    stream: TcpStream,
+/// Sends a prompt and waits for the answer; `None` on timeout, disconnect or `-`.
+async fn ask(lines: &mut ServerLines, writer: &mut ServerWriter, id: &str, text: &str) -> Result<Option<String>> {
+    write_frame(writer, ServerMsg::Prompt { id: id.into(), text: text.into() }).await?;
+    let answer = match tokio::time::timeout(KNOCK_TIMEOUT, read_prompt(lines, id)).await {
+        Ok(answer) => answer?,
+        Err(_) => None,
+    };
+    Ok(answer.filter(|a| a.trim() != "-"))
+}
+
+/// Offers to redeem an invite code; returns true once the IP has been allowlisted.
+async fn redeem_invite(lines: &mut ServerLines, writer: &mut ServerWriter, ip: IpAddr, ctx: &Ctx) -> Result<bool> {
+    let key = ctx.ip_keying.key(ip);
+    if ctx.invites.locked_out(key) {
+        return Ok(false);
+    }
+    let Some(code) = ask(lines, writer, "invite", "Invite code (- if you have none):").await? else {
+        return Ok(false);
+    };
+    // The whole client key is allowlisted, so an IPv6 client keeps access when its address
+    // changes within the prefix. The use is handed back if the allowlist cannot be written.
+    let (entry, net) = (ctx.ip_keying.entry(ip), ctx.ip_keying.net(ip));
+    let invites = Arc::clone(&ctx.invites);
+    let redeemed = access_io(&ctx.access, {
+        let entry = entry.clone();
+        move |access| {
+            let invite = match invites.redeem(&code, ip, key) {
+                Ok(invite) => invite,
+                Err(err) => return Ok(Err(err)),
+            };
+            let label = format!("invite {}", invite.code);
+            if let Err(err) = access.files().add_allow(&entry, Some(label), Some(invite.created_by.clone()), None) {
+                if let Err(undo) = invites.unredeem(&invite.code, ip) {
+                    warn!(%ip, code = %invite.code, err = %undo, "could not hand back invite use");
+                }
+                return Err(err);
+            }
+            if let Err(err) = access.drop_pending_net(net) {
+                warn!(%ip, %err, "could not drop pending entry after invite");
+            }
+            if let Err(err) = access.reload() {
+                warn!(%err, "access reload failed after invite");
+            }
+            Ok(Ok(invite))
+        }
+    })
+    .await?;
+    let invite = match redeemed {
+        Ok(invite) => invite,
+        Err(err) => {
+            warn!(%ip, %err, "invite redemption failed");
+            write_frame(writer, ServerMsg::Sys { text: "Invalid or expired invite code.".into() }).await?;
+            return Ok(false);
+        }
+    };
+    ctx.audit.record(&ip.to_string(), "invite.redeem", &format!("{} {entry}", invite.code))?;
+    info!(%ip, code = %invite.code, "invite redeemed");
+    write_frame(writer, ServerMsg::Sys { text: "Invite accepted. Reconnect to join.".into() }).await?;
+    writer.shutdown().await?;
+    Ok(true)
+}
+
+/// Lets an unapproved client leave its name and a reason for the admins.
+async fn knock(
+    lines: &mut ServerLines,
+    writer: &mut ServerWriter,
     ip: IpAddr,
-    acceptor: TlsAcceptor,
-    hub: Arc<tokio::sync::Mutex<HubState>>,
-    history: Arc<dyn HistoryStore>,
-    identities: Arc<dyn IdentityStore>,
-    motd: Option<String>,
-    idle_timeout: Option<u64>,
+    key: IpAddr,
+    access: &AccessCache,
 ) -> Result<()> {
+    if let Some(code) = access.recent_knock(key) {
+        let text = format!("Request already noted. Reference: {code}");
+        return write_frame(writer, ServerMsg::Sys { text }).await;
+    }
+
+    let Some(name) = ask(lines, writer, "knock_name", "To request access, enter your name (- to skip):").await? else {
+        return Ok(());
+    };
+    let Some(reason) = ask(lines, writer, "knock_reason", "Reason for access:").await? else {
+        return Ok(());
+    };
+
+    let code = access.knock(ip, key, &name, &reason);
+    write_frame(writer, ServerMsg::Sys { text: format!("Request noted. Reference: {code}") }).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+async fn write_frame(writer: &mut ServerWriter, msg: ServerMsg) -> Result<()> {
+    writer.write_all(format_server_msg(&msg).as_bytes()).await?;
+    writer.write_all(b"\n").await?;
+    Ok(())
+}
+
+async fn handle_client(stream: TcpStream, ip: IpAddr, acceptor: TlsAcceptor, ctx: Ctx) -> Result<()> {
+    let Ctx {
+        hub,
+        access,
+        invites: _,
+        ip_keying,
+        conns,
+        accepts,
+        offenders,
+        costs,
+        shared_rate,
+        spam,
+        slow,
+        history,
+        store_history,
+        identities,
+        roles,
+        nick_policy,
+        audit,
+        motd,
+        idle_timeout,
+    } = ctx.clone();
+    // Identities and rate limits are tracked per key; `ip` is kept for logging.
+    let key = ip_keying.key(ip);
     let tls = acceptor.accept(stream).await?;
     let (reader, mut writer) = tokio::io::split(tls);
     let mut lines = BufReader::new(reader).lines();
 
     let (tx, mut rx) = mpsc::channel::<ServerMsg>(64);
 
-    let writer_task = tokio::spawn(async move {
+    let mut writer_task = tokio::spawn(async move {
         while let Some(msg) = rx.recv().await {
             let line = format_server_msg(&msg);
             if writer.write_all(line.as_bytes()).await.is_err() {
@@ -268,21 +1387,18 @@ async fn handle_client(
         let _ = tx.send(ServerMsg::Sys { text: m }).await;
     }
 
-    let mut nick = init_identity(&tx, &mut lines, ip, &hub, identities.clone()).await?;
+    let mut nick = init_identity(&tx, &mut lines, key, &hub, identities.clone(), &nick_policy).await?;
+    identities.touch(key).await?;
+    let role = roles.effective(identities.get(key).await?.and_then(|rec| rec.role));
+    let shutdown = Arc::new(Notify::new());
 
     let mut state = hub.lock().await;
-    let client_id = state.add_client(nick.clone(), ip, tx.clone());
+    let client_id = state.add_client(nick.clone(), key, role, tx.clone(), shutdown.clone());
     drop(state);
-    info!(%ip, nick = %nick, "client joined");
+    info!(%ip, nick = %nick, %role, "client joined");
 
-    let hist = history.list().await?;
-    for item in hist {
-        let _ = tx
-            .send(ServerMsg::Hist {
-                nick: item.nick,
-                text: item.text,
-            })
-            .await;
+    if let Err(err) = send_history(&tx, history.as_ref(), HistoryQuery::latest(HISTORY_PAGE), true).await {
+        report_failure(&tx, "loading history", &err).await;
     }
 
     broadcast_sys(&hub, &format!("{nick} joined"));
@@ -290,10 +1406,16 @@ async fn handle_client(
     let idle_duration = idle_timeout.map(Duration::from_secs);
 
     loop {
-        let next_line = if let Some(idle) = idle_duration {
-            tokio::time::timeout(idle, lines.next_line()).await
-        } else {
-            Ok(lines.next_line().await)
+        let read = async {
+            if let Some(idle) = idle_duration {
+                tokio::time::timeout(idle, lines.next_line()).await
+            } else {
+                Ok(lines.next_line().await)
+            }
+        };
+        let next_line = tokio::select! {
+            _ = shutdown.notified() => break,
+            next = read => next,
         };
 
         let line = match next_line {
@@ -314,14 +1436,29 @@ async fn handle_client(
         let msg = match parse_client_line(&clean) {
             Ok(m) => m,
             Err(_) => {
+                strike(&ctx, ip, Offense::InvalidCommand);
                 let _ = tx.send(ServerMsg::Sys { text: "invalid command".into() }).await;
                 continue;
             }
         };
 
+        let cost = costs.cost(&msg);
+        let shared_ip_ok = match &shared_rate {
+            Some(shared) if cost > 0 => match shared.check_cost(key, cost).await {
+                Ok(ok) => Some(ok),
+                Err(err) => {
+                    warn!(%err, "shared rate check failed, using the local limit");
+                    None
+                }
+            },
+            _ => None,
+        };
         let mut state = hub.lock().await;
-        let conn_ok = state.conn_rate_ok(client_id);
-        let ip_ok = state.ip_rate_ok(ip);
+        let conn_ok = state.conn_rate_ok(client_id, cost);
+        let ip_ok = match shared_ip_ok {
+            Some(ok) => state.note_ip_rate(key, ok),
+            None => state.ip_rate_ok(key, cost),
+        };
         if !conn_ok || !ip_ok {
             let mut should_disconnect = false;
             if !conn_ok {
@@ -332,15 +1469,16 @@ async fn handle_client(
                 }
             }
             if !ip_ok {
-                if state.ip_warned(ip) {
+                if state.ip_warned(key) {
                     should_disconnect = true;
                 } else {
-                    state.mark_ip_warned(ip);
+                    state.mark_ip_warned(key);
                 }
             }
             drop(state);
             if should_disconnect {
                 warn!(%ip, nick = %nick, "rate limit disconnect");
+                strike(&ctx, ip, Offense::RateLimit);
                 break;
             }
             let _ = tx
@@ -350,19 +1488,49 @@ async fn handle_client(
                 .await;
             continue;
         }
+        let role = state.role_of(client_id).unwrap_or_default();
         drop(state);
 
+        if let Some(perm) = required_permission(&msg) {
+            if !roles.allows(role, perm) {
+                let _ = tx
+                    .send(ServerMsg::Sys {
+                        text: "permission denied".into(),
+                    })
+                    .await;
+                continue;
+            }
+        }
+
         match msg {
-            ClientMsg::Nick { nick: new } => {
-                if new.len() > MAX_NICK {
+            ClientMsg::Nick { nick: new } => match nick_policy.validate(&new) {
+                Err(err) => {
                     let _ = tx
                         .send(ServerMsg::Sys {
-                            text: "nickname too long".into(),
+                            text: err.to_string(),
                         })
                         .await;
-                } else {
+                }
+                Ok(new) => {
                     let mut state = hub.lock().await;
-                    let taken = state.nicks.contains(&new.to_lowercase());
+                    if let Some(left) = state.nick_cooldown_left(key, costs.nick_cooldown()) {
+                        drop(state);
+                        let text = format!("wait {}s before changing your nickname again", left.as_secs().max(1));
+                        let _ = tx.send(ServerMsg::Sys { text }).await;
+                        continue;
+                    }
+                    let mut taken = state.nick_taken(&new) && nick_key(&new) != nick_key(&nick);
+                    if !taken {
+                        drop(state);
+                        match identities.nick_holder(&new, key).await {
+                            Ok(holder) => taken = holder.is_some(),
+                            Err(err) => {
+                                report_failure(&tx, "nickname check", &err).await;
+                                continue;
+                            }
+                        }
+                        state = hub.lock().await;
+                    }
                     if taken {
                         drop(state);
                         let _ = tx
@@ -379,16 +1547,65 @@ async fn handle_client(
                                 .await;
                             continue;
                         }
+                        state.note_nick_change(key);
                         drop(state);
-                        let _ = identities.set(ip, new.clone()).await;
+                        let _ = identities.set(key, new.clone()).await;
                         nick = new.clone();
                         info!(%ip, nick = %nick, "nickname changed");
                         broadcast_sys(&hub, &format!("{old} is now {new}"));
                     }
                 }
-            }
+            },
             ClientMsg::Say { text } => {
-                history.push(nick.clone(), text.clone()).await?;
+                // Dropping the token on the spam paths below releases the slot without a wait.
+                let mut slot = None;
+                if !roles.allows(role, Permission::SlowMode) {
+                    match slow.reserve(key) {
+                        Ok(token) => slot = Some(token),
+                        Err(left) => {
+                            let text = format!("slow mode is on: wait {}s before sending another message", left.as_secs().max(1));
+                            let _ = tx.send(ServerMsg::Sys { text }).await;
+                            continue;
+                        }
+                    }
+                }
+                let state = hub.lock().await;
+                let verdict = spam.check(key, &text, |word| state.nicks.contains(&nick_key(word)), now_ts());
+                drop(state);
+                match verdict {
+                    Verdict::Allow => {}
+                    Verdict::Muted { left } => {
+                        let text = format!("you are muted for {left}s");
+                        let _ = tx.send(ServerMsg::Sys { text }).await;
+                        continue;
+                    }
+                    Verdict::Spam { kind, action } => {
+                        info!(%ip, nick = %nick, %kind, %action, "spam filtered");
+                        let text = match action {
+                            SpamAction::Warn => format!("your message looks like spam ({kind})"),
+                            SpamAction::Drop => format!("message dropped as spam ({kind})"),
+                            SpamAction::Mute => {
+                                audit_or_warn(&audit, None, "chatd", "spam.mute", &format!("{nick} {ip} {kind}")).await;
+                                format!("message dropped as spam ({kind}), you are muted for {}s", spam.policy().mute_for)
+                            }
+                            SpamAction::Kick => {
+                                let _ = tx.send(ServerMsg::Sys { text: format!("You were kicked for spam ({kind})") }).await;
+                                disconnect_client(&hub, client_id, &format!("kicked for spam ({kind})")).await;
+                                audit_or_warn(&audit, None, "chatd", "spam.kick", &format!("{nick} {ip} {kind}")).await;
+                                break;
+                            }
+                        };
+                        let _ = tx.send(ServerMsg::Sys { text }).await;
+                        if action != SpamAction::Warn {
+                            continue;
+                        }
+                    }
+                }
+                if store_history {
+                    if let Err(err) = history.push(nick.clone(), text.clone()).await {
+                        warn!(err = %format!("{err:#}"), "history write failed");
+                    }
+                }
                 let msg = ServerMsg::Msg {
                     nick: nick.clone(),
                     text,
@@ -396,6 +1613,9 @@ async fn handle_client(
                 let mut state = hub.lock().await;
                 let drop_ids = state.broadcast_with_disconnects(&msg);
                 drop(state);
+                if let Some(token) = slot {
+                    token.commit();
+                }
                 for id in drop_ids {
                     disconnect_client(&hub, id, "slow consumer").await;
                 }
@@ -421,12 +1641,327 @@ async fn handle_client(
                     })
                     .await;
             }
+            ClientMsg::Whois { nick: target } => {
+                let state = hub.lock().await;
+                let reply = match state.find_nick(&target) {
+                    Some((_, handle)) => ServerMsg::Whois {
+                        nick: handle.nick.clone(),
+                        role: handle.role,
+                    },
+                    None => ServerMsg::Sys {
+                        text: "no such nickname".into(),
+                    },
+                };
+                drop(state);
+                let _ = tx.send(reply).await;
+            }
+            ClientMsg::Kick { nick: target, reason } => {
+                let state = hub.lock().await;
+                let found = state
+                    .find_nick(&target)
+                    .map(|(id, handle)| (id, handle.clone()));
+                drop(state);
+                let Some((target_id, handle)) = found else {
+                    let _ = tx.send(ServerMsg::Sys { text: "no such nickname".into() }).await;
+                    continue;
+                };
+                if handle.role >= role {
+                    let _ = tx.send(ServerMsg::Sys { text: "permission denied".into() }).await;
+                    continue;
+                }
+                let why = match &reason {
+                    Some(reason) => format!("kicked by {nick}: {reason}"),
+                    None => format!("kicked by {nick}"),
+                };
+                let _ = handle.tx.try_send(ServerMsg::Sys {
+                    text: format!("You were {why}"),
+                });
+                disconnect_client(&hub, target_id, &why).await;
+                let detail = format!("{} {} {}", handle.nick, handle.ip, reason.unwrap_or_default());
+                audit_or_warn(&audit, Some(&tx), &nick, "kick", &detail).await;
+            }
+            ClientMsg::Role { nick: target, role: new_role } => {
+                let state = hub.lock().await;
+                let found = state
+                    .find_nick(&target)
+                    .map(|(id, handle)| (id, handle.clone()));
+                drop(state);
+                let Some((target_id, handle)) = found else {
+                    let _ = tx.send(ServerMsg::Sys { text: "no such nickname".into() }).await;
+                    continue;
+                };
+                let may_grant = new_role < role || role == Role::Owner;
+                if handle.role >= role || !may_grant {
+                    let _ = tx.send(ServerMsg::Sys { text: "permission denied".into() }).await;
+                    continue;
+                }
+                if let Err(err) = identities.set_role(handle.ip, Some(new_role)).await {
+                    report_failure(&tx, "role change", &err).await;
+                    continue;
+                }
+                hub.lock().await.set_role(target_id, new_role);
+                let detail = format!("{} {} {}", handle.nick, handle.ip, new_role);
+                audit_or_warn(&audit, Some(&tx), &nick, "role.set", &detail).await;
+                info!(ip = %handle.ip, nick = %handle.nick, role = %new_role, by = %nick, "role changed");
+                broadcast_sys(&hub, &format!("{} is now {} (set by {nick})", handle.nick, new_role));
+            }
+            ClientMsg::Pending => {
+                let entries = match access_io(&access, |access| access.pending()).await {
+                    Ok(entries) => entries,
+                    Err(err) => {
+                        report_failure(&tx, "listing pending requests", &err).await;
+                        continue;
+                    }
+                };
+                if entries.is_empty() {
+                    let _ = tx.send(ServerMsg::Sys { text: "no pending requests".into() }).await;
+                }
+                for (ip, entry) in entries {
+                    let _ = tx.send(ServerMsg::Pending { ip, attempts: entry.attempts }).await;
+                }
+            }
+            ClientMsg::Approve { ip: target } => {
+                let Ok(target) = target.parse::<IpAddr>().map(canonical_ip) else {
+                    let _ = tx.send(ServerMsg::Sys { text: "invalid ip".into() }).await;
+                    continue;
+                };
+                // Like an invite, approval covers the whole client key, so an IPv6 client stays
+                // in when its address changes within the prefix.
+                let approver = nick.clone();
+                let (entry, net) = (ip_keying.entry(target), ip_keying.net(target));
+                let approved = access_io(&access, {
+                    let entry = entry.clone();
+                    move |access| {
+                        let label = access
+                            .pending()?
+                            .into_iter()
+                            .filter(|(ip, _)| ip.parse::<IpAddr>().is_ok_and(|ip| net.contains(&ip)))
+                            .filter_map(|(_, entry)| entry.knock)
+                            .max_by_key(|knock| knock.at)
+                            .map(|knock| format!("{}: {}", knock.name, knock.reason));
+                        access.files().add_allow(&entry, label, Some(approver), None)?;
+                        access.drop_pending_net(net)?;
+                        if let Err(err) = access.reload() {
+                            warn!(%err, "access reload failed after approval");
+                        }
+                        Ok(())
+                    }
+                })
+                .await;
+                if let Err(err) = approved {
+                    report_failure(&tx, "approval", &err).await;
+                    continue;
+                }
+                audit_or_warn(&audit, Some(&tx), &nick, "allow.add", &entry).await;
+                info!(ip = %target, %entry, by = %nick, "pending ip approved");
+                let _ = tx.send(ServerMsg::Sys { text: format!("approved {entry}") }).await;
+            }
+            ClientMsg::Reject { ip: target } => {
+                let Ok(target) = target.parse::<IpAddr>().map(canonical_ip) else {
+                    let _ = tx.send(ServerMsg::Sys { text: "invalid ip".into() }).await;
+                    continue;
+                };
+                match access_io(&access, move |access| access.drop_pending(target)).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        let _ = tx.send(ServerMsg::Sys { text: format!("{target} is not pending") }).await;
+                        continue;
+                    }
+                    Err(err) => {
+                        report_failure(&tx, "rejection", &err).await;
+                        continue;
+                    }
+                }
+                audit_or_warn(&audit, Some(&tx), &nick, "pending.reject", &target.to_string()).await;
+                info!(ip = %target, by = %nick, "pending ip rejected");
+                let _ = tx.send(ServerMsg::Sys { text: format!("rejected {target}") }).await;
+            }
+            ClientMsg::Slow { seconds: None } => {
+                let text = match slow.interval() {
+                    0 => "slow mode is off".to_string(),
+                    secs => format!("slow mode: {secs}s between messages"),
+                };
+                let _ = tx.send(ServerMsg::Sys { text }).await;
+            }
+            ClientMsg::Slow { seconds: Some(secs) } => {
+                slow.set(secs);
+                audit_or_warn(&audit, Some(&tx), &nick, "slow", &secs.to_string()).await;
+                info!(%ip, nick = %nick, secs, "slow mode changed");
+                if secs == 0 {
+                    broadcast_sys(&hub, &format!("slow mode turned off by {nick}"));
+                } else {
+                    broadcast_sys(&hub, &format!("slow mode set to {secs}s by {nick}"));
+                }
+            }
+            ClientMsg::History { before, after, limit } => {
+                let query = HistoryQuery {
+                    before,
+                    after,
+                    limit: limit.unwrap_or(HISTORY_PAGE).clamp(1, HISTORY_MAX_PAGE),
+                };
+                if let Err(err) = send_history(&tx, history.as_ref(), query, false).await {
+                    report_failure(&tx, "loading history", &err).await;
+                }
+            }
+            ClientMsg::Search {
+                query,
+                nick: from,
+                since,
+                until,
+            } => {
+                let query = SearchQuery {
+                    terms: search_terms(&query),
+                    nick: from,
+                    since,
+                    until,
+                    limit: SEARCH_LIMIT,
+                };
+                if query.terms.is_empty() {
+                    let _ = tx.send(ServerMsg::Sys { text: "search needs at least one word".into() }).await;
+                    continue;
+                }
+                if let Some(term) = query.terms.iter().find(|term| term.chars().count() < SEARCH_MIN_TERM) {
+                    let text = format!("search words need at least {SEARCH_MIN_TERM} letters: \"{term}\"");
+                    let _ = tx.send(ServerMsg::Sys { text }).await;
+                    continue;
+                }
+                let found = match history.search(&query).await {
+                    Ok(found) => found,
+                    Err(err) if err.is::<TooBroad>() => {
+                        let _ = tx.send(ServerMsg::Sys { text: format!("search failed: {err}") }).await;
+                        continue;
+                    }
+                    Err(err) => {
+                        report_failure(&tx, "search", &err).await;
+                        continue;
+                    }
+                };
+                let count = found.len();
+                for item in found {
+                    let _ = tx
+                        .send(ServerMsg::Found {
+                            id: item.id,
+                            ts: item.ts,
+                            nick: item.nick,
+                            text: item.text,
+                        })
+                        .await;
+                }
+                let _ = tx.send(ServerMsg::FoundEnd { count }).await;
+            }
+            ClientMsg::Purge { from, until } => {
+                let removed = match history.purge(from, until).await {
+                    Ok(removed) => removed,
+                    Err(err) => {
+                        report_failure(&tx, "purge", &err).await;
+                        continue;
+                    }
+                };
+                audit_or_warn(&audit, Some(&tx), &nick, "history.purge", &format!("{from}..{until} {removed}")).await;
+                info!(%ip, nick = %nick, from, until, removed, "history purged");
+                let _ = tx.send(ServerMsg::Sys { text: format!("purged {removed} messages") }).await;
+            }
+            ClientMsg::Offenders => {
+                let entries = offenders.snapshot(now_ts());
+                if entries.is_empty() {
+                    let _ = tx.send(ServerMsg::Sys { text: "no offenders".into() }).await;
+                }
+                let threshold = offenders.policy().threshold;
+                for (ip, score, bans) in entries.into_iter().take(CONNS_TOP) {
+                    let text = format!("{ip}: score {score}/{threshold}, banned {bans} times");
+                    let _ = tx.send(ServerMsg::Sys { text }).await;
+                }
+            }
+            ClientMsg::Conns => {
+                let (total, per_ip) = conns.snapshot();
+                let limit = |n: usize| if n == 0 { "unlimited".to_string() } else { n.to_string() };
+                let text = format!(
+                    "connections: {total} (max {}, per ip {})",
+                    limit(conns.total),
+                    limit(conns.per_ip)
+                );
+                let _ = tx.send(ServerMsg::Sys { text }).await;
+                let stats = accepts.stats();
+                let text = format!(
+                    "accepted: {}, dropped by per-ip rate: {}, dropped by global rate: {}",
+                    stats.accepted, stats.dropped_ip, stats.dropped_global
+                );
+                let _ = tx.send(ServerMsg::Sys { text }).await;
+                for (ip, count) in per_ip.into_iter().take(CONNS_TOP) {
+                    let _ = tx.send(ServerMsg::Sys { text: format!("{ip}: {count}") }).await;
+                }
+            }
         }
     }
 
-    writer_task.abort();
     disconnect_client(&hub, client_id, "client left").await;
+    drop(tx);
+    if tokio::time::timeout(Duration::from_secs(1), &mut writer_task).await.is_err() {
+        writer_task.abort();
+    }
+
+    Ok(())
+}
+
+fn required_permission(msg: &ClientMsg) -> Option<Permission> {
+    match msg {
+        ClientMsg::Say { .. } => Some(Permission::Say),
+        ClientMsg::Nick { .. } => Some(Permission::Nick),
+        ClientMsg::Who => Some(Permission::Who),
+        ClientMsg::Whois { .. } => Some(Permission::Whois),
+        ClientMsg::Kick { .. } => Some(Permission::Kick),
+        ClientMsg::Role { .. } => Some(Permission::SetRole),
+        ClientMsg::Pending | ClientMsg::Approve { .. } | ClientMsg::Reject { .. } => Some(Permission::Approve),
+        ClientMsg::Conns | ClientMsg::Offenders => Some(Permission::Stats),
+        ClientMsg::Slow { seconds: Some(_) } => Some(Permission::SlowMode),
+        ClientMsg::Purge { .. } => Some(Permission::Purge),
+        ClientMsg::Slow { seconds: None } | ClientMsg::History { .. } | ClientMsg::Search { .. } => None,
+        ClientMsg::Quit | ClientMsg::Prompt { .. } => None,
+    }
+}
 
+/// Sends one page of history as HIST2 frames followed by HISTEND. With `legacy` each message
+/// also goes out as a HIST frame, which older clients show and newer ones skip.
+async fn send_history(
+    tx: &mpsc::Sender<ServerMsg>,
+    history: &dyn HistoryStore,
+    query: HistoryQuery,
+    legacy: bool,
+) -> Result<()> {
+    // One extra item tells whether the page is the last one.
+    let mut items = history
+        .range(HistoryQuery {
+            limit: query.limit + 1,
+            ..query
+        })
+        .await?;
+    let more = items.len() > query.limit;
+    if more {
+        if query.forward() {
+            items.pop();
+        } else {
+            items.remove(0);
+        }
+    }
+    for item in items {
+        if legacy {
+            let _ = tx
+                .send(ServerMsg::Hist {
+                    nick: item.nick.clone(),
+                    text: item.text.clone(),
+                })
+                .await;
+        }
+        let _ = tx
+            .send(ServerMsg::Hist2 {
+                id: item.id,
+                ts: item.ts,
+                nick: item.nick,
+                text: item.text,
+            })
+            .await;
+    }
+    let _ = tx.send(ServerMsg::HistEnd { more }).await;
     Ok(())
 }
 
@@ -436,6 +1971,7 @@ async fn init_identity(
     ip: IpAddr,
     hub: &Arc<tokio::sync::Mutex<HubState>>,
     identities: Arc<dyn IdentityStore>,
+    policy: &NickPolicy,
 ) -> Result<String> {
     if let Some(record) = identities.get(ip).await? {
         let prompt_id = "keep_nick".to_string();
@@ -447,22 +1983,29 @@ async fn init_identity(
             .await;
         if let Some(answer) = read_prompt(lines, &prompt_id).await? {
             if answer.to_lowercase().starts_with('y') {
-                return prompt_for_nick(tx, lines, hub, identities, ip).await;
+                return prompt_for_nick(tx, lines, hub, identities, ip, policy).await;
             }
-            let state = hub.lock().await;
-            if state.nicks.contains(&record.nick.to_lowercase()) {
-                drop(state);
+            if let Err(err) = policy.validate(&record.nick) {
+                let _ = tx
+                    .send(ServerMsg::Sys {
+                        text: err.to_string(),
+                    })
+                    .await;
+                return prompt_for_nick(tx, lines, hub, identities, ip, policy).await;
+            }
+            let taken = hub.lock().await.nick_taken(&record.nick);
+            if taken || identities.nick_holder(&record.nick, ip).await?.is_some() {
                 let _ = tx
                     .send(ServerMsg::Sys {
                         text: "nickname already taken".into(),
                     })
                     .await;
-                return prompt_for_nick(tx, lines, hub, identities, ip).await;
+                return prompt_for_nick(tx, lines, hub, identities, ip, policy).await;
             }
             return Ok(record.nick);
         }
     }
-    prompt_for_nick(tx, lines, hub, identities, ip).await
+    prompt_for_nick(tx, lines, hub, identities, ip, policy).await
 }
 
 async fn prompt_for_nick(
@@ -471,6 +2014,7 @@ async fn prompt_for_nick(
     hub: &Arc<tokio::sync::Mutex<HubState>>,
     identities: Arc<dyn IdentityStore>,
     ip: IpAddr,
+    policy: &NickPolicy,
 ) -> Result<String> {
     loop {
         let prompt_id = "nick".to_string();
@@ -480,30 +2024,31 @@ async fn prompt_for_nick(
                 text: "Choose nickname".into(),
             })
             .await;
-        if let Some(answer) = read_prompt(lines, &prompt_id).await? {
-            let nick = answer.trim().to_string();
-            if nick.is_empty() || nick.len() > MAX_NICK {
-                let _ = tx
-                    .send(ServerMsg::Sys {
-                        text: "invalid nickname".into(),
-                    })
-                    .await;
-                continue;
-            }
-            let state = hub.lock().await;
-            if state.nicks.contains(&nick.to_lowercase()) {
-                drop(state);
+        let Some(answer) = read_prompt(lines, &prompt_id).await? else {
+            anyhow::bail!("connection closed during nickname prompt");
+        };
+        let nick = match policy.validate(&answer) {
+            Ok(nick) => nick,
+            Err(err) => {
                 let _ = tx
                     .send(ServerMsg::Sys {
-                        text: "nickname already taken".into(),
+                        text: err.to_string(),
                     })
                     .await;
                 continue;
             }
-            drop(state);
-            identities.set(ip, nick.clone()).await?;
-            return Ok(nick);
+        };
+        let taken = hub.lock().await.nick_taken(&nick);
+        if taken || identities.nick_holder(&nick, ip).await?.is_some() {
+            let _ = tx
+                .send(ServerMsg::Sys {
+                    text: "nickname already taken".into(),
+                })
+                .await;
+            continue;
         }
+        identities.set(ip, nick.clone()).await?;
+        return Ok(nick);
     }
 }
 
@@ -531,6 +2076,23 @@ fn broadcast_sys(hub: &Arc<tokio::sync::Mutex<HubState>>, text: &str) {
     });
 }
 
+/// Tells the sender a command failed without ending its session; the details go to the log.
+async fn report_failure(tx: &mpsc::Sender<ServerMsg>, what: &str, err: &anyhow::Error) {
+    warn!(err = %format!("{err:#}"), "{what} failed");
+    let _ = tx.send(ServerMsg::Sys { text: format!("{what} failed, see the server log") }).await;
+}
+
+/// Writes an audit record without undoing or aborting the action it describes. A failed write
+/// is logged and, when `tx` is given, reported to the user who acted.
+async fn audit_or_warn(audit: &AuditLog, tx: Option<&mpsc::Sender<ServerMsg>>, actor: &str, action: &str, detail: &str) {
+    if let Err(err) = audit.record(actor, action, detail) {
+        error!(%err, action, "audit log write failed");
+        if let Some(tx) = tx {
+            let _ = tx.send(ServerMsg::Sys { text: format!("warning: {action} was not written to the audit log") }).await;
+        }
+    }
+}
+
 async fn disconnect_client(hub: &Arc<tokio::sync::Mutex<HubState>>, id: ClientId, reason: &str) {
     let mut state = hub.lock().await;
     if let Some(handle) = state.remove_client(id) {
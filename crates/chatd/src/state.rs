@@ -1,8 +1,11 @@
-use chat_core::rate::RateLimiter;
+use chat_core::nick::nick_key;
+use chat_core::rate::{Limiter, RateConfig};
+use chat_core::roles::Role;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::net::IpAddr;
-use std::time::Duration;
-use tokio::sync::mpsc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Notify};
 use tracing::warn;
 
 use chat_core::protocol::ServerMsg;
@@ -13,25 +16,27 @@ pub type ClientId = u64;
 pub struct ClientHandle {
     pub nick: String,
     pub ip: IpAddr,
+    pub role: Role,
     pub tx: mpsc::Sender<ServerMsg>,
+    pub shutdown: Arc<Notify>,
 }
 
 #[derive(Debug)]
 pub struct IpRate {
-    limiter: RateLimiter,
+    limiter: Limiter,
     warned: bool,
 }
 
 impl IpRate {
-    pub fn new(limit: u32) -> Self {
+    pub fn new(config: &RateConfig) -> Self {
         Self {
-            limiter: RateLimiter::new(limit, Duration::from_secs(1)),
+            limiter: config.limiter(),
             warned: false,
         }
     }
 
-    pub fn check(&mut self) -> bool {
-        let ok = self.limiter.check();
+    pub fn check(&mut self, cost: u32) -> bool {
+        let ok = self.limiter.check_cost(cost);
         if ok {
             self.warned = false;
         }
@@ -45,13 +50,14 @@ pub struct HubState {
     pub nicks: HashSet<String>,
     pub next_id: ClientId,
     pub ip_rates: HashMap<IpAddr, IpRate>,
-    pub conn_rates: HashMap<ClientId, (RateLimiter, bool)>,
-    pub conn_limit: u32,
-    pub ip_limit: u32,
+    pub conn_rates: HashMap<ClientId, (Limiter, bool)>,
+    pub conn_limit: RateConfig,
+    pub ip_limit: RateConfig,
+    pub nick_changes: HashMap<IpAddr, Instant>,
 }
 
 impl HubState {
-    pub fn new(conn_limit: u32, ip_limit: u32) -> Self {
+    pub fn new(conn_limit: RateConfig, ip_limit: RateConfig) -> Self {
         Self {
             clients: HashMap::new(),
             nicks: HashSet::new(),
@@ -60,44 +66,63 @@ impl HubState {
             conn_rates: HashMap::new(),
             conn_limit,
             ip_limit,
+            nick_changes: HashMap::new(),
         }
     }
 
-    pub fn add_client(&mut self, nick: String, ip: IpAddr, tx: mpsc::Sender<ServerMsg>) -> ClientId {
+    pub fn add_client(
+        &mut self,
+        nick: String,
+        ip: IpAddr,
+        role: Role,
+        tx: mpsc::Sender<ServerMsg>,
+        shutdown: Arc<Notify>,
+    ) -> ClientId {
         let id = self.next_id;
         self.next_id += 1;
-        self.nicks.insert(nick.to_lowercase());
-        self.clients.insert(id, ClientHandle { nick, ip, tx });
-        self.conn_rates.insert(
+        self.nicks.insert(nick_key(&nick));
+        self.clients.insert(
             id,
-            (RateLimiter::new(self.conn_limit, Duration::from_secs(1)), false),
+            ClientHandle {
+                nick,
+                ip,
+                role,
+                tx,
+                shutdown,
+            },
         );
+        self.conn_rates.insert(id, (self.conn_limit.limiter(), false));
         let ip_limit = self.ip_limit;
-        self.ip_rates.entry(ip).or_insert_with(|| IpRate::new(ip_limit));
+        self.ip_rates.entry(ip).or_insert_with(|| IpRate::new(&ip_limit));
         id
     }
 
     pub fn remove_client(&mut self, id: ClientId) -> Option<ClientHandle> {
         if let Some(handle) = self.clients.remove(&id) {
-            self.nicks.remove(&handle.nick.to_lowercase());
+            self.nicks.remove(&nick_key(&handle.nick));
             self.conn_rates.remove(&id);
+            handle.shutdown.notify_one();
             return Some(handle);
         }
         None
     }
 
+    pub fn nick_taken(&self, nick: &str) -> bool {
+        self.nicks.contains(&nick_key(nick))
+    }
+
     pub fn rename(&mut self, id: ClientId, new_nick: String) -> Result<(), String> {
-        let norm = new_nick.to_lowercase();
-        if let Some(handle) = self.clients.get(&id) {
-            if handle.nick.eq_ignore_ascii_case(&new_nick) {
-                return Ok(());
-            }
-        }
-        if self.nicks.contains(&norm) {
+        let norm = nick_key(&new_nick);
+        let same_key = self
+            .clients
+            .get(&id)
+            .map(|handle| nick_key(&handle.nick) == norm)
+            .unwrap_or(false);
+        if !same_key && self.nicks.contains(&norm) {
             return Err("nickname already taken".into());
         }
         if let Some(handle) = self.clients.get_mut(&id) {
-            self.nicks.remove(&handle.nick.to_lowercase());
+            self.nicks.remove(&nick_key(&handle.nick));
             handle.nick = new_nick.clone();
             self.nicks.insert(norm);
             Ok(())
@@ -107,14 +132,35 @@ impl HubState {
     }
 
     pub fn list_nicks(&self) -> Vec<String> {
-        self.clients.values().map(|c| c.nick.clone()).collect()
+        self.clients
+            .values()
+            .map(|c| format!("{}{}", c.role.sigil(), c.nick))
+            .collect()
+    }
+
+    pub fn find_nick(&self, nick: &str) -> Option<(ClientId, &ClientHandle)> {
+        let key = nick_key(nick);
+        self.clients
+            .iter()
+            .find(|(_, c)| nick_key(&c.nick) == key)
+            .map(|(id, c)| (*id, c))
+    }
+
+    pub fn role_of(&self, id: ClientId) -> Option<Role> {
+        self.clients.get(&id).map(|c| c.role)
     }
 
-    pub fn conn_rate_ok(&mut self, id: ClientId) -> bool {
+    pub fn set_role(&mut self, id: ClientId, role: Role) {
+        if let Some(handle) = self.clients.get_mut(&id) {
+            handle.role = role;
+        }
+    }
+
+    pub fn conn_rate_ok(&mut self, id: ClientId, cost: u32) -> bool {
         let Some((limiter, warned)) = self.conn_rates.get_mut(&id) else {
             return true;
         };
-        let ok = limiter.check();
+        let ok = limiter.check_cost(cost);
         if ok {
             *warned = false;
         }
@@ -134,10 +180,33 @@ impl HubState {
         }
     }
 
-    pub fn ip_rate_ok(&mut self, ip: IpAddr) -> bool {
+    pub fn ip_rate_ok(&mut self, ip: IpAddr, cost: u32) -> bool {
         let ip_limit = self.ip_limit;
-        let entry = self.ip_rates.entry(ip).or_insert_with(|| IpRate::new(ip_limit));
-        entry.check()
+        let entry = self.ip_rates.entry(ip).or_insert_with(|| IpRate::new(&ip_limit));
+        entry.check(cost)
+    }
+
+    /// Records the outcome of a check made by a shared limiter, resetting the warning like `ip_rate_ok`.
+    pub fn note_ip_rate(&mut self, ip: IpAddr, ok: bool) -> bool {
+        if ok {
+            if let Some(r) = self.ip_rates.get_mut(&ip) {
+                r.warned = false;
+            }
+        }
+        ok
+    }
+
+    /// Time `ip` still has to wait before changing its nickname again.
+    pub fn nick_cooldown_left(&mut self, ip: IpAddr, cooldown: Duration) -> Option<Duration> {
+        let now = Instant::now();
+        self.nick_changes.retain(|_, at| now.duration_since(*at) < cooldown);
+        self.nick_changes
+            .get(&ip)
+            .map(|at| cooldown.saturating_sub(now.duration_since(*at)))
+    }
+
+    pub fn note_nick_change(&mut self, ip: IpAddr) {
+        self.nick_changes.insert(ip, Instant::now());
     }
 
     pub fn ip_warned(&mut self, ip: IpAddr) -> bool {
@@ -158,6 +227,14 @@ impl HubState {
         }
     }
 
+    pub fn broadcast_where(&self, msg: &ServerMsg, filter: impl Fn(&ClientHandle) -> bool) {
+        for (id, handle) in self.clients.iter().filter(|(_, h)| filter(h)) {
+            if handle.tx.try_send(msg.clone()).is_err() {
+                warn!(client_id = *id, nick = %handle.nick, "client queue full, dropping");
+            }
+        }
+    }
+
     pub fn broadcast_with_disconnects(&mut self, msg: &ServerMsg) -> Vec<ClientId> {
         let mut drop = VecDeque::new();
         for (id, handle) in &self.clients {
@@ -167,5 +244,4 @@ impl HubState {
         }
         drop.into_iter().collect()
     }
-
 }
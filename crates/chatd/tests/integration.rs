@@ -1,7 +1,9 @@
 use anyhow::{Context, Result};
-use chat_core::protocol::{parse_server_line, ClientMsg, ServerMsg};
+use chat_core::protocol::{format_client_msg, parse_server_line, ClientMsg, ServerMsg};
+use chat_core::proxy::V2_SIGNATURE;
+use chat_core::roles::Role;
 use rcgen::{CertificateParams, DistinguishedName, DnType, SanType};
-use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::pki_types::ServerName;
 use rustls::{ClientConfig, RootCertStore};
 use rustls_pemfile::certs;
 use std::io::Cursor;
@@ -17,7 +19,7 @@ use std::sync::Arc;
 struct TestServer {
     child: Child,
     port: u16,
-    _dir: tempfile::TempDir,
+    dir: tempfile::TempDir,
     ca_cert: Vec<u8>,
 }
 
@@ -83,6 +85,31 @@ async fn nickname_uniqueness() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn nick_policy_rejects_reserved_and_confusable() -> Result<()> {
+    let server = start_server(5, 20).await?;
+
+    let mut a = connect_client(server.port, &server.ca_cert).await?;
+    ensure_nick(&mut a, "alice").await?;
+    wait_for_who(&mut a, 1).await?;
+
+    let mut b = connect_client(server.port, &server.ca_cert).await?;
+    ensure_nick(&mut b, "Admin").await?;
+    read_until(&mut b, |msg| {
+        matches!(msg, ServerMsg::Sys { text } if text == "nickname is reserved")
+    })
+    .await?;
+
+    expect_prompt(&mut b).await?;
+    b.send_prompt("nick", "a1ice").await?;
+    read_until(&mut b, |msg| {
+        matches!(msg, ServerMsg::Sys { text } if text == "nickname already taken")
+    })
+    .await?;
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn reconnect_prompts_for_saved_nick() -> Result<()> {
     let server = start_server(5, 20).await?;
@@ -100,6 +127,214 @@ async fn reconnect_prompts_for_saved_nick() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn sqlite_store_survives_restart() -> Result<()> {
+    let db_dir = tempdir()?;
+    let db = format!("sqlite:{}", db_dir.path().join("chat.db").display());
+    // The allowlist file is ignored with --db, so the entry has to go into the database.
+    let out = Command::new(env!("CARGO_BIN_EXE_chatd"))
+        .current_dir(db_dir.path())
+        .args(["--db", &db, "allow", "add", "127.0.0.1"])
+        .output()?;
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    let server = start_server_on("127.0.0.1", &["--db", &db], 5, 20).await?;
+    let mut a = connect_client(server.port, &server.ca_cert).await?;
+    ensure_nick(&mut a, "alice").await?;
+    a.send(ClientMsg::Say { text: "kept".into() }).await?;
+    wait_for_who(&mut a, 1).await?;
+    drop(a);
+    drop(server);
+
+    let server = start_server_on("127.0.0.1", &["--db", &db], 5, 20).await?;
+    let mut b = connect_client(server.port, &server.ca_cert).await?;
+    let (id, prompt) = expect_prompt(&mut b).await?;
+    assert_eq!(id, "keep_nick");
+    assert!(prompt.contains("Your nickname is alice"), "{prompt}");
+    b.send_prompt("keep_nick", "n").await?;
+    let hist = read_until(&mut b, |msg| matches!(msg, ServerMsg::Hist { .. })).await?;
+    assert!(matches!(hist, ServerMsg::Hist { nick, text, .. } if nick == "alice" && text == "kept"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn identity_import_refuses_policy_failures_and_look_alikes() -> Result<()> {
+    let dir = tempdir()?;
+    let import = |file: &str, force: bool| {
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_chatd"));
+        cmd.current_dir(dir.path()).args(["identities", "import", file]);
+        if force {
+            cmd.arg("--force");
+        }
+        cmd.output()
+    };
+    std::fs::write(dir.path().join("identities.toml"), "[\"10.0.0.1\"]\nnick = \"alice\"\nupdated = 1\n")?;
+    std::fs::write(
+        dir.path().join("import.toml"),
+        "[\"10.0.0.2\"]\nnick = \"a1ice\"\nupdated = 1\n\n[\"10.0.0.3\"]\nnick = \"root\"\nupdated = 1\n",
+    )?;
+
+    let out = import("import.toml", false)?;
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr)?;
+    assert!(stderr.contains("10.0.0.2 a1ice: looks like the nickname held by 10.0.0.1"), "{stderr}");
+    assert!(stderr.contains("10.0.0.3 root: nickname is reserved"), "{stderr}");
+    assert!(!std::fs::read_to_string(dir.path().join("identities.toml"))?.contains("a1ice"));
+
+    let out = import("import.toml", true)?;
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(std::fs::read_to_string(dir.path().join("identities.toml"))?.contains("a1ice"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn history_pages_by_cursor() -> Result<()> {
+    let spam_dir = tempdir()?;
+    let spam_path = spam_dir.path().join("spam.toml");
+    std::fs::write(&spam_path, "enabled = false\n")?;
+    let spam_arg = spam_path.to_string_lossy().to_string();
+    let server = start_server_on("127.0.0.1", &["--spam-policy", &spam_arg], 100, 100).await?;
+
+    let mut a = connect_client(server.port, &server.ca_cert).await?;
+    ensure_nick(&mut a, "alice").await?;
+    for n in 1..=25 {
+        a.send(ClientMsg::Say { text: format!("message {n}") }).await?;
+    }
+    wait_for_who(&mut a, 1).await?;
+
+    // A join replays only the latest page.
+    let mut b = connect_client(server.port, &server.ca_cert).await?;
+    ensure_nick(&mut b, "bob").await?;
+    let (page, more) = read_history_page(&mut b).await?;
+    assert_eq!(page.len(), 20);
+    assert!(more);
+    assert_eq!(page[0].1, "message 6");
+
+    b.send(ClientMsg::History {
+        before: Some(page[0].0),
+        after: None,
+        limit: Some(10),
+    })
+    .await?;
+    let (older, more) = read_history_page(&mut b).await?;
+    assert_eq!(older.iter().map(|(_, text)| text.as_str()).collect::<Vec<_>>(), ["message 1", "message 2", "message 3", "message 4", "message 5"]);
+    assert!(!more);
+
+    b.send(ClientMsg::History {
+        before: None,
+        after: Some(older[1].0),
+        limit: Some(2),
+    })
+    .await?;
+    let (newer, more) = read_history_page(&mut b).await?;
+    assert_eq!(newer.iter().map(|(_, text)| text.as_str()).collect::<Vec<_>>(), ["message 3", "message 4"]);
+    assert!(more);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn search_uses_sqlite_index() -> Result<()> {
+    let db_dir = tempdir()?;
+    let db = format!("sqlite:{}", db_dir.path().join("chat.db").display());
+    let out = Command::new(env!("CARGO_BIN_EXE_chatd"))
+        .current_dir(db_dir.path())
+        .args(["--db", &db, "allow", "add", "127.0.0.1"])
+        .output()?;
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let server = start_server_on("127.0.0.1", &["--db", &db], 20, 20).await?;
+
+    let mut a = connect_client(server.port, &server.ca_cert).await?;
+    ensure_nick(&mut a, "alice").await?;
+    a.send(ClientMsg::Say { text: "try cargo build --release".into() }).await?;
+    a.send(ClientMsg::Say { text: "lunch at noon?".into() }).await?;
+    wait_for_who(&mut a, 1).await?;
+
+    a.send(ClientMsg::Search {
+        query: "Cargo REL".into(),
+        nick: Some("alice".into()),
+        since: None,
+        until: None,
+    })
+    .await?;
+    let found = read_until(&mut a, |msg| matches!(msg, ServerMsg::Found { .. })).await?;
+    assert!(matches!(found, ServerMsg::Found { nick, text, .. } if nick == "alice" && text == "try cargo build --release"));
+    read_until(&mut a, |msg| matches!(msg, ServerMsg::FoundEnd { count: 1 })).await?;
+
+    a.send(ClientMsg::Search {
+        query: "cargo".into(),
+        nick: Some("bob".into()),
+        since: None,
+        until: None,
+    })
+    .await?;
+    read_until(&mut a, |msg| matches!(msg, ServerMsg::FoundEnd { count: 0 })).await?;
+
+    a.send(ClientMsg::Search {
+        query: "cargo b".into(),
+        nick: None,
+        since: None,
+        until: None,
+    })
+    .await?;
+    read_until(&mut a, |msg| matches!(msg, ServerMsg::Sys { text } if text == "search words need at least 3 letters: \"b\"")).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn retention_caps_and_purges_history() -> Result<()> {
+    let policy_dir = tempdir()?;
+    let keep_path = policy_dir.path().join("keep.toml");
+    std::fs::write(&keep_path, "max_items = 3\nmax_age = 86400\n")?;
+    let skip_path = policy_dir.path().join("skip.toml");
+    std::fs::write(&skip_path, "store = false\n")?;
+    let keep_arg = keep_path.to_string_lossy().to_string();
+    let skip_arg = skip_path.to_string_lossy().to_string();
+
+    let server = start_server_on("127.0.0.1", &["--retention", &keep_arg], 20, 20).await?;
+    std::fs::write(
+        server.dir.path().join("identities.toml"),
+        "[\"127.0.0.1\"]\nnick = \"alice\"\nupdated = 0\nrole = \"admin\"\n",
+    )?;
+    let mut a = connect_client(server.port, &server.ca_cert).await?;
+    ensure_nick(&mut a, "alice").await?;
+    for text in ["one", "two", "three", "four"] {
+        a.send(ClientMsg::Say { text: text.into() }).await?;
+    }
+    wait_for_who(&mut a, 1).await?;
+    let all = ClientMsg::History {
+        before: None,
+        after: None,
+        limit: Some(10),
+    };
+    a.send(all.clone()).await?;
+    let (page, more) = read_history_page(&mut a).await?;
+    assert_eq!(page.iter().map(|(_, text)| text.as_str()).collect::<Vec<_>>(), ["two", "three", "four"]);
+    assert!(!more);
+
+    a.send(ClientMsg::Purge { from: 0, until: u64::MAX }).await?;
+    read_until(&mut a, |msg| matches!(msg, ServerMsg::Sys { text } if text == "purged 3 messages")).await?;
+    a.send(all.clone()).await?;
+    assert!(read_history_page(&mut a).await?.0.is_empty());
+    let audit = std::fs::read_to_string(server.dir.path().join("audit.log"))?;
+    assert!(audit.contains("history.purge"), "{audit}");
+    drop(server);
+
+    // An opted-out room delivers messages but keeps none of them.
+    let server = start_server_on("127.0.0.1", &["--retention", &skip_arg], 20, 20).await?;
+    let mut b = connect_client(server.port, &server.ca_cert).await?;
+    ensure_nick(&mut b, "bob").await?;
+    b.send(ClientMsg::Say { text: "off the record".into() }).await?;
+    read_until(&mut b, |msg| matches!(msg, ServerMsg::Msg { text, .. } if text == "off the record")).await?;
+    b.send(all).await?;
+    assert!(read_history_page(&mut b).await?.0.is_empty());
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn rate_limit_disconnects() -> Result<()> {
     let server = start_server(1, 1).await?;
@@ -121,7 +356,783 @@ async fn rate_limit_disconnects() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn token_bucket_absorbs_short_burst() -> Result<()> {
+    let server = start_server_on("127.0.0.1", &["--conn-burst", "3"], 1, 20).await?;
+
+    let mut a = connect_client(server.port, &server.ca_cert).await?;
+    ensure_nick(&mut a, "alice").await?;
+    read_until(&mut a, |msg| matches!(msg, ServerMsg::Sys { text } if text == "alice joined")).await?;
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+
+    for line in ["one", "two", "three"] {
+        a.send(ClientMsg::Say { text: line.into() }).await?;
+    }
+    for line in ["one", "two", "three"] {
+        let msg = read_until(&mut a, |msg| matches!(msg, ServerMsg::Msg { .. } | ServerMsg::Sys { .. })).await?;
+        assert_eq!(msg, ServerMsg::Msg { nick: "alice".into(), text: line.into() });
+    }
+    a.send(ClientMsg::Say { text: "four".into() }).await?;
+    read_until(&mut a, |msg| matches!(msg, ServerMsg::Sys { text } if text == "rate limit exceeded")).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn command_costs_and_nick_cooldown() -> Result<()> {
+    let costs_dir = tempdir()?;
+    let costs_path = costs_dir.path().join("rate_costs.toml");
+    std::fs::write(&costs_path, "who = 4\nnick = 1\nnick_cooldown = 60\n")?;
+    let costs_arg = costs_path.to_string_lossy().to_string();
+    let server = start_server_on("127.0.0.1", &["--rate-costs", &costs_arg, "--conn-burst", "5"], 1, 20).await?;
+
+    let mut a = connect_client(server.port, &server.ca_cert).await?;
+    ensure_nick(&mut a, "alice").await?;
+    read_until(&mut a, |msg| matches!(msg, ServerMsg::Sys { text } if text == "alice joined")).await?;
+
+    a.send(ClientMsg::Nick { nick: "alicia".into() }).await?;
+    read_until(&mut a, |msg| matches!(msg, ServerMsg::Sys { text } if text == "alice is now alicia")).await?;
+    a.send(ClientMsg::Nick { nick: "ally".into() }).await?;
+    read_until(&mut a, |msg| {
+        matches!(msg, ServerMsg::Sys { text } if text.starts_with("wait ") && text.ends_with("before changing your nickname again"))
+    })
+    .await?;
+
+    // Two NICKs used two of the five tokens; a WHO needs four.
+    a.send(ClientMsg::Who).await?;
+    read_until(&mut a, |msg| matches!(msg, ServerMsg::Sys { text } if text == "rate limit exceeded")).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn spam_filter_mutes_and_kicks() -> Result<()> {
+    let spam_dir = tempdir()?;
+    let spam_path = spam_dir.path().join("spam.toml");
+    std::fs::write(
+        &spam_path,
+        "repeat_limit = 2\nmax_char_run = 5\nmute_for = 60\n[actions]\nrepeat = \"mute\"\nchar_run = \"kick\"\n",
+    )?;
+    let spam_arg = spam_path.to_string_lossy().to_string();
+    let server = start_server_on("127.0.0.1", &["--spam-policy", &spam_arg], 5, 20).await?;
+
+    let mut a = connect_client(server.port, &server.ca_cert).await?;
+    ensure_nick(&mut a, "alice").await?;
+    let mut b = connect_client(server.port, &server.ca_cert).await?;
+    ensure_nick(&mut b, "bob").await?;
+    wait_for_who(&mut b, 2).await?;
+
+    b.send(ClientMsg::Say { text: "zzzzzzzzzz".into() }).await?;
+    read_until(&mut b, |msg| matches!(msg, ServerMsg::Sys { text } if text == "You were kicked for spam (char_run)")).await?;
+    read_until(&mut a, |msg| {
+        matches!(msg, ServerMsg::Sys { text } if text == "bob left (kicked for spam (char_run))")
+    })
+    .await?;
+
+    // Mutes apply to the client key, which both test clients share, so this comes second.
+    a.send(ClientMsg::Say { text: "hello there everyone".into() }).await?;
+    read_until(&mut a, |msg| matches!(msg, ServerMsg::Msg { text, .. } if text == "hello there everyone")).await?;
+    a.send(ClientMsg::Say { text: "Hello there, everyone!".into() }).await?;
+    let reply = read_until(&mut a, |msg| matches!(msg, ServerMsg::Sys { .. } | ServerMsg::Msg { .. })).await?;
+    assert_eq!(
+        reply,
+        ServerMsg::Sys {
+            text: "message dropped as spam (repeat), you are muted for 60s".into(),
+        }
+    );
+    a.send(ClientMsg::Say { text: "sorry".into() }).await?;
+    read_until(&mut a, |msg| matches!(msg, ServerMsg::Sys { text } if text.starts_with("you are muted for"))).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn slow_mode_spaces_out_messages() -> Result<()> {
+    let server = start_server_on("127.0.0.1", &["--slow-mode", "60"], 5, 20).await?;
+
+    let mut a = connect_client(server.port, &server.ca_cert).await?;
+    ensure_nick(&mut a, "alice").await?;
+    a.send(ClientMsg::Say { text: "one".into() }).await?;
+    read_until(&mut a, |msg| matches!(msg, ServerMsg::Msg { text, .. } if text == "one")).await?;
+    a.send(ClientMsg::Say { text: "two".into() }).await?;
+    read_until(&mut a, |msg| {
+        matches!(msg, ServerMsg::Sys { text } if text.starts_with("slow mode is on: wait ") && text.ends_with("s before sending another message"))
+    })
+    .await?;
+    a.send(ClientMsg::Slow { seconds: None }).await?;
+    read_until(&mut a, |msg| matches!(msg, ServerMsg::Sys { text } if text == "slow mode: 60s between messages")).await?;
+    a.send(ClientMsg::Slow { seconds: Some(0) }).await?;
+    read_until(&mut a, |msg| matches!(msg, ServerMsg::Sys { text } if text == "permission denied")).await?;
+
+    // Moderators and above are not slowed down and can turn it off.
+    std::fs::write(
+        server.dir.path().join("identities.toml"),
+        "[\"127.0.0.1\"]\nnick = \"carol\"\nupdated = 0\nrole = \"moderator\"\n",
+    )?;
+    let mut c = connect_client(server.port, &server.ca_cert).await?;
+    ensure_nick(&mut c, "carol").await?;
+    for text in ["x", "y"] {
+        c.send(ClientMsg::Say { text: text.into() }).await?;
+        read_until(&mut a, |msg| matches!(msg, ServerMsg::Msg { text: got, .. } if got == text)).await?;
+    }
+    c.send(ClientMsg::Slow { seconds: Some(0) }).await?;
+    read_until(&mut a, |msg| matches!(msg, ServerMsg::Sys { text } if text == "slow mode turned off by carol")).await?;
+    a.send(ClientMsg::Say { text: "three".into() }).await?;
+    read_until(&mut c, |msg| matches!(msg, ServerMsg::Msg { text, .. } if text == "three")).await?;
+
+    let audit = std::fs::read_to_string(server.dir.path().join("audit.log"))?;
+    assert!(audit.contains("\"slow\""));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn slow_mode_holds_across_sessions_of_one_key() -> Result<()> {
+    let server = start_server_on("127.0.0.1", &["--slow-mode", "60"], 5, 20).await?;
+
+    let mut a = connect_client(server.port, &server.ca_cert).await?;
+    ensure_nick(&mut a, "alice").await?;
+    let mut b = connect_client(server.port, &server.ca_cert).await?;
+    ensure_nick(&mut b, "bob").await?;
+
+    // Both sessions share the 127.0.0.1 key and send at the same time: only one gets through.
+    let (sent_a, sent_b) = tokio::join!(
+        a.send(ClientMsg::Say { text: "from a".into() }),
+        b.send(ClientMsg::Say { text: "from b".into() }),
+    );
+    sent_a?;
+    sent_b?;
+    let mut delivered = 0;
+    for (client, own) in [(&mut a, "from a"), (&mut b, "from b")] {
+        let got = read_until(client, |msg| match msg {
+            ServerMsg::Msg { text, .. } => text == own,
+            ServerMsg::Sys { text } => text.starts_with("slow mode is on: wait "),
+            _ => false,
+        })
+        .await?;
+        if matches!(got, ServerMsg::Msg { .. }) {
+            delivered += 1;
+        }
+    }
+    assert_eq!(delivered, 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn members_cannot_kick() -> Result<()> {
+    let server = start_server(5, 20).await?;
+
+    let mut a = connect_client(server.port, &server.ca_cert).await?;
+    ensure_nick(&mut a, "alice").await?;
+    let mut b = connect_client(server.port, &server.ca_cert).await?;
+    ensure_nick(&mut b, "bob").await?;
+    wait_for_who(&mut b, 2).await?;
+
+    a.send(ClientMsg::Kick {
+        nick: "bob".into(),
+        reason: None,
+    })
+    .await?;
+    read_until(&mut a, |msg| {
+        matches!(msg, ServerMsg::Sys { text } if text == "permission denied")
+    })
+    .await?;
+
+    a.send(ClientMsg::Pending).await?;
+    read_until(&mut a, |msg| {
+        matches!(msg, ServerMsg::Sys { text } if text == "permission denied")
+    })
+    .await?;
+
+    a.send(ClientMsg::Whois { nick: "bob".into() }).await?;
+    let whois = read_until(&mut a, |msg| matches!(msg, ServerMsg::Whois { .. })).await?;
+    assert_eq!(
+        whois,
+        ServerMsg::Whois {
+            nick: "bob".into(),
+            role: Role::Member,
+        }
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_approves_pending_ip() -> Result<()> {
+    let server = start_server(5, 20).await?;
+    std::fs::write(
+        server.dir.path().join("identities.toml"),
+        "[\"127.0.0.1\"]\nnick = \"alice\"\nupdated = 0\nrole = \"admin\"\n",
+    )?;
+    std::fs::write(
+        server.dir.path().join("pending.toml"),
+        "[pending.\"192.0.2.9\"]\nfirst_seen = 0\nlast_seen = 0\nattempts = 3\n",
+    )?;
+
+    let mut a = connect_client(server.port, &server.ca_cert).await?;
+    ensure_nick(&mut a, "alice").await?;
+
+    a.send(ClientMsg::Pending).await?;
+    let pending = read_until(&mut a, |msg| matches!(msg, ServerMsg::Pending { .. })).await?;
+    assert_eq!(
+        pending,
+        ServerMsg::Pending {
+            ip: "192.0.2.9".into(),
+            attempts: 3,
+        }
+    );
+
+    a.send(ClientMsg::Approve {
+        ip: "192.0.2.9".into(),
+    })
+    .await?;
+    read_until(&mut a, |msg| {
+        matches!(msg, ServerMsg::Sys { text } if text == "approved 192.0.2.9")
+    })
+    .await?;
+    let allowed = std::fs::read_to_string(server.dir.path().join("allowed.toml"))?;
+    assert!(allowed.contains("192.0.2.9"));
+    assert!(allowed.contains("owner = \"alice\""));
+    assert!(allowed.contains("127.0.0.1"));
+    let pending = std::fs::read_to_string(server.dir.path().join("pending.toml"))?;
+    assert!(!pending.contains("192.0.2.9"));
+
+    a.send(ClientMsg::Reject {
+        ip: "192.0.2.9".into(),
+    })
+    .await?;
+    read_until(&mut a, |msg| {
+        matches!(msg, ServerMsg::Sys { text } if text == "192.0.2.9 is not pending")
+    })
+    .await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn approval_covers_ipv6_prefix() -> Result<()> {
+    let server = start_server_on("127.0.0.1", &["--trusted-proxy", "127.0.0.0/8", "--ipv6-prefix", "64"], 5, 20).await?;
+    std::fs::write(
+        server.dir.path().join("identities.toml"),
+        "[\"127.0.0.1\"]\nnick = \"alice\"\nupdated = 0\nrole = \"admin\"\n",
+    )?;
+    let mut a = connect_client_with_preamble(
+        server.port,
+        &server.ca_cert,
+        b"PROXY TCP4 127.0.0.1 127.0.0.1 40000 5555\r\n",
+    )
+    .await?;
+    ensure_nick(&mut a, "alice").await?;
+
+    let mut b = connect_client_with_preamble(
+        server.port,
+        &server.ca_cert,
+        b"PROXY TCP6 2001:db8:1:2::a ::1 40001 5555\r\n",
+    )
+    .await?;
+    let (id, _) = expect_prompt(&mut b).await?;
+    assert_eq!(id, "invite");
+    b.send_prompt("invite", "-").await?;
+    expect_prompt(&mut b).await?;
+    b.send_prompt("knock_name", "Dana").await?;
+    expect_prompt(&mut b).await?;
+    b.send_prompt("knock_reason", "new laptop").await?;
+    read_until(&mut b, |msg| matches!(msg, ServerMsg::Sys { text } if text.starts_with("Request noted."))).await?;
+
+    // Approving another address of the same /64 finds the knock and clears the pending entry.
+    a.send(ClientMsg::Approve {
+        ip: "2001:db8:1:2::b".into(),
+    })
+    .await?;
+    read_until(&mut a, |msg| {
+        matches!(msg, ServerMsg::Sys { text } if text == "approved 2001:db8:1:2::/64")
+    })
+    .await?;
+    let allowed = std::fs::read_to_string(server.dir.path().join("allowed.toml"))?;
+    assert!(allowed.contains("entry = \"2001:db8:1:2::/64\""), "{allowed}");
+    assert!(allowed.contains("label = \"Dana: new laptop\""), "{allowed}");
+    a.send(ClientMsg::Pending).await?;
+    read_until(&mut a, |msg| matches!(msg, ServerMsg::Sys { text } if text == "no pending requests")).await?;
+
+    // A new privacy address in the prefix gets straight in.
+    let mut c = connect_client_with_preamble(
+        server.port,
+        &server.ca_cert,
+        b"PROXY TCP6 2001:db8:1:2::c ::1 40002 5555\r\n",
+    )
+    .await?;
+    ensure_nick(&mut c, "dana").await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn rejected_ip_stays_out_of_pending_after_flush() -> Result<()> {
+    let server = start_server_on("127.0.0.1", &["--trusted-proxy", "127.0.0.0/8"], 5, 20).await?;
+    std::fs::write(
+        server.dir.path().join("identities.toml"),
+        "[\"127.0.0.1\"]\nnick = \"alice\"\nupdated = 0\nrole = \"admin\"\n",
+    )?;
+    let mut a = connect_client_with_preamble(
+        server.port,
+        &server.ca_cert,
+        b"PROXY TCP4 127.0.0.1 127.0.0.1 40000 5555\r\n",
+    )
+    .await?;
+    ensure_nick(&mut a, "alice").await?;
+
+    let mut b = connect_client_with_preamble(
+        server.port,
+        &server.ca_cert,
+        b"PROXY TCP4 192.0.2.50 127.0.0.1 40001 5555\r\n",
+    )
+    .await?;
+    read_until(&mut b, |msg| {
+        matches!(msg, ServerMsg::Sys { text } if text == "Not approved. Ask admin.")
+    })
+    .await?;
+
+    // Still only queued in memory, but already listed and rejectable.
+    a.send(ClientMsg::Pending).await?;
+    let pending = read_until(&mut a, |msg| matches!(msg, ServerMsg::Pending { .. })).await?;
+    assert_eq!(
+        pending,
+        ServerMsg::Pending {
+            ip: "192.0.2.50".into(),
+            attempts: 1,
+        }
+    );
+    a.send(ClientMsg::Reject {
+        ip: "192.0.2.50".into(),
+    })
+    .await?;
+    read_until(&mut a, |msg| {
+        matches!(msg, ServerMsg::Sys { text } if text == "rejected 192.0.2.50")
+    })
+    .await?;
+
+    tokio::time::sleep(Duration::from_secs(6)).await;
+    let pending = std::fs::read_to_string(server.dir.path().join("pending.toml")).unwrap_or_default();
+    assert!(!pending.contains("192.0.2.50"), "{pending}");
+    a.send(ClientMsg::Pending).await?;
+    read_until(&mut a, |msg| {
+        matches!(msg, ServerMsg::Sys { text } if text == "no pending requests")
+    })
+    .await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn unapproved_client_can_knock() -> Result<()> {
+    let server = start_server(5, 20).await?;
+    let allow_path = server.dir.path().join("allowed.toml");
+    let pending_path = server.dir.path().join("pending.toml");
+    std::fs::write(&allow_path, "allow = []\n")?;
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    let mut client = loop {
+        let mut client = connect_client(server.port, &server.ca_cert).await?;
+        let first = read_until(&mut client, |_| true).await?;
+        if first == (ServerMsg::Sys { text: "Not approved. Ask admin.".into() }) {
+            break client;
+        }
+        if tokio::time::Instant::now() >= deadline {
+            anyhow::bail!("allowlist was not reloaded");
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    };
+
+    let (id, _) = expect_prompt(&mut client).await?;
+    assert_eq!(id, "invite");
+    client.send_prompt("invite", "-").await?;
+    let (id, _) = expect_prompt(&mut client).await?;
+    assert_eq!(id, "knock_name");
+    client.send_prompt("knock_name", "Dana").await?;
+    let (id, _) = expect_prompt(&mut client).await?;
+    assert_eq!(id, "knock_reason");
+    client.send_prompt("knock_reason", "new laptop").await?;
+    let reply = read_until(&mut client, |msg| matches!(msg, ServerMsg::Sys { .. })).await?;
+    let ServerMsg::Sys { text } = reply else { unreachable!() };
+    let code = text
+        .strip_prefix("Request noted. Reference: ")
+        .context("reference code")?
+        .to_string();
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+    while !std::fs::read_to_string(&pending_path).unwrap_or_default().contains(&code) {
+        if tokio::time::Instant::now() >= deadline {
+            anyhow::bail!("knock was not written to pending");
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    let listed = Command::new(env!("CARGO_BIN_EXE_chatd"))
+        .arg("--pending")
+        .arg(&pending_path)
+        .args(["pending", "list"])
+        .output()?;
+    let listed = String::from_utf8(listed.stdout)?;
+    assert!(listed.contains(&format!("ref={code} name=\"Dana\" reason=\"new laptop\"")));
+
+    let mut again = connect_client(server.port, &server.ca_cert).await?;
+    expect_prompt(&mut again).await?;
+    again.send_prompt("invite", "-").await?;
+    read_until(&mut again, |msg| {
+        matches!(msg, ServerMsg::Sys { text } if *text == format!("Request already noted. Reference: {code}"))
+    })
+    .await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn dual_stack_listener_matches_ipv4_entries() -> Result<()> {
+    let server = start_server_on("[::]", &[], 5, 20).await?;
+
+    let mut a = connect_client(server.port, &server.ca_cert).await?;
+    ensure_nick(&mut a, "alice").await?;
+    wait_for_who(&mut a, 1).await?;
+
+    let identities = std::fs::read_to_string(server.dir.path().join("identities.toml"))?;
+    assert!(identities.contains("\"127.0.0.1\""));
+    assert!(!identities.contains("::ffff:"));
+    assert!(!server.dir.path().join("pending.toml").exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn proxy_header_sets_client_ip() -> Result<()> {
+    let server = start_server_on("127.0.0.1", &["--trusted-proxy", "127.0.0.0/8"], 5, 20).await?;
+
+    let mut a = connect_client_with_preamble(
+        server.port,
+        &server.ca_cert,
+        b"PROXY TCP4 127.0.0.1 127.0.0.1 40000 5555\r\n",
+    )
+    .await?;
+    ensure_nick(&mut a, "alice").await?;
+    wait_for_who(&mut a, 1).await?;
+
+    let mut b = connect_client_with_preamble(
+        server.port,
+        &server.ca_cert,
+        b"PROXY TCP4 192.0.2.50 127.0.0.1 40001 5555\r\n",
+    )
+    .await?;
+    read_until(&mut b, |msg| {
+        matches!(msg, ServerMsg::Sys { text } if text == "Not approved. Ask admin.")
+    })
+    .await?;
+
+    let pending_path = server.dir.path().join("pending.toml");
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+    while !std::fs::read_to_string(&pending_path).unwrap_or_default().contains("192.0.2.50") {
+        if tokio::time::Instant::now() >= deadline {
+            anyhow::bail!("proxied address was not recorded");
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+
+    assert!(connect_client(server.port, &server.ca_cert).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn proxy_health_checks_skip_access_rules() -> Result<()> {
+    let server = start_server_on("127.0.0.1", &["--trusted-proxy", "127.0.0.0/8"], 5, 20).await?;
+    // Only a proxied client is allowed, so the proxy's own address is unapproved.
+    std::fs::write(server.dir.path().join("allowed.toml"), "allow = [\"192.0.2.60\"]\n")?;
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    loop {
+        let preamble = b"PROXY TCP4 192.0.2.60 127.0.0.1 40000 5555\r\n";
+        let mut client = connect_client_with_preamble(server.port, &server.ca_cert, preamble).await?;
+        if matches!(read_until(&mut client, |_| true).await?, ServerMsg::Prompt { .. }) {
+            break;
+        }
+        if tokio::time::Instant::now() >= deadline {
+            anyhow::bail!("allowlist was not reloaded");
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+
+    let mut local = V2_SIGNATURE.to_vec();
+    local.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+    for preamble in [b"PROXY UNKNOWN\r\n".as_slice(), &local, b"PROXY UNKNOWN\r\n"] {
+        let mut check = connect_client_with_preamble(server.port, &server.ca_cert, preamble).await?;
+        assert!(read_until_allow_close(&mut check, |_| true).await?.is_none());
+    }
+
+    let mut b = connect_client_with_preamble(
+        server.port,
+        &server.ca_cert,
+        b"PROXY TCP4 192.0.2.50 127.0.0.1 40001 5555\r\n",
+    )
+    .await?;
+    read_until(&mut b, |msg| {
+        matches!(msg, ServerMsg::Sys { text } if text == "Not approved. Ask admin.")
+    })
+    .await?;
+    let pending_path = server.dir.path().join("pending.toml");
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+    while !std::fs::read_to_string(&pending_path).unwrap_or_default().contains("192.0.2.50") {
+        if tokio::time::Instant::now() >= deadline {
+            anyhow::bail!("proxied address was not recorded");
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    assert!(!std::fs::read_to_string(&pending_path)?.contains("127.0.0.1"));
+    assert!(!std::fs::read_to_string(server.dir.path().join("denied.toml")).unwrap_or_default().contains("127.0.0.1"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn invite_code_allowlists_client() -> Result<()> {
+    let server = start_server(5, 20).await?;
+    let allow_path = server.dir.path().join("allowed.toml");
+    let invites_path = server.dir.path().join("invites.toml");
+    std::fs::write(&allow_path, "allow = []\n")?;
+
+    let created = Command::new(env!("CARGO_BIN_EXE_chatd"))
+        .arg("--invites")
+        .arg(&invites_path)
+        .arg("--audit-log")
+        .arg(server.dir.path().join("audit.log"))
+        .args(["invite", "create", "--uses", "1", "--expires", "7d"])
+        .output()?;
+    let code = String::from_utf8(created.stdout)?.trim().to_string();
+    assert_eq!(code.len(), 12);
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    let mut client = loop {
+        let mut client = connect_client(server.port, &server.ca_cert).await?;
+        let first = read_until(&mut client, |_| true).await?;
+        if first == (ServerMsg::Sys { text: "Not approved. Ask admin.".into() }) {
+            break client;
+        }
+        if tokio::time::Instant::now() >= deadline {
+            anyhow::bail!("allowlist was not reloaded");
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    };
+
+    let (id, _) = expect_prompt(&mut client).await?;
+    assert_eq!(id, "invite");
+    client.send_prompt("invite", "NOTAREALCODE").await?;
+    read_until(&mut client, |msg| {
+        matches!(msg, ServerMsg::Sys { text } if text == "Invalid or expired invite code.")
+    })
+    .await?;
+
+    let mut client = connect_client(server.port, &server.ca_cert).await?;
+    expect_prompt(&mut client).await?;
+    client.send_prompt("invite", &code).await?;
+    read_until(&mut client, |msg| {
+        matches!(msg, ServerMsg::Sys { text } if text == "Invite accepted. Reconnect to join.")
+    })
+    .await?;
+
+    let allowed = std::fs::read_to_string(&allow_path)?;
+    assert!(allowed.contains(&format!("invite {code}")));
+    let mut joined = connect_client(server.port, &server.ca_cert).await?;
+    ensure_nick(&mut joined, "newhire").await?;
+    wait_for_who(&mut joined, 1).await?;
+
+    let invites = std::fs::read_to_string(&invites_path)?;
+    assert!(invites.contains("ip = \"127.0.0.1\""));
+    let audit = std::fs::read_to_string(server.dir.path().join("audit.log"))?;
+    assert!(audit.contains("invite.redeem"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn connection_caps_refuse_extra_connections() -> Result<()> {
+    let server = start_server_on("127.0.0.1", &["--max-conns-per-ip", "2"], 5, 20).await?;
+    std::fs::write(
+        server.dir.path().join("identities.toml"),
+        "[\"127.0.0.1\"]\nnick = \"alice\"\nupdated = 0\nrole = \"admin\"\n",
+    )?;
+
+    let mut a = connect_client(server.port, &server.ca_cert).await?;
+    ensure_nick(&mut a, "alice").await?;
+    let mut b = connect_client(server.port, &server.ca_cert).await?;
+    expect_prompt(&mut b).await?;
+
+    let mut c = connect_client(server.port, &server.ca_cert).await?;
+    let refused = read_until(&mut c, |_| true).await?;
+    assert_eq!(
+        refused,
+        ServerMsg::Error {
+            code: "too_many_connections".into(),
+            text: "Too many connections from your address (limit 2).".into(),
+        }
+    );
+
+    a.send(ClientMsg::Conns).await?;
+    read_until(&mut a, |msg| {
+        matches!(msg, ServerMsg::Sys { text } if text == "connections: 2 (max 1000, per ip 2)")
+    })
+    .await?;
+    read_until(&mut a, |msg| matches!(msg, ServerMsg::Sys { text } if text == "127.0.0.1: 2")).await?;
+
+    drop(b);
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    loop {
+        let mut d = connect_client(server.port, &server.ca_cert).await?;
+        if matches!(read_until(&mut d, |_| true).await?, ServerMsg::Prompt { .. }) {
+            break;
+        }
+        if tokio::time::Instant::now() >= deadline {
+            anyhow::bail!("slot was not released");
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn accept_rate_drops_sockets_before_tls() -> Result<()> {
+    let server = start_server_on("127.0.0.1", &["--accept-rate", "1", "--accept-burst", "3"], 5, 20).await?;
+    std::fs::write(
+        server.dir.path().join("identities.toml"),
+        "[\"127.0.0.1\"]\nnick = \"alice\"\nupdated = 0\nrole = \"admin\"\n",
+    )?;
+
+    // The startup probe used the first token.
+    let mut a = connect_client(server.port, &server.ca_cert).await?;
+    let _b = connect_client(server.port, &server.ca_cert).await?;
+    assert!(connect_client(server.port, &server.ca_cert).await.is_err());
+
+    ensure_nick(&mut a, "alice").await?;
+    a.send(ClientMsg::Conns).await?;
+    read_until(&mut a, |msg| {
+        matches!(msg, ServerMsg::Sys { text }
+            if text == "accepted: 3, dropped by per-ip rate: 1, dropped by global rate: 0")
+    })
+    .await?;
+
+    Ok(())
+}
+
+#[test]
+fn check_reports_malformed_state_files() -> Result<()> {
+    let dir = tempdir()?;
+    let allow_path = dir.path().join("allowed.toml");
+    let check = |dir: &std::path::Path| {
+        Command::new(env!("CARGO_BIN_EXE_chatd"))
+            .current_dir(dir)
+            .arg("check")
+            .output()
+    };
+
+    std::fs::write(&allow_path, "allow = [\"127.0.0.1\", { entry = \"10.0.0.0/8\" }]\n")?;
+    let out = check(dir.path())?;
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stdout));
+
+    std::fs::write(&allow_path, "allow = [\"127.0.0.1\", \"10.0.0.300\"]\n")?;
+    let out = check(dir.path())?;
+    assert!(!out.status.success());
+    let stdout = String::from_utf8(out.stdout)?;
+    assert!(stdout.contains("error ./allowed.toml") && stdout.contains("#2 \"10.0.0.300\""), "{stdout}");
+    assert!(stdout.contains("ok ./denied.toml"), "{stdout}");
+
+    std::fs::write(dir.path().join("identities.toml"), "[\"10.0.0.1\"\nnick = \"x\"\n")?;
+    let stdout = String::from_utf8(check(dir.path())?.stdout)?;
+    assert!(stdout.contains("error ./identities.toml") && stdout.contains("line 1"), "{stdout}");
+
+    std::fs::write(dir.path().join("retention.toml"), "max_items = 0\n")?;
+    let stdout = String::from_utf8(check(dir.path())?.stdout)?;
+    assert!(stdout.contains("error ./retention.toml") && stdout.contains("max_items must be positive"), "{stdout}");
+
+    let out = Command::new(env!("CARGO_BIN_EXE_chatd"))
+        .current_dir(dir.path())
+        .args(["--rate-backend", "redis", "check"])
+        .output()?;
+    let stdout = String::from_utf8(out.stdout)?;
+    assert!(stdout.contains("error rate backend redis: --rate-backend redis needs --redis"), "{stdout}");
+
+    let out = Command::new(env!("CARGO_BIN_EXE_chatd"))
+        .current_dir(dir.path())
+        .args(["--db", "sqlite:chat.db", "check"])
+        .output()?;
+    let stdout = String::from_utf8(out.stdout)?;
+    assert!(!out.status.success());
+    assert!(stdout.contains("error chat.db: chat.db does not exist"), "{stdout}");
+    assert!(!dir.path().join("chat.db").exists());
+
+    let out = Command::new(env!("CARGO_BIN_EXE_chatd"))
+        .current_dir(dir.path())
+        .args(["--identity-ttl", "0", "check"])
+        .output()?;
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr)?.contains("must be longer than 0s"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn denied_ip_is_dropped_before_tls() -> Result<()> {
+    let server = start_server(5, 20).await?;
+    std::fs::write(
+        server.dir.path().join("denied.toml"),
+        "[[deny]]\nentry = \"127.0.0.0/8\"\nreason = \"test\"\nadded = 0\n",
+    )?;
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while connect_client(server.port, &server.ca_cert).await.is_ok() {
+        if tokio::time::Instant::now() >= deadline {
+            anyhow::bail!("denylist was not reloaded");
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    let pending = std::fs::read_to_string(server.dir.path().join("pending.toml")).unwrap_or_default();
+    assert!(!pending.contains("127.0.0.1"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn repeated_invalid_commands_trigger_ban() -> Result<()> {
+    let policy_dir = tempdir()?;
+    let policy_path = policy_dir.path().join("bans.toml");
+    std::fs::write(&policy_path, "threshold = 3\nbase_ban = 600\n")?;
+    let policy_arg = policy_path.to_string_lossy().to_string();
+    let server = start_server_on("127.0.0.1", &["--ban-policy", &policy_arg], 5, 20).await?;
+
+    let mut a = connect_client(server.port, &server.ca_cert).await?;
+    ensure_nick(&mut a, "alice").await?;
+    for _ in 0..3 {
+        a.writer.write_all(b"FROB\n").await?;
+    }
+    read_until(&mut a, |msg| {
+        matches!(msg, ServerMsg::Sys { text } if text == "You are banned for 600s (invalid_command)")
+    })
+    .await?;
+    let closed = tokio::time::timeout(Duration::from_secs(5), async {
+        while let Ok(Some(_)) = a.reader.next_line().await {}
+    });
+    closed.await.context("banned client was not disconnected")?;
+
+    let denied = std::fs::read_to_string(server.dir.path().join("denied.toml"))?;
+    assert!(denied.contains("entry = \"127.0.0.1\""), "{denied}");
+    assert!(denied.contains("auto-ban #1: invalid_command"), "{denied}");
+    let audit = std::fs::read_to_string(server.dir.path().join("audit.log"))?;
+    assert!(audit.contains("ban.auto"));
+    assert!(connect_client(server.port, &server.ca_cert).await.is_err());
+
+    Ok(())
+}
+
 async fn start_server(conn_rate: u32, ip_rate: u32) -> Result<TestServer> {
+    start_server_on("127.0.0.1", &[], conn_rate, ip_rate).await
+}
+
+async fn start_server_on(host: &str, extra_args: &[&str], conn_rate: u32, ip_rate: u32) -> Result<TestServer> {
     let dir = tempdir()?;
     let port = pick_port()?;
 
@@ -130,6 +1141,9 @@ async fn start_server(conn_rate: u32, ip_rate: u32) -> Result<TestServer> {
     let allow_path = dir.path().join("allowed.toml");
     let pending_path = dir.path().join("pending.toml");
     let identities_path = dir.path().join("identities.toml");
+    let deny_path = dir.path().join("denied.toml");
+    let audit_path = dir.path().join("audit.log");
+    let invites_path = dir.path().join("invites.toml");
 
     let (cert_pem, key_pem) = generate_cert()?;
     std::fs::write(&cert_path, &cert_pem)?;
@@ -139,7 +1153,7 @@ async fn start_server(conn_rate: u32, ip_rate: u32) -> Result<TestServer> {
 
     let child = Command::new(env!("CARGO_BIN_EXE_chatd"))
         .arg("--bind")
-        .arg(format!("127.0.0.1:{port}"))
+        .arg(format!("{host}:{port}"))
         .arg("--cert")
         .arg(&cert_path)
         .arg("--key")
@@ -150,10 +1164,17 @@ async fn start_server(conn_rate: u32, ip_rate: u32) -> Result<TestServer> {
         .arg(&pending_path)
         .arg("--identities")
         .arg(&identities_path)
+        .arg("--denylist")
+        .arg(&deny_path)
+        .arg("--audit-log")
+        .arg(&audit_path)
+        .arg("--invites")
+        .arg(&invites_path)
         .arg("--conn-rate")
         .arg(conn_rate.to_string())
         .arg("--ip-rate")
         .arg(ip_rate.to_string())
+        .args(extra_args)
         .stdout(Stdio::null())
         .stderr(Stdio::null())
         .spawn()
@@ -164,7 +1185,7 @@ async fn start_server(conn_rate: u32, ip_rate: u32) -> Result<TestServer> {
     Ok(TestServer {
         child,
         port,
-        _dir: dir,
+        dir,
         ca_cert: cert_pem,
     })
 }
@@ -183,18 +1204,23 @@ fn generate_cert() -> Result<(Vec<u8>, Vec<u8>)> {
 }
 
 async fn connect_client(port: u16, ca_cert: &[u8]) -> Result<TestClient> {
+    connect_client_with_preamble(port, ca_cert, b"").await
+}
+
+async fn connect_client_with_preamble(port: u16, ca_cert: &[u8], preamble: &[u8]) -> Result<TestClient> {
     let mut root = RootCertStore::empty();
     let mut cursor = Cursor::new(ca_cert);
     let certs = certs(&mut cursor).collect::<Result<Vec<_>, _>>()?;
     for cert in certs {
-        root.add(CertificateDer::from(cert))?;
+        root.add(cert)?;
     }
     let config = ClientConfig::builder()
         .with_root_certificates(root)
         .with_no_client_auth();
 
     let connector = TlsConnector::from(Arc::new(config));
-    let tcp = TcpStream::connect(format!("127.0.0.1:{port}")).await?;
+    let mut tcp = TcpStream::connect(format!("127.0.0.1:{port}")).await?;
+    tcp.write_all(preamble).await?;
     let server_name = ServerName::try_from("localhost").context("server name")?;
     let tls = connector.connect(server_name, tcp).await?;
     let (reader, writer) = tokio::io::split(tls);
@@ -211,7 +1237,7 @@ struct TestClient {
 
 impl TestClient {
     async fn send(&mut self, msg: ClientMsg) -> Result<()> {
-        let line = format_client_msg(msg);
+        let line = format_client_msg(&msg);
         self.writer.write_all(line.as_bytes()).await?;
         self.writer.write_all(b"\n").await?;
         Ok(())
@@ -226,16 +1252,6 @@ impl TestClient {
     }
 }
 
-fn format_client_msg(msg: ClientMsg) -> String {
-    match msg {
-        ClientMsg::Nick { nick } => format!("NICK {}", nick),
-        ClientMsg::Say { text } => format!("SAY {}", text),
-        ClientMsg::Who => "WHO".into(),
-        ClientMsg::Quit => "QUIT".into(),
-        ClientMsg::Prompt { id, answer } => format!("PROMPT {} {}", id, answer),
-    }
-}
-
 async fn expect_prompt(client: &mut TestClient) -> Result<(String, String)> {
     let msg = read_until(client, |msg| matches!(msg, ServerMsg::Prompt { .. })).await?;
     match msg {
@@ -277,6 +1293,18 @@ async fn ensure_nick(client: &mut TestClient, desired: &str) -> Result<()> {
     Ok(())
 }
 
+/// Collects HIST2 frames up to the next HISTEND as (id, text) pairs.
+async fn read_history_page(client: &mut TestClient) -> Result<(Vec<(u64, String)>, bool)> {
+    let mut page = Vec::new();
+    loop {
+        match read_until(client, |msg| matches!(msg, ServerMsg::Hist2 { .. } | ServerMsg::HistEnd { .. })).await? {
+            ServerMsg::Hist2 { id, text, .. } => page.push((id, text)),
+            ServerMsg::HistEnd { more } => return Ok((page, more)),
+            _ => unreachable!(),
+        }
+    }
+}
+
 async fn read_until<F>(client: &mut TestClient, pred: F) -> Result<ServerMsg>
 where
     F: Fn(&ServerMsg) -> bool,